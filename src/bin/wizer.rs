@@ -1,12 +1,16 @@
 use anyhow::Context;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use std::fs;
 use std::io::{self, BufRead, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
-use wizer::Wizer;
+use wizer::{ModuleDiff, Provenance, Wizer};
 
 #[derive(StructOpt)]
 pub struct Options {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// The input Wasm module's file path.
     ///
     /// If not specified, then `stdin` is used.
@@ -19,41 +23,484 @@ pub struct Options {
     #[structopt(short = "o", parse(from_os_str))]
     output: Option<PathBuf>,
 
+    /// Also precompile the output Wasm module to native code, and write the
+    /// serialized `wasmtime::Module` to this file path.
+    ///
+    /// See `--target`, `--opt-level`, `--cranelift-enable`, and
+    /// `--cranelift-set`.
+    #[structopt(long = "emit-cwasm", parse(from_os_str))]
+    cwasm: Option<PathBuf>,
+
+    /// A TOML file to read options from.
+    ///
+    /// The file's keys are the kebab-case names of the options, for example
+    /// `init-func`, `func-renames`, `allow-wasi` or `dirs`. Options given on
+    /// the command line override the file's.
+    #[cfg(feature = "serde")]
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    wizer: Wizer,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Print how a Wasm module was wizened, as recorded in its `wizer` custom
+    /// section.
+    Info {
+        /// The Wasm module's file path.
+        ///
+        /// If not specified, then `stdin` is used.
+        #[structopt(parse(from_os_str))]
+        input: Option<PathBuf>,
+    },
+
+    /// Compare the initialized state of two Wasm modules, such as two
+    /// wizenings of the same module.
+    ///
+    /// Prints the modules' size and data size deltas, the globals whose
+    /// initial values differ, and the memories whose minimum sizes differ,
+    /// along with the byte ranges of their initial contents that were added,
+    /// removed, or changed.
+    Diff {
+        /// The old Wasm module's file path.
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        /// The new Wasm module's file path.
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+    },
+
+    /// Wizen many Wasm modules in parallel.
+    ///
+    /// The modules come from a manifest, or from glob patterns. All modules are
+    /// initialized in one shared Wasmtime engine, and a summary of which
+    /// modules succeeded and which failed is printed at the end. The exit
+    /// status is non-zero if any module failed.
+    Batch(Box<Batch>),
+}
+
+#[derive(StructOpt)]
+struct Batch {
+    /// A TOML manifest of the modules to wizen.
+    ///
+    /// The manifest's top-level keys are options for every module, the same as
+    /// in a `--config` file. Each `[[module]]` table has an `input` and an
+    /// `output` path, relative to the manifest, and optionally other options
+    /// that override the top-level ones for that module. Options that
+    /// configure the engine may not be overridden per module, since all
+    /// modules share one engine: `wasm-multi-memory`, `wasm-multi-value`,
+    /// `wasm-module-linking`, `init-opt-level`, `nan-canonicalization`,
+    /// `debug-info`, `wasmtime-cache`, `wasmtime-cache-config`, and
+    /// `dirty-page-tracking`.
+    #[cfg(feature = "serde")]
+    #[structopt(long, parse(from_os_str))]
+    manifest: Option<PathBuf>,
+
+    /// A glob pattern of Wasm modules to wizen.
+    ///
+    /// Within a path component, `*` matches any sequence of characters and `?`
+    /// matches any single character. A `**` component matches any number of
+    /// directories. Requires `--out-dir`.
+    #[structopt(long = "glob", value_name = "pattern")]
+    globs: Vec<String>,
+
+    /// The directory to write the output Wasm modules that match `--glob` to,
+    /// under their input file names.
+    #[structopt(long, parse(from_os_str))]
+    out_dir: Option<PathBuf>,
+
+    /// The number of modules to wizen at the same time.
+    ///
+    /// Defaults to the number of CPUs.
+    #[structopt(short = "j", long)]
+    jobs: Option<usize>,
+
+    /// Also precompile each output Wasm module to native code, and write it
+    /// next to the output with the `cwasm` extension.
+    #[structopt(long)]
+    emit_cwasm: bool,
+
+    /// Options for every module, which override the manifest's.
     #[structopt(flatten)]
     wizer: Wizer,
 }
 
+/// One module to wizen in batch mode.
+struct Job {
+    input: PathBuf,
+    output: PathBuf,
+    wizer: Wizer,
+}
+
 fn main() -> anyhow::Result<()> {
     env_logger::init();
-    let options = Options::from_args();
+    let matches = Options::clap().get_matches();
+    let options = Options::from_clap(&matches);
 
-    let stdin = io::stdin();
-    let mut input: Box<dyn BufRead> = if let Some(input) = options.input.as_ref() {
-        Box::new(io::BufReader::new(
-            fs::File::open(input).context("failed to open input file")?,
-        ))
-    } else {
-        Box::new(stdin.lock())
+    match &options.command {
+        Some(Command::Info { input }) => return info(input.as_deref()),
+        Some(Command::Diff { old, new }) => return diff(old, new),
+        Some(Command::Batch(batch)) => {
+            return self::batch(batch, matches.subcommand_matches("batch").unwrap())
+        }
+        None => {}
+    }
+
+    #[cfg(feature = "serde")]
+    let wizer = match &options.config {
+        Some(config) => load_config(config, &options.wizer, &matches)?,
+        None => options.wizer.clone(),
     };
+    #[cfg(not(feature = "serde"))]
+    let wizer = options.wizer.clone();
+
+    let input_wasm = read_input(options.input.as_deref())?;
 
     let mut output: Box<dyn Write> = if let Some(output) = options.output.as_ref() {
         Box::new(io::BufWriter::new(
             fs::File::create(output).context("failed to create output file")?,
         ))
     } else {
-        Box::new(io::stdout())
+        Box::new(io::BufWriter::new(io::stdout()))
+    };
+
+    // Precompiling needs the whole wizened module, but otherwise stream it
+    // straight to the output.
+    if let Some(cwasm) = &options.cwasm {
+        let output_wasm = wizer.run(&input_wasm)?;
+        output
+            .write_all(&output_wasm)
+            .context("failed to write to output")?;
+        let cwasm_bytes = wizer.precompile(&output_wasm)?;
+        fs::write(cwasm, cwasm_bytes).context("failed to write precompiled module")?;
+    } else {
+        wizer.run_to_writer(&input_wasm, &mut output)?;
+    }
+    output.flush().context("failed to write to output")?;
+
+    Ok(())
+}
+
+fn read_input(input: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    let stdin = io::stdin();
+    let mut input: Box<dyn BufRead> = if let Some(input) = input {
+        Box::new(io::BufReader::new(
+            fs::File::open(input).context("failed to open input file")?,
+        ))
+    } else {
+        Box::new(stdin.lock())
     };
 
     let mut input_wasm = vec![];
     input
         .read_to_end(&mut input_wasm)
         .context("failed to read input Wasm module")?;
+    Ok(input_wasm)
+}
+
+fn info(input: Option<&Path>) -> anyhow::Result<()> {
+    let wasm = read_input(input)?;
+    match Provenance::from_wasm(&wasm)? {
+        Some(provenance) => print!("{}", provenance),
+        None => anyhow::bail!("the Wasm module does not have a `wizer` custom section"),
+    }
+    Ok(())
+}
+
+fn diff(old: &Path, new: &Path) -> anyhow::Result<()> {
+    let old_wasm = fs::read(old).with_context(|| format!("failed to read {}", old.display()))?;
+    let new_wasm = fs::read(new).with_context(|| format!("failed to read {}", new.display()))?;
+    print!("{}", ModuleDiff::new(&old_wasm, &new_wasm)?);
+    Ok(())
+}
+
+/// Read options from the given TOML file, and then override them with the ones
+/// given on the command line.
+#[cfg(feature = "serde")]
+fn load_config(
+    path: &Path,
+    cli: &Wizer,
+    matches: &structopt::clap::ArgMatches,
+) -> anyhow::Result<Wizer> {
+    let mut config = read_toml(path)?;
+    config.extend(cli_options(cli, matches)?);
+    toml::Value::Table(config)
+        .try_into()
+        .with_context(|| format!("invalid config file {}", path.display()))
+}
+
+#[cfg(feature = "serde")]
+fn read_toml(path: &Path) -> anyhow::Result<toml::value::Table> {
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Get the options that were given on the command line, as TOML.
+#[cfg(feature = "serde")]
+fn cli_options(
+    cli: &Wizer,
+    matches: &structopt::clap::ArgMatches,
+) -> anyhow::Result<toml::value::Table> {
+    // Both the command line arguments and the TOML keys are the kebab-case
+    // names of `Wizer`'s fields.
+    let cli = match toml::Value::try_from(cli)? {
+        toml::Value::Table(cli) => cli,
+        _ => unreachable!(),
+    };
+    Ok(cli
+        .into_iter()
+        .filter(|(key, _)| matches.occurrences_of(key) > 0)
+        .collect())
+}
+
+fn batch(batch: &Batch, matches: &structopt::clap::ArgMatches) -> anyhow::Result<()> {
+    let mut jobs = vec![];
+
+    #[cfg(feature = "serde")]
+    let base = match &batch.manifest {
+        Some(manifest) => read_manifest(manifest, &batch.wizer, matches, &mut jobs)?,
+        None => batch.wizer.clone(),
+    };
+    #[cfg(not(feature = "serde"))]
+    let base = {
+        let _ = matches;
+        batch.wizer.clone()
+    };
+
+    if !batch.globs.is_empty() {
+        let out_dir = batch
+            .out_dir
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("`--glob` requires `--out-dir`"))?;
+        for pattern in &batch.globs {
+            let inputs = glob(pattern)?;
+            if inputs.is_empty() {
+                anyhow::bail!("no files match `{}`", pattern);
+            }
+            for input in inputs {
+                let output = out_dir.join(input.file_name().unwrap());
+                jobs.push(Job {
+                    input,
+                    output,
+                    wizer: base.clone(),
+                });
+            }
+        }
+    }
 
-    let output_wasm = options.wizer.run(&input_wasm)?;
+    if jobs.is_empty() {
+        anyhow::bail!("no modules to wizen; use `--manifest` or `--glob`");
+    }
+    let mut outputs = std::collections::HashSet::new();
+    for job in &jobs {
+        if !outputs.insert(&job.output) {
+            anyhow::bail!("multiple modules are written to {}", job.output.display());
+        }
+    }
+
+    let engine = base.engine()?;
+    let wizen = |job: &Job| -> anyhow::Result<()> {
+        let wasm = fs::read(&job.input).context("failed to read input Wasm module")?;
+        let wasm = job.wizer.run_with_engine(&engine, &wasm)?;
+        fs::write(&job.output, &wasm).context("failed to write output Wasm module")?;
+        if batch.emit_cwasm {
+            let cwasm = job.wizer.precompile(&wasm)?;
+            fs::write(job.output.with_extension("cwasm"), cwasm)
+                .context("failed to write precompiled module")?;
+        }
+        Ok(())
+    };
+    let results: Vec<_> = match batch.jobs {
+        Some(n) => rayon::ThreadPoolBuilder::new()
+            .num_threads(n)
+            .build()?
+            .install(|| jobs.par_iter().map(wizen).collect()),
+        None => jobs.par_iter().map(wizen).collect(),
+    };
 
-    output
-        .write_all(&output_wasm)
-        .context("failed to write to output")?;
+    let mut failures = 0;
+    for (job, result) in jobs.iter().zip(&results) {
+        match result {
+            Ok(()) => println!(
+                "ok      {} -> {}",
+                job.input.display(),
+                job.output.display()
+            ),
+            Err(e) => {
+                failures += 1;
+                println!("FAILED  {}: {:#}", job.input.display(), e);
+            }
+        }
+    }
+    println!("\n{} succeeded, {} failed", jobs.len() - failures, failures);
 
+    // Every failure was already reported above, so don't report it again by
+    // returning an error.
+    if failures > 0 {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// The options that configure the engine, which all modules in a batch share.
+#[cfg(feature = "serde")]
+const ENGINE_OPTIONS: &[&str] = &[
+    "wasm-multi-memory",
+    "wasm-multi-value",
+    "wasm-module-linking",
+    "init-opt-level",
+    "nan-canonicalization",
+    "debug-info",
+    "wasmtime-cache",
+    "wasmtime-cache-config",
+    "dirty-page-tracking",
+];
+
+/// Read the jobs from the given batch manifest, and return the options for
+/// every module.
+#[cfg(feature = "serde")]
+fn read_manifest(
+    path: &Path,
+    cli: &Wizer,
+    matches: &structopt::clap::ArgMatches,
+    jobs: &mut Vec<Job>,
+) -> anyhow::Result<Wizer> {
+    let mut manifest = read_toml(path)?;
+    let modules = match manifest.remove("module") {
+        Some(toml::Value::Array(modules)) => modules,
+        Some(_) => anyhow::bail!("`module` must be an array of tables in {}", path.display()),
+        None => vec![],
+    };
+    let cli = cli_options(cli, matches)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let to_wizer = |mut options: toml::value::Table| -> anyhow::Result<Wizer> {
+        options.extend(cli.clone());
+        toml::Value::Table(options)
+            .try_into()
+            .with_context(|| format!("invalid options in {}", path.display()))
+    };
+
+    for module in modules {
+        let mut module = match module {
+            toml::Value::Table(module) => module,
+            _ => anyhow::bail!("`module` must be an array of tables in {}", path.display()),
+        };
+        let mut path_of = |key: &str| match module.remove(key) {
+            Some(toml::Value::String(p)) => Ok(dir.join(p)),
+            _ => Err(anyhow::anyhow!(
+                "every module in {} needs an `{}` path",
+                path.display(),
+                key
+            )),
+        };
+        let input = path_of("input")?;
+        let output = path_of("output")?;
+
+        for key in ENGINE_OPTIONS {
+            if module.contains_key(*key) {
+                anyhow::bail!(
+                    "`{}` may not be set for {} in {}, since all modules share one engine",
+                    key,
+                    input.display(),
+                    path.display()
+                );
+            }
+        }
+
+        let mut options = manifest.clone();
+        options.extend(module);
+        jobs.push(Job {
+            input,
+            output,
+            wizer: to_wizer(options)?,
+        });
+    }
+
+    to_wizer(manifest)
+}
+
+/// Find the files that match the given glob pattern.
+fn glob(pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let (mut paths, components) = if let Some(rest) = pattern.strip_prefix('/') {
+        (vec![PathBuf::from("/")], rest)
+    } else {
+        (vec![PathBuf::new()], pattern)
+    };
+
+    for component in components.split('/').filter(|c| !c.is_empty()) {
+        let mut matches = vec![];
+        for path in &paths {
+            if component == "**" {
+                descendant_dirs(path, &mut matches)?;
+                continue;
+            }
+            if !component.contains(&['*', '?'][..]) {
+                let path = path.join(component);
+                if path.exists() {
+                    matches.push(path);
+                }
+                continue;
+            }
+            let dir = if path.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                path
+            };
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let name = entry?.file_name();
+                if let Some(name) = name.to_str() {
+                    if glob_match(component.as_bytes(), name.as_bytes()) {
+                        matches.push(path.join(name));
+                    }
+                }
+            }
+        }
+        paths = matches;
+    }
+
+    paths.retain(|p| p.is_file());
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+/// Push the given directory and all the directories below it.
+fn descendant_dirs(dir: &Path, dirs: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    dirs.push(dir.to_path_buf());
+    let read_dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    if !read_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(read_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            descendant_dirs(&dir.join(entry.file_name()), dirs)?;
+        }
+    }
+    Ok(())
+}
+
+/// Match a file name against a glob pattern component.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
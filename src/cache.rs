@@ -0,0 +1,502 @@
+//! A content-addressed cache of wizened modules.
+//!
+//! Each wizened module is stored in the cache directory under the hex-encoded
+//! SHA-256 hash of everything that went into making it: the input Wasm, the
+//! `Wizer` options, the Wizer version, and the WASI inputs. Initialization
+//! that can observe something we can't hash isn't cached at all.
+
+use crate::{vfs::Vfs, Wizer};
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The cache entry for one wizening.
+pub(crate) struct Entry {
+    path: PathBuf,
+}
+
+impl Entry {
+    /// Get the cache entry for wizening the given Wasm with the given `Wizer`,
+    /// or `None` if it can't be cached.
+    pub(crate) fn new(
+        wizer: &Wizer,
+        dir: &Path,
+        wasm: &[u8],
+        vfs: Option<&Vfs>,
+    ) -> anyhow::Result<Option<Entry>> {
+        if let Some(reason) = uncacheable(wizer, wasm) {
+            log::info!("Not caching the wizened module: {}", reason);
+            return Ok(None);
+        }
+
+        let mut hasher = Sha256::new();
+        hash_bytes(&mut hasher, env!("CARGO_PKG_VERSION").as_bytes());
+        hash_bytes(&mut hasher, wasm);
+
+        hash_options(&mut hasher, wizer);
+
+        if wizer.allow_wasi {
+            if let Some(path) = &wizer.wasi_stdin {
+                let stdin = fs::read(path).with_context(|| {
+                    format!("failed to read stdin from file: {}", path.display())
+                })?;
+                hash_bytes(&mut hasher, &stdin);
+            }
+            for (_, dir) in wizer.preopens()? {
+                let symlink = hash_dir(&mut hasher, &dir)
+                    .with_context(|| format!("failed to hash directory: {}", dir.display()))?;
+                if let Some(symlink) = symlink {
+                    log::info!(
+                        "Not caching the wizened module: {} is a symlink, whose target might change",
+                        symlink.display()
+                    );
+                    return Ok(None);
+                }
+            }
+            if let Some(vfs) = vfs {
+                hash_bytes(&mut hasher, vfs.sha256_hex().as_bytes());
+            }
+        }
+
+        let key = crate::provenance::hex(&hasher.finalize());
+        Ok(Some(Entry {
+            path: dir.join(format!("{}.wasm", key)),
+        }))
+    }
+
+    /// Get the cached wizened module, if any.
+    pub(crate) fn get(&self) -> Option<Vec<u8>> {
+        match fs::read(&self.path) {
+            Ok(wasm) => {
+                log::info!("Using the cached wizened module {}", self.path.display());
+                Some(wasm)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                log::warn!(
+                    "Failed to read the cached wizened module {}: {}",
+                    self.path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Wrap the given output in a writer that also writes everything to this
+    /// cache entry, which is stored once the writer is finished.
+    ///
+    /// Failing to write the cache entry only logs a warning, since the module
+    /// is still wizened.
+    pub(crate) fn tee<'a, W: Write + ?Sized>(&self, output: &'a mut W) -> Tee<'a, W> {
+        // Write to a temporary file first, and then rename it into place, so
+        // that concurrent wizenings never see a partially written entry.
+        static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+        let temp = self.path.with_extension(format!(
+            "wasm.{}.{}.tmp",
+            std::process::id(),
+            NEXT_TEMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = fs::create_dir_all(self.path.parent().unwrap())
+            .context("failed to create the cache directory")
+            .and_then(|()| File::create(&temp).context("failed to create the cache entry"));
+        let mut tee = Tee {
+            output,
+            path: self.path.clone(),
+            temp: None,
+        };
+        match file {
+            Ok(file) => tee.temp = Some((BufWriter::new(file), temp)),
+            Err(e) => tee.warn(e),
+        }
+        tee
+    }
+}
+
+/// WASI functions whose results differ from run to run.
+const NONDETERMINISTIC_WASI_FUNCS: &[&str] = &["clock_time_get", "random_get"];
+
+/// Why the given `Wizer`'s wizenings of the given Wasm can't be cached, if
+/// they can't.
+fn uncacheable(wizer: &Wizer, wasm: &[u8]) -> Option<String> {
+    if wizer.wasmtime_config_hook.is_some() {
+        return Some("a Wasmtime config hook is set".into());
+    }
+    if wizer.heap_image_dir.is_some() {
+        return Some("heap images are written".into());
+    }
+    if !wizer.allow_wasi {
+        return None;
+    }
+    if wizer.inherit_stdio.unwrap_or(crate::DEFAULT_INHERIT_STDIO) {
+        return Some("stdio is inherited".into());
+    }
+    if wizer.inherit_env.unwrap_or(crate::DEFAULT_INHERIT_ENV) {
+        return Some("environment variables are inherited".into());
+    }
+    if !wizer.read_only_dirs && (!wizer.dirs.is_empty() || !wizer.map_dirs.is_empty()) {
+        return Some("preopened directories are writable".into());
+    }
+    if let Some(func) = nondeterministic_import(wasm) {
+        return Some(format!("initialization can call WASI's `{}`", func));
+    }
+    None
+}
+
+/// Find an import of a WASI function whose results differ from run to run, in
+/// the given Wasm or any of its nested modules.
+///
+/// Wasm that doesn't parse fails to wizen anyway, so it has none.
+fn nondeterministic_import(wasm: &[u8]) -> Option<&str> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        let mut imports = match payload {
+            Ok(wasmparser::Payload::ImportSection(imports)) => imports,
+            Ok(_) => continue,
+            Err(_) => return None,
+        };
+        for _ in 0..imports.get_count() {
+            let import = imports.read().ok()?;
+            if let Some(field) = import.field {
+                if crate::dummy::is_wasi(import.module)
+                    && NONDETERMINISTIC_WASI_FUNCS.contains(&field)
+                {
+                    return Some(field);
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Hash the options that can change the wizened module.
+///
+/// Every field is named here, so that adding an option to `Wizer` means
+/// deciding whether it belongs in the key.
+fn hash_options(hasher: &mut Sha256, wizer: &Wizer) {
+    let Wizer {
+        init_func,
+        init_args,
+        init_arg_vals,
+        check_init_status,
+        func_renames,
+        allow_wasi,
+        stub_imports,
+        stub_import_vals,
+        inherit_stdio,
+        inherit_env,
+        dirs,
+        map_dirs,
+        read_only_dirs,
+        vfs_tars,
+        vfs_files,
+        wasi_args,
+        wasi_envs,
+        wasi_stdin,
+        wasi_stdin_bytes,
+        wasm_multi_memory,
+        wasm_multi_value,
+        wasm_module_linking,
+        max_data_segments,
+        data_segment_merge_gap,
+        single_data_segment_per_memory,
+        page_aligned_data_segments,
+        data_segment_page_size,
+        shrink_memory_mins,
+        heap_end_export,
+        exclude_memory_ranges,
+        exclude_memory_range_globals,
+        zero_shadow_stack,
+        stack_pointer_export,
+        stack_size,
+        compress_data,
+        dedupe_data_segments,
+        provenance,
+        allow_rewizening,
+        dry_run,
+        nan_canonicalization,
+
+        // These only change how initialization runs, or what is done with the
+        // wizened module afterwards.
+        dirty_page_tracking: _,
+        cache_dir: _,
+        target: _,
+        opt_level: _,
+        cranelift_enable: _,
+        cranelift_set: _,
+        wasmtime_cache: _,
+        wasmtime_cache_config: _,
+        init_opt_level: _,
+        debug_info: _,
+
+        // Wizenings with these set aren't cached at all.
+        heap_image_dir: _,
+        wasmtime_config_hook: _,
+    } = wizer;
+
+    init_func.hash_key(hasher);
+    init_args.hash_key(hasher);
+    init_arg_vals.hash_key(hasher);
+    check_init_status.hash_key(hasher);
+    func_renames.hash_key(hasher);
+    allow_wasi.hash_key(hasher);
+    stub_imports.hash_key(hasher);
+    stub_import_vals.hash_key(hasher);
+    inherit_stdio.hash_key(hasher);
+    inherit_env.hash_key(hasher);
+    dirs.hash_key(hasher);
+    map_dirs.hash_key(hasher);
+    read_only_dirs.hash_key(hasher);
+    vfs_tars.hash_key(hasher);
+    vfs_files.len().hash_key(hasher);
+    for (path, contents) in vfs_files {
+        path.hash_key(hasher);
+        hash_bytes(hasher, contents);
+    }
+    wasi_args.hash_key(hasher);
+    wasi_envs.hash_key(hasher);
+    wasi_stdin.hash_key(hasher);
+    match wasi_stdin_bytes {
+        None => hasher.update([0]),
+        Some(bytes) => {
+            hasher.update([1]);
+            hash_bytes(hasher, bytes);
+        }
+    }
+    wasm_multi_memory.hash_key(hasher);
+    wasm_multi_value.hash_key(hasher);
+    wasm_module_linking.hash_key(hasher);
+    max_data_segments.hash_key(hasher);
+    data_segment_merge_gap.hash_key(hasher);
+    single_data_segment_per_memory.hash_key(hasher);
+    page_aligned_data_segments.hash_key(hasher);
+    data_segment_page_size.hash_key(hasher);
+    shrink_memory_mins.hash_key(hasher);
+    heap_end_export.hash_key(hasher);
+    exclude_memory_ranges.hash_key(hasher);
+    exclude_memory_range_globals.hash_key(hasher);
+    zero_shadow_stack.hash_key(hasher);
+    stack_pointer_export.hash_key(hasher);
+    stack_size.hash_key(hasher);
+    compress_data.hash_key(hasher);
+    dedupe_data_segments.hash_key(hasher);
+    provenance.hash_key(hasher);
+    allow_rewizening.hash_key(hasher);
+    dry_run.hash_key(hasher);
+    nan_canonicalization.hash_key(hasher);
+}
+
+/// A value that can be part of a cache key.
+///
+/// Unlike `Debug` output, the encoding is fixed, and every variable-length
+/// value is prefixed with its length, so different values never hash alike.
+trait HashKey {
+    fn hash_key(&self, hasher: &mut Sha256);
+}
+
+impl HashKey for bool {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        hasher.update([*self as u8]);
+    }
+}
+
+impl HashKey for u32 {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        hasher.update(self.to_le_bytes());
+    }
+}
+
+impl HashKey for usize {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        hasher.update((*self as u64).to_le_bytes());
+    }
+}
+
+impl HashKey for String {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        hash_bytes(hasher, self.as_bytes());
+    }
+}
+
+impl HashKey for PathBuf {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            hash_bytes(hasher, self.as_os_str().as_bytes());
+        }
+        #[cfg(not(unix))]
+        hash_bytes(hasher, self.to_string_lossy().as_bytes());
+    }
+}
+
+impl HashKey for wasmtime::Val {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        // Floats are hashed by their bits, so that NaN payloads count.
+        match self {
+            wasmtime::Val::I32(x) => {
+                hasher.update(b"i32");
+                hasher.update(x.to_le_bytes());
+            }
+            wasmtime::Val::I64(x) => {
+                hasher.update(b"i64");
+                hasher.update(x.to_le_bytes());
+            }
+            wasmtime::Val::F32(bits) => {
+                hasher.update(b"f32");
+                hasher.update(bits.to_le_bytes());
+            }
+            wasmtime::Val::F64(bits) => {
+                hasher.update(b"f64");
+                hasher.update(bits.to_le_bytes());
+            }
+            wasmtime::Val::V128(x) => {
+                hasher.update(b"v128");
+                hasher.update(x.to_le_bytes());
+            }
+            // Initialization rejects reference values, so wizenings with
+            // them always fail, and are never cached.
+            wasmtime::Val::ExternRef(_) => hasher.update(b"externref"),
+            wasmtime::Val::FuncRef(_) => hasher.update(b"funcref"),
+        }
+    }
+}
+
+impl<T: HashKey> HashKey for Option<T> {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        match self {
+            None => hasher.update([0]),
+            Some(x) => {
+                hasher.update([1]);
+                x.hash_key(hasher);
+            }
+        }
+    }
+}
+
+impl<T: HashKey> HashKey for Vec<T> {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        self.len().hash_key(hasher);
+        for x in self {
+            x.hash_key(hasher);
+        }
+    }
+}
+
+impl<A: HashKey, B: HashKey, C: HashKey> HashKey for (A, B, C) {
+    fn hash_key(&self, hasher: &mut Sha256) {
+        self.0.hash_key(hasher);
+        self.1.hash_key(hasher);
+        self.2.hash_key(hasher);
+    }
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_le_bytes());
+    hasher.update(bytes);
+}
+
+/// Hash the names, kinds, and contents of everything in the given directory,
+/// recursively.
+///
+/// Returns the first symlink found instead, if there is one, since what it
+/// points to can change without anything in the directory changing.
+fn hash_dir(hasher: &mut Sha256, dir: &Path) -> io::Result<Option<PathBuf>> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    hasher.update((entries.len() as u64).to_le_bytes());
+    for path in entries {
+        hash_bytes(
+            hasher,
+            path.file_name().unwrap().to_string_lossy().as_bytes(),
+        );
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_symlink() {
+            return Ok(Some(path));
+        } else if file_type.is_dir() {
+            hasher.update(b"d");
+            if let Some(symlink) = hash_dir(hasher, &path)? {
+                return Ok(Some(symlink));
+            }
+        } else {
+            hasher.update(b"f");
+            hash_bytes(hasher, &fs::read(&path)?);
+        }
+    }
+    Ok(None)
+}
+
+/// A writer that also writes everything written to it to a cache entry.
+pub(crate) struct Tee<'a, W: ?Sized> {
+    output: &'a mut W,
+
+    /// Where the cache entry goes.
+    path: PathBuf,
+
+    /// The temporary file that the cache entry is written to, and its path,
+    /// unless writing it failed.
+    temp: Option<(BufWriter<File>, PathBuf)>,
+}
+
+impl<W: ?Sized> Tee<'_, W> {
+    /// Move the written cache entry into place.
+    pub(crate) fn finish(mut self) {
+        if let Some((file, temp)) = self.temp.take() {
+            let result = file
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .context("failed to write the cache entry")
+                .and_then(|_| {
+                    fs::rename(&temp, &self.path).context("failed to rename the cache entry")
+                });
+            if let Err(e) = result {
+                let _ = fs::remove_file(&temp);
+                self.warn(e);
+            }
+        }
+    }
+
+    /// Stop writing the cache entry, and remove what was written of it.
+    fn discard(&mut self) {
+        if let Some((file, temp)) = self.temp.take() {
+            drop(file);
+            let _ = fs::remove_file(temp);
+        }
+    }
+
+    fn warn(&self, e: anyhow::Error) {
+        log::warn!(
+            "Failed to cache the wizened module {}: {:#}",
+            self.path.display(),
+            e
+        );
+    }
+}
+
+impl<W: Write + ?Sized> Write for Tee<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.output.write(buf)?;
+        if let Some((file, _)) = &mut self.temp {
+            if let Err(e) = file.write_all(&buf[..n]) {
+                self.discard();
+                self.warn(anyhow::Error::new(e).context("failed to write the cache entry"));
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl<W: ?Sized> Drop for Tee<'_, W> {
+    fn drop(&mut self) {
+        // Wizening failed, so there's nothing to cache.
+        self.discard();
+    }
+}
@@ -0,0 +1,428 @@
+//! Compressing the snapshot's data segments.
+//!
+//! For each memory, we compress the image of the memory's initialized region
+//! with LZ4 and put it in a passive data segment. Then we inject a small LZ4
+//! decompressor written in Wasm, and the start function decompresses each
+//! image into place:
+//!
+//! ```wat
+//! (func $start
+//!   ;; Copy the compressed image into zeroed scratch space.
+//!   (memory.init $compressed (i32.const $scratch) (i32.const 0) (i32.const $len))
+//!   ;; Decompress it to where the data segments would have put it.
+//!   (call $decompress (i32.const $scratch) (i32.const $len) (i32.const $dst))
+//!   ;; Zero the scratch space again and free the compressed image.
+//!   (memory.fill (i32.const $scratch) (i32.const 0) (i32.const $len))
+//!   (data.drop $compressed)
+//! )
+//! ```
+
+use crate::passive_data::ActiveSegment;
+use std::convert::TryFrom;
+use wasm_encoder::Instruction;
+
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+/// A memory's initialized region, compressed into one passive data segment.
+pub(crate) struct CompressedImage {
+    pub memory_index: u32,
+
+    /// Where the decompressed image starts in memory.
+    pub offset: u32,
+
+    /// The LZ4-compressed image.
+    pub compressed: Vec<u8>,
+
+    /// Where we copy the compressed image to in memory before decompressing
+    /// it.
+    pub scratch: u32,
+}
+
+/// Compress the initialized region of each memory, and decide where to put the
+/// compressed image before decompressing it.
+///
+/// The scratch space has to be zeroed memory within the memory's minimum size,
+/// either after the initialized region or before it, since growing the
+/// minimum would change the `memory.size` that the program sees.
+pub(crate) fn compress_images(
+    segments: &[ActiveSegment],
+    memories: &[wasmparser::MemoryType],
+) -> anyhow::Result<Vec<CompressedImage>> {
+    let mut images = vec![];
+
+    for (memory_index, mem) in memories.iter().enumerate() {
+        let memory_index = u32::try_from(memory_index).unwrap();
+        let segs: Vec<_> = segments
+            .iter()
+            .filter(|s| s.memory_index == memory_index)
+            .collect();
+        let (first, last) = match (segs.first(), segs.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue,
+        };
+
+        // Reconstruct the memory image between the first and last segment.
+        let start = first.offset;
+        let end = last.offset + u32::try_from(last.data.len()).unwrap();
+        let mut image = vec![0; usize::try_from(end - start).unwrap()];
+        for seg in &segs {
+            let at = usize::try_from(seg.offset - start).unwrap();
+            image[at..at + seg.data.len()].copy_from_slice(seg.data);
+        }
+
+        let compressed = lz4_compress(&image);
+        let uncompressed_len: usize = segs.iter().map(|s| s.data.len()).sum();
+        if compressed.len() >= uncompressed_len {
+            log::debug!(
+                "Not compressing memory {}: no gain over {} bytes of data segments",
+                memory_index,
+                uncompressed_len
+            );
+            continue;
+        }
+
+        let min = match mem {
+            wasmparser::MemoryType::M32 { limits, .. } => limits.initial,
+            wasmparser::MemoryType::M64 { .. } => continue,
+        };
+        let len = u64::try_from(compressed.len()).unwrap();
+        let min_bytes = u64::from(min) * WASM_PAGE_SIZE;
+        let scratch = if u64::from(end) + len <= min_bytes {
+            min_bytes - len
+        } else if len <= u64::from(start) {
+            u64::from(start) - len
+        } else {
+            anyhow::bail!(
+                "cannot compress memory {}: its {}-byte compressed image doesn't fit in the \
+                 zeroed memory before or after its initialized region within its minimum \
+                 size of {} pages",
+                memory_index,
+                len,
+                min
+            );
+        };
+
+        log::info!(
+            "Compressed memory {}'s {} bytes of data segments into {} bytes",
+            memory_index,
+            uncompressed_len,
+            compressed.len()
+        );
+        images.push(CompressedImage {
+            memory_index,
+            offset: start,
+            compressed,
+            scratch: u32::try_from(scratch).unwrap(),
+        });
+    }
+
+    Ok(images)
+}
+
+/// The smallest match that LZ4 can encode.
+const LZ4_MIN_MATCH: usize = 4;
+
+/// LZ4 requires the last match to start at least this many bytes before the
+/// end of the input.
+const LZ4_MF_LIMIT: usize = 12;
+
+/// LZ4 requires the last this many bytes of input to be literals.
+const LZ4_LAST_LITERALS: usize = 5;
+
+/// Compress `input` into an LZ4 block.
+///
+/// This is a simple greedy compressor that finds matches through a hash table
+/// of the most recent position of every four byte sequence.
+fn lz4_compress(input: &[u8]) -> Vec<u8> {
+    const HASH_LOG: u32 = 16;
+
+    let hash = |i: usize| {
+        let word = u32::from_le_bytes([input[i], input[i + 1], input[i + 2], input[i + 3]]);
+        (word.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+    };
+
+    let mut out = vec![];
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut i = 0;
+
+    if input.len() > LZ4_MF_LIMIT {
+        let match_limit = input.len() - LZ4_MF_LIMIT;
+        let end_limit = input.len() - LZ4_LAST_LITERALS;
+        while i < match_limit {
+            let h = hash(i);
+            let candidate = table[h];
+            table[h] = i;
+
+            if candidate == usize::MAX
+                || i - candidate > 0xffff
+                || input[candidate..candidate + LZ4_MIN_MATCH] != input[i..i + LZ4_MIN_MATCH]
+            {
+                i += 1;
+                continue;
+            }
+
+            let mut len = LZ4_MIN_MATCH;
+            while i + len < end_limit && input[candidate + len] == input[i + len] {
+                len += 1;
+            }
+
+            lz4_sequence(&mut out, &input[anchor..i], Some((i - candidate, len)));
+            i += len;
+            anchor = i;
+        }
+    }
+
+    lz4_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+/// Encode one LZ4 sequence: a run of literals followed by a match, except for
+/// the last sequence, which only has literals.
+fn lz4_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - LZ4_MIN_MATCH);
+    let token = (std::cmp::min(literals.len(), 15) << 4) | std::cmp::min(match_len, 15);
+    out.push(token as u8);
+    lz4_length(out, literals.len());
+    out.extend_from_slice(literals);
+
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        lz4_length(out, match_len);
+    }
+}
+
+/// Encode the remainder of a length that didn't fit in its token nibble.
+fn lz4_length(out: &mut Vec<u8>, len: usize) {
+    if len < 15 {
+        return;
+    }
+    let mut rest = len - 15;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+/// Generate a function of type `[src: i32, len: i32, dst: i32] -> []` that
+/// decompresses the LZ4 block at `src..src+len` into memory starting at `dst`.
+pub(crate) fn decompressor(memory_index: u32) -> wasm_encoder::Function {
+    const SRC: u32 = 0;
+    const LEN: u32 = 1;
+    const DST: u32 = 2;
+    const END: u32 = 3;
+    const TOKEN: u32 = 4;
+    const N: u32 = 5;
+    const BYTE: u32 = 6;
+    const MATCH_SRC: u32 = 7;
+
+    let memarg = |align| wasm_encoder::MemArg {
+        offset: 0,
+        align,
+        memory_index,
+    };
+    let copy = Instruction::MemoryCopy {
+        src: memory_index,
+        dst: memory_index,
+    };
+
+    let mut f = wasm_encoder::Function::new(vec![(5, wasm_encoder::ValType::I32)]);
+    let mut ins = |i: Instruction| {
+        f.instruction(i);
+    };
+
+    // Read a length that continues in following bytes when its token nibble
+    // is 15, adding each byte to the `N` local.
+    let read_length = |ins: &mut dyn FnMut(Instruction)| {
+        ins(Instruction::LocalGet(N));
+        ins(Instruction::I32Const(15));
+        ins(Instruction::I32Eq);
+        ins(Instruction::If(wasm_encoder::BlockType::Empty));
+        ins(Instruction::Loop(wasm_encoder::BlockType::Empty));
+        ins(Instruction::LocalGet(SRC));
+        ins(Instruction::I32Load8_U(memarg(0)));
+        ins(Instruction::LocalSet(BYTE));
+        ins(Instruction::LocalGet(SRC));
+        ins(Instruction::I32Const(1));
+        ins(Instruction::I32Add);
+        ins(Instruction::LocalSet(SRC));
+        ins(Instruction::LocalGet(N));
+        ins(Instruction::LocalGet(BYTE));
+        ins(Instruction::I32Add);
+        ins(Instruction::LocalSet(N));
+        ins(Instruction::LocalGet(BYTE));
+        ins(Instruction::I32Const(255));
+        ins(Instruction::I32Eq);
+        ins(Instruction::BrIf(0));
+        ins(Instruction::End);
+        ins(Instruction::End);
+    };
+
+    // end = src + len
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::LocalGet(LEN));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(END));
+
+    ins(Instruction::Block(wasm_encoder::BlockType::Empty));
+    ins(Instruction::Loop(wasm_encoder::BlockType::Empty));
+
+    // token = *src++
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::I32Load8_U(memarg(0)));
+    ins(Instruction::LocalSet(TOKEN));
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::I32Const(1));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(SRC));
+
+    // Copy the literals.
+    ins(Instruction::LocalGet(TOKEN));
+    ins(Instruction::I32Const(4));
+    ins(Instruction::I32ShrU);
+    ins(Instruction::LocalSet(N));
+    read_length(&mut ins);
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::LocalGet(N));
+    ins(copy);
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(N));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(DST));
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::LocalGet(N));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(SRC));
+
+    // The last sequence has no match.
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::LocalGet(END));
+    ins(Instruction::I32GeU);
+    ins(Instruction::BrIf(1));
+
+    // match_src = dst - offset
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::I32Load16_U(memarg(0)));
+    ins(Instruction::I32Sub);
+    ins(Instruction::LocalSet(MATCH_SRC));
+    ins(Instruction::LocalGet(SRC));
+    ins(Instruction::I32Const(2));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(SRC));
+
+    // The match length.
+    ins(Instruction::LocalGet(TOKEN));
+    ins(Instruction::I32Const(15));
+    ins(Instruction::I32And);
+    ins(Instruction::LocalSet(N));
+    read_length(&mut ins);
+    ins(Instruction::LocalGet(N));
+    ins(Instruction::I32Const(LZ4_MIN_MATCH as i32));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(N));
+
+    // Copy the match. It may overlap the output that it produces, in which
+    // case the output repeats with a period of `dst - match_src`, so we copy
+    // it in chunks that double in size.
+    ins(Instruction::Loop(wasm_encoder::BlockType::Empty));
+    // byte = min(dst - match_src, n)
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(MATCH_SRC));
+    ins(Instruction::I32Sub);
+    ins(Instruction::LocalGet(N));
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(MATCH_SRC));
+    ins(Instruction::I32Sub);
+    ins(Instruction::LocalGet(N));
+    ins(Instruction::I32LtU);
+    ins(Instruction::Select);
+    ins(Instruction::LocalSet(BYTE));
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(MATCH_SRC));
+    ins(Instruction::LocalGet(BYTE));
+    ins(copy);
+    ins(Instruction::LocalGet(DST));
+    ins(Instruction::LocalGet(BYTE));
+    ins(Instruction::I32Add);
+    ins(Instruction::LocalSet(DST));
+    ins(Instruction::LocalGet(N));
+    ins(Instruction::LocalGet(BYTE));
+    ins(Instruction::I32Sub);
+    ins(Instruction::LocalTee(N));
+    ins(Instruction::BrIf(0));
+    ins(Instruction::End);
+
+    ins(Instruction::Br(0));
+    ins(Instruction::End);
+    ins(Instruction::End);
+    ins(Instruction::End);
+
+    f
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lz4_decompress(mut input: &[u8]) -> Vec<u8> {
+        fn length(input: &mut &[u8], mut n: usize) -> usize {
+            if n == 15 {
+                loop {
+                    let byte = input[0];
+                    *input = &input[1..];
+                    n += usize::from(byte);
+                    if byte != 255 {
+                        break;
+                    }
+                }
+            }
+            n
+        }
+
+        let mut out = vec![];
+        loop {
+            let token = input[0];
+            input = &input[1..];
+            let literals = length(&mut input, usize::from(token >> 4));
+            out.extend_from_slice(&input[..literals]);
+            input = &input[literals..];
+            if input.is_empty() {
+                return out;
+            }
+            let offset = usize::from(u16::from_le_bytes([input[0], input[1]]));
+            input = &input[2..];
+            let len = length(&mut input, usize::from(token & 15)) + LZ4_MIN_MATCH;
+            for _ in 0..len {
+                out.push(out[out.len() - offset]);
+            }
+        }
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        let mut inputs = vec![
+            vec![],
+            vec![1],
+            vec![0; 100_000],
+            (0..1000u32).map(|i| (i % 7) as u8).collect::<Vec<_>>(),
+            b"the quick brown fox jumps over the lazy dog".repeat(100),
+        ];
+        let mut noisy = vec![0; 200_000];
+        let mut x = 1u32;
+        for (i, b) in noisy.iter_mut().enumerate() {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            if i % 3 == 0 {
+                *b = (x >> 24) as u8;
+            }
+        }
+        inputs.push(noisy);
+
+        for input in inputs {
+            let compressed = lz4_compress(&input);
+            assert_eq!(lz4_decompress(&compressed), input);
+        }
+    }
+}
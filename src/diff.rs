@@ -0,0 +1,564 @@
+//! Comparing the initialized state of two wizened modules.
+//!
+//! Each module's memory images are reconstructed from its active data
+//! segments, and compared byte by byte, along with its globals' initial values
+//! and its memories' minimum sizes. Images are kept sparse, so that comparing
+//! a data segment at a high address doesn't mean comparing all the zeroes
+//! below it.
+
+use crate::parse;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use wasm_encoder::SectionId;
+
+/// The differences between the initialized state of two Wasm modules, such as
+/// two wizenings of the same module.
+///
+/// See [`ModuleDiff::new`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleDiff {
+    /// The size of the old module, in bytes.
+    pub old_size: u64,
+
+    /// The size of the new module, in bytes.
+    pub new_size: u64,
+
+    /// The number of data segments in the old module.
+    pub old_data_segments: u64,
+
+    /// The number of data segments in the new module.
+    pub new_data_segments: u64,
+
+    /// The total size of the old module's data segments' data, in bytes.
+    pub old_data_size: u64,
+
+    /// The total size of the new module's data segments' data, in bytes.
+    pub new_data_size: u64,
+
+    /// The globals whose initial values differ.
+    pub globals: Vec<GlobalDiff>,
+
+    /// The memories whose minimum sizes or initial contents differ.
+    pub memories: Vec<MemoryDiff>,
+}
+
+/// A global whose initial value differs between two modules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GlobalDiff {
+    /// The global's index.
+    pub index: u32,
+
+    /// The global's type and initial value in the old module, such as
+    /// `i32 42`, or `None` if the old module doesn't define it.
+    pub old: Option<String>,
+
+    /// The global's type and initial value in the new module, or `None` if
+    /// the new module doesn't define it.
+    pub new: Option<String>,
+}
+
+/// A memory whose minimum size or initial contents differ between two
+/// modules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryDiff {
+    /// The memory's index.
+    pub index: u32,
+
+    /// The memory's minimum size in Wasm pages in the old module, or `None` if
+    /// the old module doesn't define it.
+    pub old_min_pages: Option<u64>,
+
+    /// The memory's minimum size in Wasm pages in the new module, or `None` if
+    /// the new module doesn't define it.
+    pub new_min_pages: Option<u64>,
+
+    /// The ranges of bytes whose initial values differ, in order.
+    pub ranges: Vec<ByteRangeDiff>,
+}
+
+/// A range of memory whose initial contents differ between two modules.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ByteRangeDiff {
+    /// The first address of the range.
+    pub start: u64,
+
+    /// The address just past the end of the range.
+    pub end: u64,
+
+    /// How the bytes in the range differ.
+    pub kind: ByteRangeDiffKind,
+}
+
+/// How the bytes of a [`ByteRangeDiff`] differ.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRangeDiffKind {
+    /// The bytes are zero in the old module, and non-zero in the new one.
+    Added,
+
+    /// The bytes are non-zero in the old module, and zero in the new one.
+    Removed,
+
+    /// The bytes are non-zero in both modules, but differ.
+    Changed,
+}
+
+impl ModuleDiff {
+    /// Compare the initialized state of the two given Wasm modules.
+    ///
+    /// Only active data segments with constant offsets are supported, and
+    /// modules that use module linking are not.
+    pub fn new(old: &[u8], new: &[u8]) -> anyhow::Result<ModuleDiff> {
+        let old_state = State::new(old)?;
+        let new_state = State::new(new)?;
+
+        let mut diff = ModuleDiff {
+            old_size: u64::try_from(old.len()).unwrap(),
+            new_size: u64::try_from(new.len()).unwrap(),
+            old_data_segments: old_state.data_segments,
+            new_data_segments: new_state.data_segments,
+            old_data_size: old_state.data_size,
+            new_data_size: new_state.data_size,
+            ..Default::default()
+        };
+
+        let num_globals = std::cmp::max(old_state.globals.len(), new_state.globals.len());
+        for i in 0..num_globals {
+            let old = old_state.globals.get(i).cloned().flatten();
+            let new = new_state.globals.get(i).cloned().flatten();
+            if old != new {
+                diff.globals.push(GlobalDiff {
+                    index: u32::try_from(i).unwrap(),
+                    old,
+                    new,
+                });
+            }
+        }
+
+        let num_memories = std::cmp::max(old_state.memories.len(), new_state.memories.len());
+        for i in 0..num_memories {
+            let old = old_state.memories.get(i).and_then(|m| m.as_ref());
+            let new = new_state.memories.get(i).and_then(|m| m.as_ref());
+            let old_min_pages = old.map(|m| m.min_pages);
+            let new_min_pages = new.map(|m| m.min_pages);
+            let empty = Image::default();
+            let ranges = diff_images(
+                old.map_or(&empty, |m| &m.image),
+                new.map_or(&empty, |m| &m.image),
+            );
+            if old_min_pages != new_min_pages || !ranges.is_empty() {
+                diff.memories.push(MemoryDiff {
+                    index: u32::try_from(i).unwrap(),
+                    old_min_pages,
+                    new_min_pages,
+                    ranges,
+                });
+            }
+        }
+
+        Ok(diff)
+    }
+
+    /// Whether the two modules have the same initialized state.
+    ///
+    /// This ignores everything else about them, including their sizes.
+    pub fn is_empty(&self) -> bool {
+        self.globals.is_empty() && self.memories.is_empty()
+    }
+}
+
+impl fmt::Display for ModuleDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "module size: {} -> {} bytes ({})",
+            self.old_size,
+            self.new_size,
+            Delta(self.old_size, self.new_size)
+        )?;
+        writeln!(
+            f,
+            "data segments: {} -> {} ({})",
+            self.old_data_segments,
+            self.new_data_segments,
+            Delta(self.old_data_segments, self.new_data_segments)
+        )?;
+        writeln!(
+            f,
+            "data size: {} -> {} bytes ({})",
+            self.old_data_size,
+            self.new_data_size,
+            Delta(self.old_data_size, self.new_data_size)
+        )?;
+        for global in &self.globals {
+            writeln!(
+                f,
+                "global {}: {} -> {}",
+                global.index,
+                global.old.as_deref().unwrap_or("none"),
+                global.new.as_deref().unwrap_or("none")
+            )?;
+        }
+        for memory in &self.memories {
+            let pages = |p: Option<u64>| p.map_or("none".to_string(), |p| p.to_string());
+            if memory.old_min_pages != memory.new_min_pages {
+                writeln!(
+                    f,
+                    "memory {}: minimum pages {} -> {}",
+                    memory.index,
+                    pages(memory.old_min_pages),
+                    pages(memory.new_min_pages)
+                )?;
+            }
+            for range in &memory.ranges {
+                let kind = match range.kind {
+                    ByteRangeDiffKind::Added => "added",
+                    ByteRangeDiffKind::Removed => "removed",
+                    ByteRangeDiffKind::Changed => "changed",
+                };
+                writeln!(
+                    f,
+                    "memory {}: {} {:#x}..{:#x} ({} bytes)",
+                    memory.index,
+                    kind,
+                    range.start,
+                    range.end,
+                    range.end - range.start
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The signed difference between two sizes.
+struct Delta(u64, u64);
+
+impl fmt::Display for Delta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 >= self.0 {
+            write!(f, "+{}", self.1 - self.0)
+        } else {
+            write!(f, "-{}", self.0 - self.1)
+        }
+    }
+}
+
+/// The initialized state of a module.
+struct State {
+    /// Each global's type and initial value, or `None` for imported globals.
+    globals: Vec<Option<String>>,
+
+    /// Each memory, or `None` for imported memories.
+    memories: Vec<Option<Memory>>,
+
+    data_segments: u64,
+    data_size: u64,
+}
+
+struct Memory {
+    min_pages: u64,
+
+    /// The memory's initial contents.
+    image: Image,
+}
+
+/// A sparse memory image.
+#[derive(Default)]
+struct Image {
+    /// Disjoint, non-adjacent chunks of initialized bytes, keyed by their
+    /// addresses. Everything else is zero.
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Image {
+    /// Write the given bytes at the given address, as a data segment would.
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + u64::try_from(data.len()).unwrap();
+
+        // Merge the new bytes with every chunk that they overlap or touch.
+        let start = match self.chunks.range(..=offset).next_back() {
+            Some((&start, chunk)) if start + u64::try_from(chunk.len()).unwrap() >= offset => start,
+            _ => offset,
+        };
+        let merged: Vec<u64> = self.chunks.range(start..=end).map(|(&s, _)| s).collect();
+        let mut bytes = vec![];
+        for chunk_start in merged {
+            let chunk = self.chunks.remove(&chunk_start).unwrap();
+            copy_at(&mut bytes, chunk_start - start, &chunk);
+        }
+        copy_at(&mut bytes, offset - start, data);
+        self.chunks.insert(start, bytes);
+    }
+
+    /// Get the bytes from `start` to `end`, if they are initialized.
+    ///
+    /// No chunk may start or end between `start` and `end`.
+    fn get(&self, start: u64, end: u64) -> Option<&[u8]> {
+        let (&chunk_start, chunk) = self.chunks.range(..=start).next_back()?;
+        let chunk_end = chunk_start + u64::try_from(chunk.len()).unwrap();
+        if chunk_end <= start {
+            return None;
+        }
+        debug_assert!(end <= chunk_end);
+        let index = |address: u64| usize::try_from(address - chunk_start).unwrap();
+        Some(&chunk[index(start)..index(end)])
+    }
+}
+
+/// Copy `data` into `bytes` at the given index, growing `bytes` as needed.
+fn copy_at(bytes: &mut Vec<u8>, index: u64, data: &[u8]) {
+    let index = usize::try_from(index).unwrap();
+    let end = index + data.len();
+    if bytes.len() < end {
+        bytes.resize(end, 0);
+    }
+    bytes[index..end].copy_from_slice(data);
+}
+
+impl State {
+    fn new(wasm: &[u8]) -> anyhow::Result<State> {
+        let cx = parse::parse(wasm)?;
+        if cx.uses_module_linking() {
+            anyhow::bail!("comparing modules that use module linking is not supported");
+        }
+        let module = cx.root();
+
+        let num_globals = module.defined_globals_index(&cx).unwrap_or(0)
+            + u32::try_from(module.defined_globals_len(&cx)).unwrap();
+        let mut globals = vec![None; usize::try_from(num_globals).unwrap()];
+        let mut memories: Vec<Option<Memory>> =
+            (0..module.defined_memories_index(&cx).unwrap_or(0))
+                .map(|_| None)
+                .collect();
+        for (_, ty) in module.defined_memories(&cx) {
+            let min_pages = match ty {
+                wasmparser::MemoryType::M32 { limits, .. } => limits.initial.into(),
+                wasmparser::MemoryType::M64 { limits, .. } => limits.initial,
+            };
+            memories.push(Some(Memory {
+                min_pages,
+                image: Image::default(),
+            }));
+        }
+
+        let mut data_segments = 0;
+        let mut data_size = 0;
+        for section in module.raw_sections(&cx) {
+            if section.id == SectionId::Global.into() {
+                let mut reader = wasmparser::GlobalSectionReader::new(section.data, 0)?;
+                let first = module.defined_globals_index(&cx).unwrap_or(0);
+                for i in 0..reader.get_count() {
+                    let global = reader.read()?;
+                    let value = format_init_expr(&global.init_expr)?;
+                    globals[usize::try_from(first + i).unwrap()] =
+                        Some(format!("{} {}", type_name(global.ty.content_type), value));
+                }
+            } else if section.id == SectionId::Data.into() {
+                let mut reader = wasmparser::DataSectionReader::new(section.data, 0)?;
+                for _ in 0..reader.get_count() {
+                    let data = reader.read()?;
+                    data_segments += 1;
+                    data_size += u64::try_from(data.data.len()).unwrap();
+                    let (memory_index, init_expr) = match data.kind {
+                        wasmparser::DataKind::Active {
+                            memory_index,
+                            init_expr,
+                        } => (memory_index, init_expr),
+                        wasmparser::DataKind::Passive => {
+                            anyhow::bail!("comparing passive data segments is not supported")
+                        }
+                    };
+                    let offset = match init_expr.get_operators_reader().read()? {
+                        wasmparser::Operator::I32Const { value } => value as u32,
+                        _ => anyhow::bail!(
+                            "comparing data segments with non-constant offsets is not supported"
+                        ),
+                    };
+                    let memory = memories
+                        .get_mut(usize::try_from(memory_index).unwrap())
+                        .and_then(|m| m.as_mut())
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("data segment for undefined memory {}", memory_index)
+                        })?;
+                    memory.image.write(offset.into(), data.data);
+                }
+            }
+        }
+
+        Ok(State {
+            globals,
+            memories,
+            data_segments,
+            data_size,
+        })
+    }
+}
+
+fn type_name(ty: wasmparser::Type) -> String {
+    match ty {
+        wasmparser::Type::I32 => "i32".to_string(),
+        wasmparser::Type::I64 => "i64".to_string(),
+        wasmparser::Type::F32 => "f32".to_string(),
+        wasmparser::Type::F64 => "f64".to_string(),
+        wasmparser::Type::V128 => "v128".to_string(),
+        wasmparser::Type::FuncRef => "funcref".to_string(),
+        wasmparser::Type::ExternRef => "externref".to_string(),
+        ty => format!("{:?}", ty),
+    }
+}
+
+fn format_init_expr(init_expr: &wasmparser::InitExpr<'_>) -> anyhow::Result<String> {
+    Ok(match init_expr.get_operators_reader().read()? {
+        wasmparser::Operator::I32Const { value } => value.to_string(),
+        wasmparser::Operator::I64Const { value } => value.to_string(),
+        // With the bits too, so that different NaNs show up as different.
+        wasmparser::Operator::F32Const { value } => {
+            format!("{} ({:#010x})", f32::from_bits(value.bits()), value.bits())
+        }
+        wasmparser::Operator::F64Const { value } => {
+            format!("{} ({:#018x})", f64::from_bits(value.bits()), value.bits())
+        }
+        wasmparser::Operator::GlobalGet { global_index } => {
+            format!("global.get {}", global_index)
+        }
+        op => format!("{:?}", op),
+    })
+}
+
+/// Find the ranges of bytes that differ between two memory images.
+///
+/// Only the bytes that are initialized in either image are compared, one
+/// stretch between chunk boundaries at a time.
+fn diff_images(old: &Image, new: &Image) -> Vec<ByteRangeDiff> {
+    let mut boundaries: Vec<u64> = old
+        .chunks
+        .iter()
+        .chain(&new.chunks)
+        .flat_map(|(&start, chunk)| [start, start + u64::try_from(chunk.len()).unwrap()])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut ranges: Vec<ByteRangeDiff> = vec![];
+    for window in boundaries.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        let (old_bytes, new_bytes) = match (old.get(start, end), new.get(start, end)) {
+            (None, None) => continue,
+            bytes => bytes,
+        };
+        for (i, address) in (start..end).enumerate() {
+            let a = old_bytes.map_or(0, |b| b[i]);
+            let b = new_bytes.map_or(0, |b| b[i]);
+            let kind = match (a, b) {
+                _ if a == b => continue,
+                (0, _) => ByteRangeDiffKind::Added,
+                (_, 0) => ByteRangeDiffKind::Removed,
+                _ => ByteRangeDiffKind::Changed,
+            };
+            match ranges.last_mut() {
+                Some(last) if last.end == address && last.kind == kind => last.end += 1,
+                _ => ranges.push(ByteRangeDiff {
+                    start: address,
+                    end: address + 1,
+                    kind,
+                }),
+            }
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(segments: &[(u64, &[u8])]) -> Image {
+        let mut image = Image::default();
+        for (offset, data) in segments {
+            image.write(*offset, data);
+        }
+        image
+    }
+
+    #[test]
+    fn image_ranges() {
+        assert_eq!(
+            diff_images(
+                &image(&[(0, &[0, 1, 2, 3, 0, 5])]),
+                &image(&[(0, &[0, 1, 9, 9, 4, 0, 0, 7])])
+            ),
+            [
+                ByteRangeDiff {
+                    start: 2,
+                    end: 4,
+                    kind: ByteRangeDiffKind::Changed,
+                },
+                ByteRangeDiff {
+                    start: 4,
+                    end: 5,
+                    kind: ByteRangeDiffKind::Added,
+                },
+                ByteRangeDiff {
+                    start: 5,
+                    end: 6,
+                    kind: ByteRangeDiffKind::Removed,
+                },
+                ByteRangeDiff {
+                    start: 7,
+                    end: 8,
+                    kind: ByteRangeDiffKind::Added,
+                },
+            ]
+        );
+        assert!(diff_images(&image(&[(0, &[1, 2, 0, 0])]), &image(&[(0, &[1, 2])])).is_empty());
+    }
+
+    #[test]
+    fn sparse_image_ranges() {
+        // Later segments overwrite earlier ones, and merge with the chunks
+        // they overlap or touch.
+        let old = image(&[
+            (10, &[1, 1, 1]),
+            (13, &[2]),
+            (11, &[3]),
+            (0xffff_fff0, &[4]),
+        ]);
+        assert_eq!(old.chunks.len(), 2);
+        assert_eq!(old.chunks[&10], [1, 3, 1, 2]);
+
+        let new = image(&[(8, &[0, 0, 1, 3, 1, 2]), (0xffff_fff1, &[5])]);
+        assert_eq!(
+            diff_images(&old, &new),
+            [
+                ByteRangeDiff {
+                    start: 0xffff_fff0,
+                    end: 0xffff_fff1,
+                    kind: ByteRangeDiffKind::Removed,
+                },
+                ByteRangeDiff {
+                    start: 0xffff_fff1,
+                    end: 0xffff_fff2,
+                    kind: ByteRangeDiffKind::Added,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn float_init_exprs() -> anyhow::Result<()> {
+        let f32_const = |bits: u32| {
+            let mut expr = vec![0x43];
+            expr.extend(bits.to_le_bytes());
+            expr.push(0x0b);
+            format_init_expr(&wasmparser::InitExpr::new(&expr, 0))
+        };
+        assert_eq!(f32_const(0x3fc0_0000)?, "1.5 (0x3fc00000)");
+        assert_eq!(f32_const(0x7fc0_0000)?, "NaN (0x7fc00000)");
+        assert_eq!(f32_const(0x7fa0_0000)?, "NaN (0x7fa00000)");
+        Ok(())
+    }
+}
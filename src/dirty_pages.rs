@@ -0,0 +1,247 @@
+//! Finding the Wasm pages that initialization never touched, so that
+//! snapshotting doesn't have to scan them for non-zero bytes.
+//!
+//! On Linux, linear memories are backed by private, anonymous mappings that
+//! we create ourselves. A page of such a mapping that was never touched is
+//! neither present in physical memory nor swapped out, and therefore is all
+//! zeros, which `/proc/self/pagemap` tells us without reading the page. Other
+//! platforms aren't supported, and always fall back to scanning every page.
+
+use std::sync::Arc;
+
+/// Get a memory creator whose memories can have their untouched pages found
+/// with `touched_wasm_pages`, if this platform supports it.
+#[cfg(target_os = "linux")]
+pub(crate) fn memory_creator() -> Option<Arc<dyn wasmtime::MemoryCreator>> {
+    Some(Arc::new(linux::AnonymousMemoryCreator))
+}
+
+/// Get a memory creator whose memories can have their untouched pages found
+/// with `touched_wasm_pages`, if this platform supports it.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn memory_creator() -> Option<Arc<dyn wasmtime::MemoryCreator>> {
+    None
+}
+
+/// For each Wasm page of the given memory, which must have been created by
+/// `memory_creator`, get whether any of it may have been touched.
+///
+/// Returns `None` if that can't be determined, in which case every page must
+/// be scanned.
+#[cfg(target_os = "linux")]
+pub(crate) fn touched_wasm_pages(memory: &[u8]) -> Option<Vec<bool>> {
+    match linux::touched_wasm_pages(memory) {
+        Ok(pages) => Some(pages),
+        Err(e) => {
+            log::warn!(
+                "Failed to find untouched pages, scanning all of memory instead: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+/// For each Wasm page of the given memory, which must have been created by
+/// `memory_creator`, get whether any of it may have been touched.
+///
+/// Returns `None` if that can't be determined, in which case every page must
+/// be scanned.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn touched_wasm_pages(_memory: &[u8]) -> Option<Vec<bool>> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use anyhow::Context;
+    use std::convert::TryFrom;
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::FileExt;
+
+    const WASM_PAGE_SIZE: usize = 65_536;
+
+    /// The page is present in physical memory.
+    const PAGEMAP_PRESENT: u64 = 1 << 63;
+
+    /// The page is swapped out.
+    const PAGEMAP_SWAPPED: u64 = 1 << 62;
+
+    /// How many pagemap entries to read at a time.
+    const PAGEMAP_CHUNK: usize = 4096;
+
+    fn page_size() -> usize {
+        usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).unwrap()
+    }
+
+    pub(super) fn touched_wasm_pages(memory: &[u8]) -> anyhow::Result<Vec<bool>> {
+        let page_size = page_size();
+        let base = memory.as_ptr() as usize;
+        anyhow::ensure!(
+            base & (page_size - 1) == 0 && page_size <= WASM_PAGE_SIZE,
+            "memory is not page aligned"
+        );
+
+        let pagemap = File::open("/proc/self/pagemap").context("failed to open the pagemap")?;
+        let num_pages = memory.len() / page_size;
+        let mut touched = vec![false; memory.len() / WASM_PAGE_SIZE];
+        let mut entries = vec![0; PAGEMAP_CHUNK * 8];
+        let mut page = 0;
+        while page < num_pages {
+            let n = std::cmp::min(PAGEMAP_CHUNK, num_pages - page);
+            let offset = (base / page_size + page) * 8;
+            pagemap
+                .read_exact_at(&mut entries[..n * 8], u64::try_from(offset).unwrap())
+                .context("failed to read the pagemap")?;
+            for (i, entry) in entries[..n * 8].chunks_exact(8).enumerate() {
+                let entry = u64::from_le_bytes(<[u8; 8]>::try_from(entry).unwrap());
+                if entry & (PAGEMAP_PRESENT | PAGEMAP_SWAPPED) != 0 {
+                    touched[(page + i) * page_size / WASM_PAGE_SIZE] = true;
+                }
+            }
+            page += n;
+        }
+        Ok(touched)
+    }
+
+    /// Creates linear memories backed by private, anonymous mappings.
+    ///
+    /// Each memory reserves its whole address space, plus its guard region,
+    /// up front, so that growing it never moves or copies it.
+    pub(super) struct AnonymousMemoryCreator;
+
+    unsafe impl wasmtime::MemoryCreator for AnonymousMemoryCreator {
+        fn new_memory(
+            &self,
+            _ty: wasmtime::MemoryType,
+            minimum: usize,
+            maximum: Option<usize>,
+            reserved_size_in_bytes: Option<usize>,
+            guard_size_in_bytes: usize,
+        ) -> Result<Box<dyn wasmtime::LinearMemory>, String> {
+            // Without a static reservation, reserve enough for any 32-bit
+            // memory.
+            let reserved = reserved_size_in_bytes
+                .or(maximum)
+                .unwrap_or(1 << 32)
+                .max(minimum);
+            let page_size = page_size();
+            let reserved = (reserved + page_size - 1) & !(page_size - 1);
+            AnonymousMemory::new(minimum, maximum, reserved, guard_size_in_bytes)
+                .map(|m| Box::new(m) as _)
+                .map_err(|e| format!("failed to create memory: {}", e))
+        }
+    }
+
+    struct AnonymousMemory {
+        base: usize,
+        size: usize,
+        maximum: Option<usize>,
+        reserved: usize,
+        mapped: usize,
+    }
+
+    impl AnonymousMemory {
+        fn new(
+            minimum: usize,
+            maximum: Option<usize>,
+            reserved: usize,
+            guard: usize,
+        ) -> io::Result<Self> {
+            let mapped = reserved + guard;
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    mapped,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                    -1,
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            let mut memory = AnonymousMemory {
+                base: ptr as usize,
+                size: 0,
+                maximum,
+                reserved,
+                mapped,
+            };
+            memory.make_accessible(minimum)?;
+            Ok(memory)
+        }
+
+        fn make_accessible(&mut self, new_size: usize) -> io::Result<()> {
+            if new_size > self.size {
+                let result = unsafe {
+                    libc::mprotect(
+                        (self.base + self.size) as *mut libc::c_void,
+                        new_size - self.size,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                    )
+                };
+                if result != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                self.size = new_size;
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for AnonymousMemory {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.base as *mut libc::c_void, self.mapped);
+            }
+        }
+    }
+
+    unsafe impl wasmtime::LinearMemory for AnonymousMemory {
+        fn byte_size(&self) -> usize {
+            self.size
+        }
+
+        fn maximum_byte_size(&self) -> Option<usize> {
+            self.maximum
+        }
+
+        fn grow_to(&mut self, new_size: usize) -> anyhow::Result<()> {
+            anyhow::ensure!(
+                new_size <= self.reserved,
+                "cannot grow memory beyond its reservation"
+            );
+            self.make_accessible(new_size)?;
+            Ok(())
+        }
+
+        fn as_ptr(&self) -> *mut u8 {
+            self.base as *mut u8
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use wasmtime::{MemoryCreator, MemoryType};
+
+        #[test]
+        fn untouched_pages() {
+            let mut memory = AnonymousMemoryCreator
+                .new_memory(MemoryType::new(2, None), 2 * WASM_PAGE_SIZE, None, None, 0)
+                .unwrap();
+            memory.grow_to(4 * WASM_PAGE_SIZE).unwrap();
+            let data =
+                unsafe { std::slice::from_raw_parts_mut(memory.as_ptr(), memory.byte_size()) };
+            data[WASM_PAGE_SIZE + 1] = 1;
+            data[3 * WASM_PAGE_SIZE + 2] = 2;
+            assert_eq!(
+                touched_wasm_pages(data).unwrap(),
+                [false, true, false, true]
+            );
+        }
+    }
+}
@@ -2,15 +2,101 @@
 //!
 //! Forked from `wasmtime/crates/fuzzing/src/oracles/dummy.rs`.
 
-use anyhow::Result;
+use crate::{DisallowedImport, WizerError};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
 use std::fmt::Write;
+use std::sync::{Arc, Mutex};
 use wasmtime::*;
 
+/// How dummy functions behave when they are called.
+#[derive(Clone, Default)]
+pub struct DummyOptions {
+    /// Whether WASI imports are allowed. If not, calls to dummy WASI functions
+    /// say that they would be allowed.
+    pub allow_wasi: bool,
+
+    /// If set, calls to dummy functions are recorded here, and return default
+    /// values instead of trapping.
+    pub record: Option<Arc<Mutex<Vec<DisallowedImport>>>>,
+
+    /// Function imports that return fixed values instead of trapping.
+    pub stubs: StubImports,
+}
+
+/// Function imports that return fixed values, rather than trapping, when they
+/// are called during initialization.
+#[derive(Clone, Default)]
+pub struct StubImports {
+    /// For each stubbed `(module, name)` import, the values it returns.
+    results: HashMap<(String, String), StubResults>,
+}
+
+/// The values that a stubbed import returns.
+#[derive(Clone)]
+enum StubResults {
+    /// Values to parse as the import's result types, as given in its spec.
+    /// Empty if it returns zeroes.
+    Text(Vec<String>),
+
+    /// Values given through the library API. Empty if it returns zeroes.
+    Vals(Vec<Val>),
+}
+
+impl StubImports {
+    /// Parse `module::name` or `module::name=value,...` specs, and add the
+    /// given `(module, name, results)` stubs.
+    pub fn parse(specs: &[String], vals: &[(String, String, Vec<Val>)]) -> Result<StubImports> {
+        let mut results = HashMap::new();
+        for spec in specs {
+            let (import, values) = match spec.find('=') {
+                Some(equal) => (&spec[..equal], Some(&spec[equal + 1..])),
+                None => (&spec[..], None),
+            };
+            let colons = import.find("::").ok_or_else(|| {
+                anyhow::anyhow!("invalid stub import `{}`: expected `module::name`", spec)
+            })?;
+            let key = (
+                import[..colons].to_string(),
+                import[colons + 2..].to_string(),
+            );
+            let values = values
+                .map(|v| v.split(',').map(|v| v.trim().to_string()).collect())
+                .unwrap_or_default();
+            if results.insert(key, StubResults::Text(values)).is_some() {
+                anyhow::bail!("duplicated stub import `{}`", import);
+            }
+        }
+        for (module, name, values) in vals {
+            if let Some(value) = values.iter().find(|v| !is_number(&v.ty())) {
+                anyhow::bail!(
+                    "the stub for {} returns a {}, but stub imports can only return numbers",
+                    import_name(module, Some(name)),
+                    value.ty()
+                );
+            }
+            let key = (module.clone(), name.clone());
+            if results
+                .insert(key, StubResults::Vals(values.clone()))
+                .is_some()
+            {
+                anyhow::bail!("duplicated stub import `{}::{}`", module, name);
+            }
+        }
+        Ok(StubImports { results })
+    }
+
+    fn get(&self, module: &str, name: Option<&str>) -> Option<&StubResults> {
+        self.results.get(&(module.to_string(), name?.to_string()))
+    }
+}
+
 /// Create dummy imports for instantiating the module.
 pub fn dummy_imports(
     store: &mut crate::Store,
     module: &wasmtime::Module,
     linker: &mut crate::Linker,
+    options: &DummyOptions,
 ) -> Result<()> {
     log::debug!("Creating dummy imports");
 
@@ -26,11 +112,7 @@ pub fn dummy_imports(
                     .define(
                         imp.module(),
                         name,
-                        dummy_extern(
-                            &mut *store,
-                            imp.ty(),
-                            &format!("'{}' '{}'", imp.module(), name),
-                        )?,
+                        dummy_extern(&mut *store, imp.ty(), imp.module(), Some(name), options)?,
                     )
                     .unwrap();
             }
@@ -49,7 +131,13 @@ pub fn dummy_imports(
                             .define(
                                 imp.module(),
                                 ty.name(),
-                                dummy_extern(&mut *store, ty.ty(), &format!("'{}'", imp.module()))?,
+                                dummy_extern(
+                                    &mut *store,
+                                    ty.ty(),
+                                    imp.module(),
+                                    Some(ty.name()),
+                                    options,
+                                )?,
                             )
                             .unwrap();
                     }
@@ -63,7 +151,7 @@ pub fn dummy_imports(
                     linker
                         .define_name(
                             imp.module(),
-                            dummy_extern(&mut *store, other, &format!("'{}'", imp.module()))?,
+                            dummy_extern(&mut *store, other, imp.module(), None, options)?,
                         )
                         .unwrap();
                 }
@@ -75,40 +163,214 @@ pub fn dummy_imports(
 }
 
 /// Construct a dummy `Extern` from its type signature
-pub fn dummy_extern(store: &mut crate::Store, ty: ExternType, name: &str) -> Result<Extern> {
+pub fn dummy_extern(
+    store: &mut crate::Store,
+    ty: ExternType,
+    module: &str,
+    name: Option<&str>,
+    options: &DummyOptions,
+) -> Result<Extern> {
     Ok(match ty {
-        ExternType::Func(func_ty) => Extern::Func(dummy_func(store, func_ty, name)),
+        ExternType::Func(func_ty) => match options.stubs.get(module, name) {
+            Some(values) => Extern::Func(stub_func(store, func_ty, module, name, values)?),
+            None => Extern::Func(dummy_func(store, func_ty, module, name, options)),
+        },
         ExternType::Instance(instance_ty) => {
-            Extern::Instance(dummy_instance(store, instance_ty, name)?)
+            Extern::Instance(dummy_instance(store, instance_ty, module)?)
         }
-        ExternType::Global(_) => {
-            anyhow::bail!("Error: attempted to import unknown global: {}", name)
+        ExternType::Global(_) => return Err(unknown_import("global", module, name)),
+        ExternType::Table(_) => return Err(unknown_import("table", module, name)),
+        ExternType::Memory(_) => return Err(unknown_import("memory", module, name)),
+        ExternType::Module(_) => return Err(unknown_import("module", module, name)),
+    })
+}
+
+fn import_name(module: &str, name: Option<&str>) -> String {
+    match name {
+        Some(name) => format!("'{}' '{}'", module, name),
+        None => format!("'{}'", module),
+    }
+}
+
+/// The error for an import of a kind that we can't make a dummy for.
+fn unknown_import(kind: &str, module: &str, name: Option<&str>) -> anyhow::Error {
+    WizerError::Import {
+        module: module.to_string(),
+        name: name.map(|name| name.to_string()),
+        message: format!(
+            "Error: attempted to import unknown {}: {}",
+            kind,
+            import_name(module, name)
+        ),
+    }
+    .into()
+}
+
+/// Construct a dummy function for the given function type
+pub fn dummy_func(
+    store: &mut crate::Store,
+    ty: FuncType,
+    module: &str,
+    name: Option<&str>,
+    options: &DummyOptions,
+) -> Func {
+    let import = import_name(module, name);
+    let wasi = !options.allow_wasi && is_wasi(module);
+    let record = options.record.clone();
+    let (module, name) = (module.to_string(), name.map(|name| name.to_string()));
+    let result_tys = ty.results().collect::<Vec<_>>();
+    Func::new(store, ty, move |_caller, _params, results| {
+        let record = match &record {
+            Some(record) => record,
+            None => {
+                let mut message = format!(
+                    "Error: attempted to call an unknown imported function: {}\n\
+                     \n\
+                     You cannot call arbitrary imported functions during Wizer initialization.",
+                    import,
+                );
+                if wasi {
+                    message.push_str(
+                        "\n\nThis is a WASI import, which is allowed with `--allow-wasi`.",
+                    );
+                }
+                return Err(Trap::new(message));
+            }
+        };
+
+        let mut record = record.lock().unwrap();
+        match record
+            .iter_mut()
+            .find(|import| import.module == module && import.name == name)
+        {
+            Some(import) => import.calls += 1,
+            None => {
+                log::warn!("Initialization called the disallowed import {}", import);
+                record.push(DisallowedImport {
+                    module: module.clone(),
+                    name: name.clone(),
+                    wasi,
+                    calls: 1,
+                    backtrace: crate::error::backtrace(&Trap::new("")),
+                });
+            }
         }
-        ExternType::Table(_) => anyhow::bail!("Error: attempted to import unknown table: {}", name),
-        ExternType::Memory(_) => {
-            anyhow::bail!("Error: attempted to import unknown memory: {}", name)
+        results.clone_from_slice(&dummy_values(result_tys.iter().cloned()));
+        Ok(())
+    })
+}
+
+/// Construct a function for a stubbed import, which returns the given values,
+/// or zeroes if there are none.
+fn stub_func(
+    store: &mut crate::Store,
+    ty: FuncType,
+    module: &str,
+    name: Option<&str>,
+    values: &StubResults,
+) -> Result<Func> {
+    let import = import_name(module, name);
+    let num_values = match values {
+        StubResults::Text(values) => values.len(),
+        StubResults::Vals(values) => values.len(),
+    };
+    if num_values != 0 && num_values != ty.results().len() {
+        anyhow::bail!(
+            "the stub for {} gives {} value(s), but the import returns {}",
+            import,
+            num_values,
+            ty.results().len()
+        );
+    }
+    let stub_results = match values {
+        _ if num_values == 0 => dummy_values(ty.results()),
+        StubResults::Text(values) => ty
+            .results()
+            .zip(values)
+            .map(|(ty, value)| parse_value(ty, value))
+            .collect::<Result<Vec<_>>>()
+            .with_context(|| format!("invalid value in the stub for {}", import))?,
+        StubResults::Vals(values) => {
+            for (ty, value) in ty.results().zip(values) {
+                if value.ty() != ty {
+                    anyhow::bail!(
+                        "invalid value in the stub for {}: {} is not a valid {}",
+                        import,
+                        crate::format_number(value),
+                        ty
+                    );
+                }
+            }
+            values.clone()
         }
-        ExternType::Module(_) => {
-            anyhow::bail!("Error: attempted to import unknown module: {}", name)
+    };
+    Ok(Func::new(store, ty, move |_caller, params, results| {
+        log::info!("Stubbed call to {} with {:?}", import, params);
+        results.clone_from_slice(&stub_results);
+        Ok(())
+    }))
+}
+
+/// Parse a value of the given type from a stub import's spec or an
+/// initialization function argument.
+///
+/// Besides decimal numbers, floats may be given as `nan:0x...` or
+/// `-nan:0x...`, for a NaN with the given payload.
+pub(crate) fn parse_value(ty: ValType, value: &str) -> Result<Val> {
+    let invalid = || anyhow::anyhow!("`{}` is not a valid {}", value, ty);
+    // The bits of a NaN with the given sign bit and payload bits.
+    let nan = |sign: u64, payload_mask: u64| -> Result<u64> {
+        let (negative, value) = match value.strip_prefix('-') {
+            Some(value) => (true, value),
+            None => (false, value),
+        };
+        let payload = value.strip_prefix("nan:0x").ok_or_else(invalid)?;
+        let payload = u64::from_str_radix(payload, 16).map_err(|_| invalid())?;
+        if payload == 0 || payload & !payload_mask != 0 {
+            return Err(invalid());
         }
+        let exponent = (sign - 1) & !payload_mask;
+        Ok(if negative { sign } else { 0 } | exponent | payload)
+    };
+    Ok(match ty {
+        ValType::I32 => Val::I32(
+            value
+                .parse::<i32>()
+                .or_else(|_| value.parse::<u32>().map(|x| x as i32))
+                .map_err(|_| invalid())?,
+        ),
+        ValType::I64 => Val::I64(
+            value
+                .parse::<i64>()
+                .or_else(|_| value.parse::<u64>().map(|x| x as i64))
+                .map_err(|_| invalid())?,
+        ),
+        ValType::F32 => match value.parse::<f32>() {
+            Ok(x) => x.into(),
+            Err(_) => Val::F32(nan(1 << 31, (1 << 23) - 1)? as u32),
+        },
+        ValType::F64 => match value.parse::<f64>() {
+            Ok(x) => x.into(),
+            Err(_) => Val::F64(nan(1 << 63, (1 << 52) - 1)?),
+        },
+        _ => anyhow::bail!("stub imports can only return numbers, not {}", ty),
     })
 }
 
-/// Construct a dummy function for the given function type
-pub fn dummy_func(store: &mut crate::Store, ty: FuncType, name: &str) -> Func {
-    let name = name.to_string();
-    Func::new(store, ty.clone(), move |_caller, _params, _results| {
-        Err(Trap::new(format!(
-            "Error: attempted to call an unknown imported function: {}\n\
-             \n\
-             You cannot call arbitrary imported functions during Wizer initialization.",
-            name,
-        )))
-    })
+/// Is the given value type a number type?
+pub(crate) fn is_number(ty: &ValType) -> bool {
+    matches!(
+        ty,
+        ValType::I32 | ValType::I64 | ValType::F32 | ValType::F64
+    )
+}
+
+/// Is the given import module one that WASI provides?
+pub(crate) fn is_wasi(module: &str) -> bool {
+    module == "wasi_snapshot_preview1" || module == "wasi_unstable"
 }
 
 /// Construct a dummy value for the given value type.
-#[cfg(fuzzing)]
 pub fn dummy_value(val_ty: ValType) -> Val {
     match val_ty {
         ValType::I32 => Val::I32(0),
@@ -122,7 +384,6 @@ pub fn dummy_value(val_ty: ValType) -> Val {
 }
 
 /// Construct a sequence of dummy values for the given types.
-#[cfg(fuzzing)]
 pub fn dummy_values(val_tys: impl IntoIterator<Item = ValType>) -> Vec<Val> {
     val_tys.into_iter().map(dummy_value).collect()
 }
@@ -170,26 +431,18 @@ impl WatGenerator {
         match item_ty {
             ExternType::Func(_) => write!(self.dst, "func").unwrap(),
             ExternType::Instance(_) => write!(self.dst, "instance").unwrap(),
-            ExternType::Memory(_) => anyhow::bail!(
-                "Error: attempted to import unknown memory: '{}' '{}'",
-                instance_name,
-                ty.name()
-            ),
-            ExternType::Global(_) => anyhow::bail!(
-                "Error: attempted to import unknown global: '{}' '{}'",
-                instance_name,
-                ty.name()
-            ),
-            ExternType::Table(_) => anyhow::bail!(
-                "Error: attempted to import unknown table: '{}' '{}'",
-                instance_name,
-                ty.name()
-            ),
-            ExternType::Module(_) => anyhow::bail!(
-                "Error: attempted to import unknown module: '{}' '{}'",
-                instance_name,
-                ty.name()
-            ),
+            ExternType::Memory(_) => {
+                return Err(unknown_import("memory", instance_name, Some(ty.name())))
+            }
+            ExternType::Global(_) => {
+                return Err(unknown_import("global", instance_name, Some(ty.name())))
+            }
+            ExternType::Table(_) => {
+                return Err(unknown_import("table", instance_name, Some(ty.name())))
+            }
+            ExternType::Module(_) => {
+                return Err(unknown_import("module", instance_name, Some(ty.name())))
+            }
         }
         writeln!(self.dst, " ${}))", wat_name).unwrap();
         Ok(())
@@ -220,26 +473,18 @@ impl WatGenerator {
                 self.dst.push_str(")\n");
                 writeln!(self.dst, "(instance ${} (instantiate ${0}_module))", name).unwrap();
             }
-            ExternType::Memory(_) => anyhow::bail!(
-                "Error: attempted to import unknown memory: '{}' '{}'",
-                instance_name,
-                item_name
-            ),
-            ExternType::Global(_) => anyhow::bail!(
-                "Error: attempted to import unknown global: '{}' '{}'",
-                instance_name,
-                item_name
-            ),
-            ExternType::Table(_) => anyhow::bail!(
-                "Error: attempted to import unknown table: '{}' '{}'",
-                instance_name,
-                item_name
-            ),
-            ExternType::Module(_) => anyhow::bail!(
-                "Error: attempted to import unknown module: '{}' '{}'",
-                instance_name,
-                item_name
-            ),
+            ExternType::Memory(_) => {
+                return Err(unknown_import("memory", instance_name, Some(item_name)))
+            }
+            ExternType::Global(_) => {
+                return Err(unknown_import("global", instance_name, Some(item_name)))
+            }
+            ExternType::Table(_) => {
+                return Err(unknown_import("table", instance_name, Some(item_name)))
+            }
+            ExternType::Module(_) => {
+                return Err(unknown_import("module", instance_name, Some(item_name)))
+            }
         }
         Ok(())
     }
@@ -327,7 +572,13 @@ mod tests {
     fn dummy_function_import() {
         let mut store = store();
         let func_ty = FuncType::new(vec![ValType::I32], vec![ValType::I64]);
-        let func = dummy_func(&mut store, func_ty.clone(), "f");
+        let func = dummy_func(
+            &mut store,
+            func_ty.clone(),
+            "m",
+            Some("f"),
+            &DummyOptions::default(),
+        );
         assert_eq!(func.ty(&store), func_ty);
     }
 
@@ -0,0 +1,217 @@
+//! The errors that wizening can fail with.
+
+use std::fmt;
+
+/// An error from [`Wizer::run`][crate::Wizer::run].
+///
+/// The variants distinguish problems with the input module, which the user can
+/// fix, from everything else, which ends up in [`WizerError::Other`].
+///
+/// `WizerError` implements `std::error::Error`, so it converts into an
+/// `anyhow::Error` with `?`, and an `anyhow::Error` converts back into a
+/// `WizerError`, recovering the original variant if there is one.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WizerError {
+    /// The input Wasm module is invalid, or uses something that Wizer does not
+    /// support.
+    Validation {
+        /// The byte offset in the input module where the problem is.
+        offset: usize,
+        /// The unsupported instruction, such as `table.copy`, if the problem is
+        /// an instruction.
+        instruction: Option<String>,
+        /// A description of the problem.
+        message: String,
+    },
+
+    /// The initialization function is missing or has the wrong type.
+    InitFunc {
+        /// The name of the initialization function export.
+        name: String,
+        /// A description of the problem.
+        message: String,
+    },
+
+    /// The initialization function returned a non-zero status.
+    ///
+    /// Only returned with
+    /// [`Wizer::check_init_status`][crate::Wizer::check_init_status].
+    InitStatus {
+        /// The name of the initialization function export.
+        name: String,
+        /// The status that it returned.
+        status: i64,
+    },
+
+    /// An initialization function trapped.
+    Trap {
+        /// The name of the function that trapped, either the initialization
+        /// function or the WASI reactor's `_initialize`.
+        func: String,
+        /// The Wasm backtrace of the trap, innermost frame first.
+        backtrace: Vec<Frame>,
+        /// The trap itself.
+        trap: wasmtime::Trap,
+    },
+
+    /// The module imports something that Wizer cannot provide during
+    /// initialization.
+    Import {
+        /// The import's module name.
+        module: String,
+        /// The import's field name, if any.
+        name: Option<String>,
+        /// A description of the problem.
+        message: String,
+    },
+
+    /// Initialization called imports that are not allowed during
+    /// initialization.
+    ///
+    /// Only returned with [`Wizer::dry_run`][crate::Wizer::dry_run]; otherwise,
+    /// the first such call traps.
+    DisallowedImports {
+        /// Every import that was called, in the order of their first calls.
+        imports: Vec<DisallowedImport>,
+    },
+
+    /// A function rename is invalid or conflicts with another rename.
+    Rename {
+        /// The rename, as given to
+        /// [`Wizer::func_rename`][crate::Wizer::func_rename].
+        spec: String,
+        /// A description of the problem.
+        message: String,
+    },
+
+    /// Any other error.
+    ///
+    /// This displays the `anyhow::Error`'s outermost message, and its
+    /// `source` is the rest of the `anyhow::Error`'s chain.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for WizerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WizerError::Validation {
+                offset, message, ..
+            } => write!(f, "{} (at offset {:#x})", message, offset),
+            WizerError::InitFunc { message, .. } => write!(f, "{}", message),
+            WizerError::InitStatus { name, status } => write!(
+                f,
+                "the `{}` function returned non-zero status {}",
+                name, status
+            ),
+            WizerError::Trap { func, .. } => write!(f, "the `{}` function trapped", func),
+            WizerError::Import { message, .. } => write!(f, "{}", message),
+            WizerError::DisallowedImports { imports } => {
+                write!(
+                    f,
+                    "initialization called {} import(s) that are not allowed during \
+                     initialization:",
+                    imports.len()
+                )?;
+                for import in imports {
+                    write!(f, "\n\n{}", import)?;
+                }
+                Ok(())
+            }
+            WizerError::Rename { spec, message } => {
+                write!(f, "invalid function rename `{}`: {}", spec, message)
+            }
+            WizerError::Other(e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl std::error::Error for WizerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WizerError::Trap { trap, .. } => Some(trap),
+            // `Display` already shows `e`'s own message, so the chain goes on
+            // with what caused `e`, rather than repeating `e`.
+            WizerError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for WizerError {
+    fn from(e: anyhow::Error) -> WizerError {
+        match e.downcast::<WizerError>() {
+            Ok(e) => e,
+            Err(e) => WizerError::Other(e),
+        }
+    }
+}
+
+/// A frame of a Wasm backtrace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    /// The index of the frame's function in its module.
+    pub func_index: u32,
+    /// The function's name from the name section, if any.
+    pub func_name: Option<String>,
+    /// The byte offset of the frame's instruction from the start of its
+    /// function's body.
+    pub func_offset: usize,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.func_name {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "<wasm function {}>", self.func_index)?,
+        }
+        write!(f, " (at function offset {:#x})", self.func_offset)
+    }
+}
+
+/// Get the Wasm backtrace of the given trap.
+pub(crate) fn backtrace(trap: &wasmtime::Trap) -> Vec<Frame> {
+    trap.trace()
+        .iter()
+        .map(|frame| Frame {
+            func_index: frame.func_index(),
+            func_name: frame.func_name().map(|name| name.to_string()),
+            func_offset: frame.func_offset(),
+        })
+        .collect()
+}
+
+/// An import that initialization called, even though it is not allowed during
+/// initialization.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DisallowedImport {
+    /// The import's module name.
+    pub module: String,
+    /// The import's field name, if any.
+    pub name: Option<String>,
+    /// Whether this is a WASI import that
+    /// [`Wizer::allow_wasi`][crate::Wizer::allow_wasi] would allow.
+    pub wasi: bool,
+    /// How many times the import was called.
+    pub calls: usize,
+    /// The Wasm backtrace of the import's first call, innermost frame first.
+    pub backtrace: Vec<Frame>,
+}
+
+impl fmt::Display for DisallowedImport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "'{}' '{}'", self.module, name)?,
+            None => write!(f, "'{}'", self.module)?,
+        }
+        write!(f, " was called {} time(s)", self.calls)?;
+        if self.wasi {
+            write!(f, "; it is a WASI import, which `--allow-wasi` allows")?;
+        }
+        write!(f, "\nfirst called from:")?;
+        for (i, frame) in self.backtrace.iter().enumerate() {
+            write!(f, "\n  {}: {}", i, frame)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Writing the snapshotted memories as raw image files, for hosts that map
+//! memory images directly rather than initializing memory from data segments.
+//!
+//! Each memory's image holds its contents from address zero up to the end of
+//! the Wasm page holding its last non-zero byte. Everything above that is zero.
+//! A `manifest.toml` next to the images describes each memory and the
+//! snapshotted values of the globals.
+
+use crate::snapshot::{pages_for_bytes, Snapshot};
+use anyhow::Context;
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use toml::value::{Table, Value};
+
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+/// The name of the manifest file.
+const MANIFEST: &str = "manifest.toml";
+
+/// Write an image of each memory in the snapshot, and the manifest, to the
+/// given directory.
+///
+/// Images left in the directory by an earlier run are removed first, so that
+/// none are mistaken for one of this snapshot's memories.
+pub(crate) fn write(dir: &Path, store: &crate::Store, snapshot: &Snapshot) -> anyhow::Result<()> {
+    log::debug!("Writing heap images to {}", dir.display());
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory: {}", dir.display()))?;
+    remove_images(dir)?;
+
+    let mut manifest = Table::new();
+    manifest.insert("page-size".into(), int(WASM_PAGE_SIZE));
+
+    let mut memories = vec![];
+    for (index, minimum) in snapshot.memory_mins.iter().enumerate() {
+        let index = u32::try_from(index).unwrap();
+        let segments = snapshot
+            .data_segments
+            .iter()
+            .filter(|s| s.memory_index == index);
+
+        // Data segments are sorted by offset and never overlap, so the last
+        // one ends the image.
+        let end = segments
+            .clone()
+            .next_back()
+            .map_or(0, |s| u64::from(s.offset) + u64::from(s.len));
+        let pages = pages_for_bytes(end);
+
+        let name = image_name(index);
+        let path = dir.join(&name);
+        let mut file = File::create(&path)
+            .with_context(|| format!("failed to create heap image: {}", path.display()))?;
+        // Leave the gaps between segments as holes, which read as zeros.
+        file.set_len(pages * WASM_PAGE_SIZE)?;
+        for segment in segments {
+            file.seek(SeekFrom::Start(u64::from(segment.offset)))?;
+            file.write_all(segment.data(store))?;
+        }
+
+        let mut memory = Table::new();
+        memory.insert("index".into(), int(index));
+        memory.insert("image".into(), Value::String(name));
+        memory.insert("image-pages".into(), int(pages));
+        memory.insert("minimum-pages".into(), int(*minimum));
+        memories.push(Value::Table(memory));
+    }
+    manifest.insert("memory".into(), Value::Array(memories));
+
+    let mut globals = vec![];
+    for (index, global) in snapshot.globals.iter().enumerate() {
+        let mut entry = Table::new();
+        entry.insert("index".into(), int(u32::try_from(index).unwrap()));
+        // Floats' bits too, since TOML can't represent NaN payloads. TOML
+        // integers are signed 64-bit, so `f64` bits are reinterpreted as such.
+        let (ty, value, bits) = match *global {
+            wasmtime::Val::I32(x) => ("i32", int(x), None),
+            wasmtime::Val::I64(x) => ("i64", int(x), None),
+            wasmtime::Val::F32(bits) => (
+                "f32",
+                Value::Float(f32::from_bits(bits).into()),
+                Some(int(bits)),
+            ),
+            wasmtime::Val::F64(bits) => (
+                "f64",
+                Value::Float(f64::from_bits(bits)),
+                Some(Value::Integer(bits as i64)),
+            ),
+            _ => unreachable!("reference types are not enabled"),
+        };
+        entry.insert("type".into(), Value::String(ty.into()));
+        entry.insert("value".into(), value);
+        if let Some(bits) = bits {
+            entry.insert("bits".into(), bits);
+        }
+        globals.push(Value::Table(entry));
+    }
+    manifest.insert("global".into(), Value::Array(globals));
+
+    let manifest = format!(
+        "# Written by Wizer {}.\n{}",
+        env!("CARGO_PKG_VERSION"),
+        toml::to_string(&Value::Table(manifest)).context("failed to serialize manifest")?
+    );
+    let path = dir.join(MANIFEST);
+    fs::write(&path, manifest)
+        .with_context(|| format!("failed to write manifest: {}", path.display()))
+}
+
+fn image_name(index: u32) -> String {
+    format!("memory{}.img", index)
+}
+
+/// Remove every `memory<index>.img` file in the given directory.
+fn remove_images(dir: &Path) -> anyhow::Result<()> {
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("failed to read directory: {}", dir.display()))?
+    {
+        let path = entry?.path();
+        let index = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("memory"))
+            .and_then(|name| name.strip_suffix(".img"));
+        let is_image = match index {
+            Some(index) => !index.is_empty() && index.bytes().all(|b| b.is_ascii_digit()),
+            None => false,
+        };
+        if is_image {
+            fs::remove_file(&path)
+                .with_context(|| format!("failed to remove old heap image: {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// A TOML integer. Everything in the manifest fits in an `i64`.
+fn int<T>(x: T) -> Value
+where
+    T: TryInto<i64>,
+    T::Error: std::fmt::Debug,
+{
+    Value::Integer(x.try_into().unwrap())
+}
@@ -593,6 +593,45 @@ impl Module {
             .map(|(i, g)| (u32::try_from(i).unwrap(), g))
     }
 
+    /// Find the index of the global that this module's name section gives the
+    /// name `name`, if any.
+    pub fn global_index_by_name(self, cx: &ModuleContext<'_>, name: &str) -> Option<u32> {
+        // The id of the global names subsection from the extended name section
+        // proposal.
+        const GLOBAL_NAMES: u32 = 7;
+
+        for section in self.raw_sections(cx) {
+            if section.id != SectionId::Custom.into() {
+                continue;
+            }
+            let mut reader = wasmparser::BinaryReader::new(section.data);
+            if !matches!(reader.read_string(), Ok("name")) {
+                continue;
+            }
+            let data = &section.data[reader.original_position()..];
+            let mut names = wasmparser::NameSectionReader::new(data, 0).ok()?;
+            while !names.eof() {
+                let data = match names.read().ok()? {
+                    wasmparser::Name::Unknown {
+                        ty: GLOBAL_NAMES,
+                        data,
+                        ..
+                    } => data,
+                    _ => continue,
+                };
+                let mut reader = wasmparser::BinaryReader::new(data);
+                let count = reader.read_var_u32().ok()?;
+                for _ in 0..count {
+                    let index = reader.read_var_u32().ok()?;
+                    if reader.read_string().ok()? == name {
+                        return Some(index);
+                    }
+                }
+            }
+        }
+        None
+    }
+
     /// Iterate over the initial sections in this Wasm module.
     pub fn initial_sections<'a, 'b>(
         self,
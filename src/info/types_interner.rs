@@ -2,7 +2,7 @@ use std::{
     borrow::Cow,
     collections::{BTreeMap, HashMap},
     convert::TryFrom,
-    rc::Rc,
+    sync::Arc,
 };
 
 /// A de-duplicated set of type definitions.
@@ -18,10 +18,10 @@ use std::{
 #[derive(Default)]
 pub struct TypesInterner<'a> {
     /// The interned types.
-    types: Vec<Rc<Type<'a>>>,
+    types: Vec<Arc<Type<'a>>>,
 
     /// An map from a type to its index in `self.types`.
-    type_to_index: HashMap<Rc<Type<'a>>, u32>,
+    type_to_index: HashMap<Arc<Type<'a>>, u32>,
 }
 
 /// An interned Wasm type definition.
@@ -132,7 +132,7 @@ impl<'a> TypesInterner<'a> {
         }
 
         let index = u32::try_from(self.types.len()).unwrap();
-        let ty = Rc::new(ty);
+        let ty = Arc::new(ty);
         self.type_to_index.insert(ty.clone(), index);
         self.types.push(ty);
         TypeId { index }
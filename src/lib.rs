@@ -9,21 +9,44 @@ pub mod dummy;
 #[cfg(not(fuzzing))]
 mod dummy;
 
+mod cache;
+mod compress;
+mod diff;
+mod dirty_pages;
+mod error;
+mod heap_image;
 mod info;
 mod instrument;
 mod parse;
+mod passive_data;
+mod proposals;
+mod provenance;
 mod rewrite;
+mod shadow_stack;
 mod snapshot;
 mod stack_ext;
 mod translate;
+mod vfs;
 
 use anyhow::Context;
-use dummy::dummy_imports;
-use std::collections::{HashMap, HashSet};
+pub use diff::{ByteRangeDiff, ByteRangeDiffKind, GlobalDiff, MemoryDiff, ModuleDiff};
+use dummy::{dummy_imports, DummyOptions, StubImports};
+pub use error::{DisallowedImport, Frame, WizerError};
+use info::ModuleContext;
+pub use provenance::Provenance;
+use shadow_stack::StackPointer;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt::Display;
-use std::path::PathBuf;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 #[cfg(feature = "structopt")]
 use structopt::StructOpt;
+use wasi_common::dir::DirCaps;
+use wasi_common::file::FileCaps;
+use wasi_common::pipe::{ReadPipe, WritePipe};
 use wasmtime::Extern;
 use wasmtime_wasi::WasiCtx;
 
@@ -32,6 +55,7 @@ const DEFAULT_INHERIT_ENV: bool = false;
 const DEFAULT_WASM_MULTI_VALUE: bool = true;
 const DEFAULT_WASM_MULTI_MEMORY: bool = true;
 const DEFAULT_WASM_MODULE_LINKING: bool = false;
+const DEFAULT_DATA_SEGMENT_PAGE_SIZE: u32 = 4096;
 
 /// We only ever use `Store<T>` with a fixed `T` that is our optional WASI
 /// context.
@@ -41,6 +65,37 @@ pub(crate) type Store = wasmtime::Store<Option<WasiCtx>>;
 /// context.
 pub(crate) type Linker = wasmtime::Linker<Option<WasiCtx>>;
 
+/// A callback that adds the embedder's definitions to the linker before
+/// initialization.
+type DefineImports<'a> = Box<dyn FnOnce(&mut Linker) -> anyhow::Result<()> + Send + 'a>;
+
+/// A module that has been parsed, instrumented, and compiled, and is ready to
+/// be initialized.
+struct Prepared<'a> {
+    cx: ModuleContext<'a>,
+    store: Store,
+    module: wasmtime::Module,
+    init_args: Vec<wasmtime::Val>,
+    renames: FuncRenames,
+    excluded_ranges: Vec<ExcludedRange>,
+    previous_provenance: Option<provenance::Provenance>,
+    vfs: Option<vfs::Vfs>,
+}
+
+/// An instance whose initialization function has returned.
+struct Initialized {
+    instance: wasmtime::Instance,
+    has_wasi_initialize: bool,
+    shadow_stack: Option<Range<u64>>,
+    heap_end: Option<u64>,
+}
+
+/// Where to read the end of the live heap from.
+enum HeapEnd {
+    Value(u64),
+    Func(wasmtime::TypedFunc<(), i32>),
+}
+
 /// Wizer: the WebAssembly pre-initializer!
 ///
 /// Don't wait for your Wasm module to initialize itself, pre-initialize it!
@@ -62,7 +117,19 @@ pub(crate) type Linker = wasmtime::Linker<Option<WasiCtx>>;
 ///   snapshot the new table state, but funcrefs and externrefs don't have
 ///   identity and aren't comparable in the Wasm spec, which makes snapshotting
 ///   difficult.
+///
+/// ## Configuration Files
+///
+/// With the `serde` cargo feature enabled, `Wizer` implements `Serialize` and
+/// `Deserialize`, with a kebab-case key for each option. Missing keys take
+/// their default values, and unknown keys are an error. The `wizer` CLI loads
+/// such a configuration from a TOML file given with `--config`.
 #[cfg_attr(feature = "structopt", derive(StructOpt))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(default, deny_unknown_fields, rename_all = "kebab-case")
+)]
 #[derive(Clone, Debug)]
 pub struct Wizer {
     /// The Wasm export name of the function that should be executed to
@@ -73,6 +140,30 @@ pub struct Wizer {
     )]
     init_func: String,
 
+    /// Arguments to pass to the initialization function, one per parameter.
+    ///
+    /// The initialization function may have `i32`, `i64`, `f32`, and `f64`
+    /// parameters, and each argument is parsed as its parameter's type.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "init-arg", value_name = "value", allow_hyphen_values = true)
+    )]
+    init_args: Vec<String>,
+
+    /// Arguments to pass to the initialization function given through the
+    /// library API, which come after `init_args`.
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    init_arg_vals: Vec<wasmtime::Val>,
+
+    /// Treat the initialization function's result as a status code, and fail
+    /// if it is non-zero.
+    ///
+    /// The initialization function must then return an `i32` or `i64`.
+    /// Otherwise, any result is logged and ignored.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    check_init_status: bool,
+
     /// Any function renamings to perform.
     ///
     /// A renaming specification `dst=src` renames a function export `src` to
@@ -113,6 +204,26 @@ pub struct Wizer {
     #[cfg_attr(feature = "structopt", structopt(long = "allow-wasi"))]
     allow_wasi: bool,
 
+    /// Function imports that return fixed values, rather than trapping, when
+    /// they are called during initialization.
+    ///
+    /// Each is given as `module::name`, to return zeroes, or as
+    /// `module::name=value,...`, to return the given values, where a float
+    /// may also be a NaN with a given payload, such as `nan:0x200000`. Every
+    /// call is logged. This is useful for imports like logging functions, whose
+    /// effects don't matter during initialization.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "stub-import", value_name = "module::name[=values]")
+    )]
+    stub_imports: Vec<String>,
+
+    /// Stubbed function imports given through the library API, as
+    /// `(module, name, results)`.
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    stub_import_vals: Vec<(String, String, Vec<wasmtime::Val>)>,
+
     /// When using WASI during initialization, should `stdin`, `stderr`, and
     /// `stdout` be inherited?
     ///
@@ -143,6 +254,81 @@ pub struct Wizer {
     )]
     dirs: Vec<PathBuf>,
 
+    /// When using WASI during initialization, which file system directories
+    /// should be made available under a different path?
+    ///
+    /// Each is given as `GUEST::HOST`, where `HOST` is the directory on the
+    /// host and `GUEST` is the path that initialization sees it at.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "mapdir", value_name = "GUEST::HOST")
+    )]
+    map_dirs: Vec<String>,
+
+    /// When using WASI during initialization, make the preopened directories
+    /// read-only.
+    ///
+    /// Initialization can then read files and list directories, but not
+    /// create, write, rename, or remove anything.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    read_only_dirs: bool,
+
+    /// When using WASI during initialization, tar archives whose contents to
+    /// make available in an in-memory, read-only file system at `/`.
+    ///
+    /// Later archives replace files of earlier archives with the same path.
+    /// Nothing on the host file system is touched.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "vfs-tar", parse(from_os_str), value_name = "archive")
+    )]
+    vfs_tars: Vec<PathBuf>,
+
+    /// When using WASI during initialization, files to make available in the
+    /// in-memory file system, by guest path.
+    ///
+    /// Only settable through the library API.
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    vfs_files: BTreeMap<String, Vec<u8>>,
+
+    /// When using WASI during initialization, the command-line arguments to
+    /// give it, starting with the program name.
+    ///
+    /// None are given by default.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "wasi-arg", value_name = "arg")
+    )]
+    wasi_args: Vec<String>,
+
+    /// When using WASI during initialization, environment variables to set,
+    /// given as `KEY=VALUE`.
+    ///
+    /// These take precedence over inherited environment variables.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "wasi-env", value_name = "KEY=VALUE")
+    )]
+    wasi_envs: Vec<String>,
+
+    /// When using WASI during initialization, a file whose contents to give
+    /// it as stdin.
+    ///
+    /// This takes precedence over inheriting stdin.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "wasi-stdin", parse(from_os_str), value_name = "file")
+    )]
+    wasi_stdin: Option<PathBuf>,
+
+    /// When using WASI during initialization, the bytes to give it as stdin.
+    ///
+    /// Only settable through the library API.
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    wasi_stdin_bytes: Option<Vec<u8>>,
+
     /// Enable or disable Wasm multi-memory proposal.
     ///
     /// Enabled by default.
@@ -160,6 +346,336 @@ pub struct Wizer {
     /// Disabled by default.
     #[cfg_attr(feature = "structopt", structopt(long, value_name = "true|false"))]
     wasm_module_linking: Option<bool>,
+
+    /// The maximum number of data segments to emit for each module.
+    ///
+    /// When the snapshot has more regions of non-zero memory than this, the
+    /// regions with the smallest gaps between them are merged together until
+    /// it fits.
+    ///
+    /// Defaults to 100,000, the limit that most engines impose.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "count"))]
+    max_data_segments: Option<usize>,
+
+    /// Merge two regions of non-zero memory into a single data segment when
+    /// the gap of zero bytes between them is at most this many bytes.
+    ///
+    /// Larger values produce fewer, larger data segments at the cost of
+    /// encoding more zero bytes.
+    ///
+    /// Defaults to 4, the minimum overhead of defining a new active data
+    /// segment.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "bytes"))]
+    data_segment_merge_gap: Option<u32>,
+
+    /// Emit exactly one data segment for each memory, spanning from its first
+    /// to its last non-zero byte.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    single_data_segment_per_memory: bool,
+
+    /// Align data segments to host pages, padding them out with zeros.
+    ///
+    /// Wasmtime, for example, can only instantiate modules with copy-on-write
+    /// memory images, rather than by copying data segments into memory, when
+    /// the data segments line up with pages. The padding this adds is logged.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    page_aligned_data_segments: bool,
+
+    /// The page size to align data segments to with
+    /// `--page-aligned-data-segments`.
+    ///
+    /// Must be a power of two no larger than the Wasm page size. Defaults to
+    /// 4096.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "data-segment-page-size", value_name = "bytes")
+    )]
+    data_segment_page_size: Option<u32>,
+
+    /// Set each memory's minimum size to the number of pages that hold
+    /// non-zero data, rather than its size after initialization.
+    ///
+    /// This helps when an allocator grew memory during initialization but
+    /// then freed most of it again. Pages above the new minimum are all zero,
+    /// so growing the memory again at runtime restores the snapshotted state.
+    /// However, the module must grow its memory before it touches those pages
+    /// again. Without `--heap-end-export`, the live heap may end in zeroed
+    /// pages that get cut off, so a warning is logged.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    shrink_memory_mins: bool,
+
+    /// The name of an exported `i32` global, or an exported function of type
+    /// `[] -> i32`, that reports the end of the live heap in the first memory.
+    ///
+    /// The first memory's minimum size always covers the live heap. It is an
+    /// error for the memory to contain non-zero bytes above the heap end.
+    ///
+    /// Implies `--shrink-memory-mins`.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "heap-end-export", value_name = "export")
+    )]
+    heap_end_export: Option<String>,
+
+    /// Skip the memory pages that initialization never touched when
+    /// snapshotting, rather than scanning all of memory for non-zero bytes.
+    ///
+    /// This speeds up wizening modules with large, sparse memories. It is only
+    /// supported on Linux, where it requires `/proc/self/pagemap`; elsewhere,
+    /// or when that isn't available, all of memory is scanned as usual.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    dirty_page_tracking: bool,
+
+    /// Ranges of the first memory to exclude from the snapshot.
+    ///
+    /// An excluded range is zeroed after initialization, before the snapshot is
+    /// taken, so nothing that initialization stored there ends up in the
+    /// pre-initialized module. This is useful for scratch buffers, the shadow
+    /// stack, or secrets read during initialization.
+    ///
+    /// A range is given as `start..end`, where `start` and `end` are decimal or
+    /// `0x`-prefixed hexadecimal addresses.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "exclude-memory-range", value_name = "start..end")
+    )]
+    exclude_memory_ranges: Vec<String>,
+
+    /// Ranges of the first memory to exclude from the snapshot, given by a pair
+    /// of exported `i32` globals that hold the range's start address and its
+    /// length after initialization.
+    ///
+    /// These ranges are zeroed the same way as `--exclude-memory-range`.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "exclude-memory-range-globals", value_name = "addr,len")
+    )]
+    exclude_memory_range_globals: Vec<String>,
+
+    /// Zero the shadow stack in the snapshot.
+    ///
+    /// Clang and Rust keep the shadow stack's pointer in a mutable global. When
+    /// this option is enabled, Wizer checks that initialization leaves the
+    /// stack pointer at its initial value, and then zeroes everything below it
+    /// on the stack, since it is garbage left behind by dead stack frames.
+    ///
+    /// The stack pointer is the `__stack_pointer` global in the name section,
+    /// unless `--stack-pointer-export` is given. The stack's extent comes from
+    /// `--stack-size` or, failing that, the `__data_end` global.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    zero_shadow_stack: bool,
+
+    /// The name of the exported global that holds the shadow stack pointer.
+    ///
+    /// Implies `--zero-shadow-stack`.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "stack-pointer-export", value_name = "export")
+    )]
+    stack_pointer_export: Option<String>,
+
+    /// The size of the shadow stack, in bytes.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "bytes"))]
+    stack_size: Option<u32>,
+
+    /// Compress the snapshot's data segments.
+    ///
+    /// Each memory's data segments are replaced by a single, LZ4-compressed
+    /// passive data segment, which a synthesized start function decompresses
+    /// into place when the module is instantiated. This makes the output
+    /// smaller at the cost of some instantiation time, and requires that the
+    /// engine running the output supports the bulk memory proposal.
+    ///
+    /// The compressed image is copied into zeroed memory before or after the
+    /// initialized region before being decompressed, and wizening fails if
+    /// the memory's minimum size has no room for it. Memories' minimum sizes
+    /// are never grown.
+    ///
+    /// This is not supported with module linking.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    compress_data: bool,
+
+    /// Emit identical data segment payloads only once.
+    ///
+    /// Without module linking, payloads that are repeated across memories, or
+    /// at different offsets within a memory, are emitted once as a passive
+    /// data segment, which a synthesized start function copies into place.
+    /// Payloads that are too short for that to shrink the output are left
+    /// alone. This requires that the engine running the output supports the
+    /// bulk memory proposal.
+    ///
+    /// With module linking, nested instantiations whose state is identical
+    /// share a single state module, which is instantiated once for each of
+    /// them.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    dedupe_data_segments: bool,
+
+    /// Write the snapshotted memories as raw image files to this directory,
+    /// and leave them out of the wizened module.
+    ///
+    /// This is for hosts that map memory images directly, for example with
+    /// copy-on-write, and would only pay for data segments. Each memory's
+    /// image, `memory<index>.img`, holds its contents up to the end of the
+    /// Wasm page holding its last non-zero byte, and everything above that is
+    /// zero. A `manifest.toml` describes each memory's image and minimum size,
+    /// and the globals' snapshotted values, which the wizened module also
+    /// initializes its globals to. The wizened module has no data section.
+    /// Other `memory<index>.img` files in the directory are removed.
+    ///
+    /// Not supported with module linking, or when compressing or
+    /// deduplicating data segments.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "heap-image-dir", parse(from_os_str), value_name = "directory")
+    )]
+    heap_image_dir: Option<PathBuf>,
+
+    /// Record how the module was wizened in a `wizer` custom section.
+    ///
+    /// The section records the Wizer version, the initialization functions
+    /// that were called, the function renames, the stubbed imports, the WASI
+    /// settings and preopened directories, and hashes of the input module and
+    /// of the snapshot. Use `wizer info` to read it back.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    provenance: bool,
+
+    /// Allow wizening a module that was already wizened.
+    ///
+    /// By default, it is an error to wizen a module that has a `wizer` custom
+    /// section, since its initialization function most likely already ran.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    allow_rewizening: bool,
+
+    /// Find every disallowed import that initialization calls, rather than
+    /// stopping at the first one.
+    ///
+    /// Calls to imports that are not allowed during initialization return
+    /// zeroes instead of trapping. If there were any such calls, wizening
+    /// fails with a list of them and where they were first called from.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    dry_run: bool,
+
+    /// A directory to cache wizened modules in.
+    ///
+    /// Wizened modules are stored under a hash of the input Wasm, all of the
+    /// options, the Wizer version, and the WASI inputs, such as stdin and the
+    /// contents of preopened directories. If the cache already has a module
+    /// for the same hash, it is used instead of wizening again.
+    ///
+    /// Caching is disabled when initialization can observe inputs that can't
+    /// be hashed, or have side effects that wouldn't happen again: when WASI
+    /// stdio or environment variables are inherited, when preopened
+    /// directories are writable or contain symlinks, when the module imports
+    /// WASI's `clock_time_get` or `random_get`, or when writing heap images.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "cache-dir", parse(from_os_str), value_name = "directory")
+    )]
+    cache_dir: Option<PathBuf>,
+
+    /// The target triple to precompile the wizened module for.
+    ///
+    /// Only used when precompiling, for example with `--emit-cwasm`. Defaults
+    /// to the host. Other targets require that Wasmtime was built with support
+    /// for them.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "triple"))]
+    target: Option<String>,
+
+    /// The Cranelift optimization level to precompile the wizened module with:
+    /// `none`, `speed`, or `speed_and_size`.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "level"))]
+    opt_level: Option<String>,
+
+    /// Cranelift boolean settings to enable when precompiling the wizened
+    /// module.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "cranelift-enable", value_name = "setting")
+    )]
+    cranelift_enable: Vec<String>,
+
+    /// Cranelift settings to set when precompiling the wizened module.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long = "cranelift-set", value_name = "setting=value")
+    )]
+    cranelift_set: Vec<String>,
+
+    /// Use Wasmtime's code cache when compiling the module for
+    /// initialization?
+    ///
+    /// Enabled by default, with the cache configuration from
+    /// `--wasmtime-cache-config`, or Wasmtime's default one, which keeps the
+    /// cache under the user's home directory.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "true|false"))]
+    wasmtime_cache: Option<bool>,
+
+    /// The Wasmtime cache configuration file to use, instead of Wasmtime's
+    /// default one.
+    #[cfg_attr(
+        feature = "structopt",
+        structopt(long, parse(from_os_str), value_name = "path")
+    )]
+    wasmtime_cache_config: Option<PathBuf>,
+
+    /// The Cranelift optimization level to compile the module for
+    /// initialization with: `none`, `speed`, or `speed_and_size`.
+    #[cfg_attr(feature = "structopt", structopt(long, value_name = "level"))]
+    init_opt_level: Option<String>,
+
+    /// Canonicalize NaNs produced during initialization, so that the snapshot
+    /// doesn't depend on the host's NaN bit patterns.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    nan_canonicalization: bool,
+
+    /// Generate native debug info when compiling the module for
+    /// initialization, so that native debuggers can step through it.
+    #[cfg_attr(feature = "structopt", structopt(long))]
+    debug_info: bool,
+
+    /// A callback that adjusts the Wasmtime configuration for initialization.
+    ///
+    /// Only settable through the library API.
+    #[cfg_attr(feature = "structopt", structopt(skip))]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    wasmtime_config_hook: Option<ConfigHook>,
+}
+
+/// A callback that adjusts the Wasmtime `Config` that initialization uses.
+///
+/// See [`Wizer::wasmtime_config_hook`].
+#[derive(Clone)]
+struct ConfigHook(Arc<ConfigHookFn>);
+
+type ConfigHookFn = dyn Fn(&mut wasmtime::Config) -> anyhow::Result<()> + Send + Sync;
+
+impl std::fmt::Debug for ConfigHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ConfigHook")
+    }
+}
+
+/// A wizened Wasm module, along with what its initialization wrote to stdout
+/// and stderr.
+///
+/// See [`Wizer::run_and_capture_stdio`].
+#[derive(Clone, Debug, Default)]
+pub struct CapturedOutput {
+    /// The wizened Wasm module.
+    pub wasm: Vec<u8>,
+
+    /// What initialization wrote to WASI's stdout.
+    pub stdout: Vec<u8>,
+
+    /// What initialization wrote to WASI's stderr.
+    pub stderr: Vec<u8>,
+}
+
+/// The buffers that initialization's stdout and stderr are captured in.
+#[derive(Default)]
+struct CapturedStdio {
+    stdout: Arc<RwLock<Vec<u8>>>,
+    stderr: Arc<RwLock<Vec<u8>>>,
 }
 
 struct FuncRenames {
@@ -182,18 +698,22 @@ impl FuncRenames {
         }
 
         for rename_spec in renames {
+            let error = |message: String| WizerError::Rename {
+                spec: rename_spec.clone(),
+                message,
+            };
             let equal = rename_spec
                 .trim()
                 .find('=')
-                .ok_or_else(|| anyhow::anyhow!("Invalid function rename part: {}", rename_spec))?;
+                .ok_or_else(|| error("expected `dst=src`".to_string()))?;
             // TODO: use .split_off() when the API is stabilized.
             let dst = rename_spec[..equal].to_owned();
             let src = rename_spec[equal + 1..].to_owned();
             if ret.rename_dsts.contains(&dst) {
-                anyhow::bail!("Duplicated function rename dst {}", dst);
+                anyhow::bail!(error(format!("duplicated function rename dst {}", dst)));
             }
             if ret.rename_src_to_dst.contains_key(&src) {
-                anyhow::bail!("Duplicated function rename src {}", src);
+                anyhow::bail!(error(format!("duplicated function rename src {}", src)));
             }
             ret.rename_dsts.insert(dst.clone());
             ret.rename_src_to_dst.insert(src, dst);
@@ -203,19 +723,152 @@ impl FuncRenames {
     }
 }
 
+/// A range of memory to exclude from the snapshot.
+enum ExcludedRange {
+    /// An explicit range of addresses.
+    Explicit(Range<u32>),
+
+    /// The names of exported globals holding the range's start address and its
+    /// length.
+    Globals { addr: String, len: String },
+}
+
+impl ExcludedRange {
+    fn parse(ranges: &[String], range_globals: &[String]) -> anyhow::Result<Vec<ExcludedRange>> {
+        let mut ret = vec![];
+
+        for range_spec in ranges {
+            let dots = range_spec
+                .find("..")
+                .ok_or_else(|| anyhow::anyhow!("Invalid memory range: {}", range_spec))?;
+            let start = parse_address(range_spec[..dots].trim())
+                .with_context(|| format!("Invalid memory range: {}", range_spec))?;
+            let end = parse_address(range_spec[dots + 2..].trim())
+                .with_context(|| format!("Invalid memory range: {}", range_spec))?;
+            if start > end {
+                anyhow::bail!("Invalid memory range: {}: start is after end", range_spec);
+            }
+            ret.push(ExcludedRange::Explicit(start..end));
+        }
+
+        for globals_spec in range_globals {
+            let comma = globals_spec
+                .find(',')
+                .ok_or_else(|| anyhow::anyhow!("Invalid memory range globals: {}", globals_spec))?;
+            ret.push(ExcludedRange::Globals {
+                addr: globals_spec[..comma].trim().to_owned(),
+                len: globals_spec[comma + 1..].trim().to_owned(),
+            });
+        }
+
+        Ok(ret)
+    }
+
+    /// Resolve this range to concrete addresses, reading the initialized values
+    /// of its globals if necessary.
+    fn resolve(
+        &self,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+    ) -> anyhow::Result<Range<u64>> {
+        match self {
+            ExcludedRange::Explicit(range) => Ok(range.start.into()..range.end.into()),
+            ExcludedRange::Globals { addr, len } => {
+                let mut get = |name: &str| -> anyhow::Result<u64> {
+                    match instance
+                        .get_global(&mut *store, name)
+                        .map(|g| g.get(&mut *store))
+                    {
+                        Some(wasmtime::Val::I32(x)) => Ok(u64::from(x as u32)),
+                        Some(_) => anyhow::bail!(
+                            "the Wasm module's `{}` global export is not an `i32`",
+                            name
+                        ),
+                        None => anyhow::bail!(
+                            "the Wasm module does not have a `{}` global export",
+                            name
+                        ),
+                    }
+                };
+                let start = get(addr)?;
+                let len = get(len)?;
+                Ok(start..start + len)
+            }
+        }
+    }
+}
+
+/// Parse a decimal or `0x`-prefixed hexadecimal address.
+fn parse_address(s: &str) -> anyhow::Result<u32> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.with_context(|| format!("Invalid address: {}", s))
+}
+
+impl Default for Wizer {
+    fn default() -> Self {
+        Wizer::new()
+    }
+}
+
 impl Wizer {
     /// Construct a new `Wizer` builder.
     pub fn new() -> Self {
         Wizer {
             init_func: "wizer.initialize".into(),
+            init_args: vec![],
+            init_arg_vals: vec![],
+            check_init_status: false,
             func_renames: vec![],
+            stub_imports: vec![],
+            stub_import_vals: vec![],
             allow_wasi: false,
             inherit_stdio: None,
             inherit_env: None,
             dirs: vec![],
+            map_dirs: vec![],
+            read_only_dirs: false,
+            vfs_tars: vec![],
+            vfs_files: BTreeMap::new(),
+            wasi_args: vec![],
+            wasi_envs: vec![],
+            wasi_stdin: None,
+            wasi_stdin_bytes: None,
             wasm_multi_memory: None,
             wasm_multi_value: None,
             wasm_module_linking: None,
+            max_data_segments: None,
+            data_segment_merge_gap: None,
+            single_data_segment_per_memory: false,
+            page_aligned_data_segments: false,
+            data_segment_page_size: None,
+            shrink_memory_mins: false,
+            heap_end_export: None,
+            dirty_page_tracking: false,
+            exclude_memory_ranges: vec![],
+            exclude_memory_range_globals: vec![],
+            zero_shadow_stack: false,
+            stack_pointer_export: None,
+            stack_size: None,
+            compress_data: false,
+            dedupe_data_segments: false,
+            heap_image_dir: None,
+            provenance: false,
+            allow_rewizening: false,
+            dry_run: false,
+            cache_dir: None,
+            target: None,
+            opt_level: None,
+            cranelift_enable: vec![],
+            cranelift_set: vec![],
+            wasmtime_cache: None,
+            wasmtime_cache_config: None,
+            init_opt_level: None,
+            nan_canonicalization: false,
+            debug_info: false,
+            wasmtime_config_hook: None,
         }
     }
 
@@ -227,12 +880,51 @@ impl Wizer {
         self
     }
 
+    /// Add an argument to pass to the initialization function.
+    ///
+    /// Arguments are passed in the order they are added, after any given in a
+    /// configuration file, and must match the initialization function's
+    /// parameters, which may only be numbers. Invalid arguments are reported
+    /// when wizening.
+    pub fn init_arg(&mut self, arg: wasmtime::Val) -> &mut Self {
+        self.init_arg_vals.push(arg);
+        self
+    }
+
+    /// Should the initialization function's result be treated as a status
+    /// code, where non-zero means that initialization failed?
+    ///
+    /// The initialization function must then return an `i32` or `i64`.
+    ///
+    /// Defaults to `false`.
+    pub fn check_init_status(&mut self, check: bool) -> &mut Self {
+        self.check_init_status = check;
+        self
+    }
+
     /// Add a function rename to perform.
     pub fn func_rename(&mut self, new_name: impl Display, old_name: impl Display) -> &mut Self {
         self.func_renames.push(format!("{}={}", new_name, old_name));
         self
     }
 
+    /// Make a function import return fixed values, rather than trap, when it
+    /// is called during initialization.
+    ///
+    /// The import returns the given values, which must be numbers matching
+    /// its result types, or zeroes if `results` is empty. Every call is
+    /// logged. Invalid values are reported when wizening.
+    pub fn stub_import(
+        &mut self,
+        module: impl Display,
+        name: impl Display,
+        results: &[wasmtime::Val],
+    ) -> &mut Self {
+        self.stub_import_vals
+            .push((module.to_string(), name.to_string(), results.to_vec()));
+        self
+    }
+
     /// Allow WASI imports to be called during initialization?
     ///
     /// This can introduce diverging semantics because the initialization can
@@ -279,6 +971,86 @@ impl Wizer {
         self
     }
 
+    /// When using WASI during initialization, make the host directory
+    /// `host_dir` available at the path `guest_dir`.
+    pub fn map_dir(&mut self, guest_dir: impl Display, host_dir: impl AsRef<Path>) -> &mut Self {
+        self.map_dirs
+            .push(format!("{}::{}", guest_dir, host_dir.as_ref().display()));
+        self
+    }
+
+    /// When using WASI during initialization, should the preopened directories
+    /// be read-only?
+    ///
+    /// Defaults to `false`.
+    pub fn read_only_dirs(&mut self, read_only: bool) -> &mut Self {
+        self.read_only_dirs = read_only;
+        self
+    }
+
+    /// When using WASI during initialization, make the contents of this tar
+    /// archive available in an in-memory, read-only file system at `/`.
+    ///
+    /// Files of later archives replace files of earlier archives with the same
+    /// path, and files given to [`Wizer::vfs_file`] replace them all.
+    pub fn vfs_tar(&mut self, archive: impl Into<PathBuf>) -> &mut Self {
+        self.vfs_tars.push(archive.into());
+        self
+    }
+
+    /// When using WASI during initialization, make a file with these contents
+    /// available at `guest_path` in an in-memory, read-only file system at `/`.
+    ///
+    /// Parent directories are created as needed.
+    pub fn vfs_file(
+        &mut self,
+        guest_path: impl Into<String>,
+        contents: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.vfs_files.insert(guest_path.into(), contents.into());
+        self
+    }
+
+    /// When using WASI during initialization, add a command-line argument to
+    /// give it.
+    ///
+    /// The first argument is conventionally the program name. None are given
+    /// by default.
+    pub fn wasi_arg(&mut self, arg: impl Into<String>) -> &mut Self {
+        self.wasi_args.push(arg.into());
+        self
+    }
+
+    /// When using WASI during initialization, set an environment variable.
+    ///
+    /// This takes precedence over an inherited environment variable with the
+    /// same name.
+    pub fn wasi_env(&mut self, key: impl Display, value: impl Display) -> &mut Self {
+        self.wasi_envs.push(format!("{}={}", key, value));
+        self
+    }
+
+    /// When using WASI during initialization, give it these bytes as stdin.
+    ///
+    /// This takes precedence over inheriting stdin, and replaces any file given
+    /// to [`Wizer::wasi_stdin_file`].
+    pub fn wasi_stdin(&mut self, stdin: impl Into<Vec<u8>>) -> &mut Self {
+        self.wasi_stdin = None;
+        self.wasi_stdin_bytes = Some(stdin.into());
+        self
+    }
+
+    /// When using WASI during initialization, give it the contents of this
+    /// file as stdin.
+    ///
+    /// This takes precedence over inheriting stdin, and replaces any bytes
+    /// given to [`Wizer::wasi_stdin`].
+    pub fn wasi_stdin_file(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.wasi_stdin = Some(path.into());
+        self.wasi_stdin_bytes = None;
+        self
+    }
+
     /// Enable or disable the Wasm multi-memory proposal.
     ///
     /// Defaults to `true`.
@@ -303,91 +1075,1031 @@ impl Wizer {
         self
     }
 
-    /// Initialize the given Wasm, snapshot it, and return the serialized
-    /// snapshot as a new, pre-initialized Wasm module.
-    pub fn run(&self, wasm: &[u8]) -> anyhow::Result<Vec<u8>> {
-        // Parse rename spec.
-        let renames = FuncRenames::parse(&self.func_renames)?;
+    /// The maximum number of data segments to emit for each module.
+    ///
+    /// When the snapshot has more regions of non-zero memory than this, the
+    /// regions with the smallest gaps between them are merged together until
+    /// it fits.
+    ///
+    /// Defaults to `100_000`, the limit that most engines impose.
+    pub fn max_data_segments(&mut self, max: usize) -> &mut Self {
+        self.max_data_segments = Some(max);
+        self
+    }
 
-        // Make sure we're given valid Wasm from the get go.
-        self.wasm_validate(&wasm)?;
+    /// Merge two regions of non-zero memory into a single data segment when
+    /// the gap of zero bytes between them is at most `gap` bytes.
+    ///
+    /// Defaults to `4`, the minimum overhead of defining a new active data
+    /// segment.
+    pub fn data_segment_merge_gap(&mut self, gap: u32) -> &mut Self {
+        self.data_segment_merge_gap = Some(gap);
+        self
+    }
 
-        let mut cx = parse::parse(wasm)?;
-        let instrumented_wasm = instrument::instrument(&cx);
+    /// Emit exactly one data segment for each memory, spanning from its first
+    /// to its last non-zero byte?
+    ///
+    /// Defaults to `false`.
+    pub fn single_data_segment_per_memory(&mut self, enable: bool) -> &mut Self {
+        self.single_data_segment_per_memory = enable;
+        self
+    }
 
-        if cfg!(debug_assertions) {
-            if let Err(error) = self.wasm_validate(&instrumented_wasm) {
-                #[cfg(feature = "wasmprinter")]
-                let wat = wasmprinter::print_bytes(&wasm)
-                    .unwrap_or_else(|e| format!("Disassembling to WAT failed: {}", e));
-                #[cfg(not(feature = "wasmprinter"))]
+    /// Align data segments to host pages, padding them out with zeros?
+    ///
+    /// Wasmtime, for example, can only instantiate modules with copy-on-write
+    /// memory images, rather than by copying data segments into memory, when
+    /// the data segments line up with pages. The padding this adds is logged.
+    ///
+    /// Defaults to `false`.
+    pub fn page_aligned_data_segments(&mut self, enable: bool) -> &mut Self {
+        self.page_aligned_data_segments = enable;
+        self
+    }
+
+    /// The page size to align data segments to with
+    /// [`Wizer::page_aligned_data_segments`].
+    ///
+    /// Must be a power of two no larger than the Wasm page size.
+    ///
+    /// Defaults to 4096.
+    pub fn data_segment_page_size(&mut self, bytes: u32) -> &mut Self {
+        self.data_segment_page_size = Some(bytes);
+        self
+    }
+
+    /// Set each memory's minimum size to the number of pages that hold
+    /// non-zero data, rather than its size after initialization?
+    ///
+    /// Pages above the new minimum are all zero, so growing the memory again
+    /// at runtime restores the snapshotted state. However, the module must grow
+    /// its memory before it touches those pages again. Without a
+    /// [`heap_end_export`][Wizer::heap_end_export], the live heap may end in
+    /// zeroed pages that get cut off, so Wizer logs a warning.
+    ///
+    /// Defaults to `false`.
+    pub fn shrink_memory_mins(&mut self, enable: bool) -> &mut Self {
+        self.shrink_memory_mins = enable;
+        self
+    }
+
+    /// The name of an exported `i32` global, or an exported function of type
+    /// `[] -> i32`, that reports the end of the live heap in the first memory.
+    ///
+    /// The first memory's minimum size always covers the live heap. It is an
+    /// error for the memory to contain non-zero bytes above the heap end. The
+    /// function, if any, is called after initialization and must not have
+    /// side effects.
+    ///
+    /// Implies `shrink_memory_mins(true)`.
+    pub fn heap_end_export(&mut self, name: impl Into<String>) -> &mut Self {
+        self.heap_end_export = Some(name.into());
+        self
+    }
+
+    /// Skip the memory pages that initialization never touched when
+    /// snapshotting, rather than scanning all of memory for non-zero bytes?
+    ///
+    /// This backs memories with anonymous mappings, and asks the kernel which
+    /// of their pages were ever touched. It is only supported on Linux;
+    /// elsewhere, or when the kernel can't tell, all of memory is scanned.
+    /// The wizened module is the same either way.
+    ///
+    /// Defaults to `false`.
+    pub fn dirty_page_tracking(&mut self, enable: bool) -> &mut Self {
+        self.dirty_page_tracking = enable;
+        self
+    }
+
+    /// Exclude the given range of the first memory from the snapshot.
+    ///
+    /// The range is zeroed after initialization, before the snapshot is taken,
+    /// so nothing that initialization stored there ends up in the
+    /// pre-initialized module.
+    pub fn exclude_memory_range(&mut self, range: Range<u32>) -> &mut Self {
+        self.exclude_memory_ranges
+            .push(format!("{}..{}", range.start, range.end));
+        self
+    }
+
+    /// Exclude a range of the first memory from the snapshot, where the range's
+    /// start address and length are the initialized values of the exported
+    /// `i32` globals `addr_global` and `len_global`.
+    pub fn exclude_memory_range_globals(
+        &mut self,
+        addr_global: impl Display,
+        len_global: impl Display,
+    ) -> &mut Self {
+        self.exclude_memory_range_globals
+            .push(format!("{},{}", addr_global, len_global));
+        self
+    }
+
+    /// Zero the shadow stack in the snapshot?
+    ///
+    /// Wizer checks that initialization leaves the stack pointer at its initial
+    /// value, and then zeroes everything below it on the stack, since it is
+    /// garbage left behind by dead stack frames.
+    ///
+    /// The stack pointer is the `__stack_pointer` global in the name section,
+    /// unless [`Wizer::stack_pointer_export`] is given. The stack's extent
+    /// comes from [`Wizer::stack_size`] or, failing that, the `__data_end`
+    /// global.
+    ///
+    /// Defaults to `false`.
+    pub fn zero_shadow_stack(&mut self, enable: bool) -> &mut Self {
+        self.zero_shadow_stack = enable;
+        self
+    }
+
+    /// The name of the exported global that holds the shadow stack pointer.
+    ///
+    /// Implies `zero_shadow_stack(true)`.
+    pub fn stack_pointer_export(&mut self, name: impl Into<String>) -> &mut Self {
+        self.stack_pointer_export = Some(name.into());
+        self
+    }
+
+    /// The size of the shadow stack, in bytes.
+    pub fn stack_size(&mut self, size: u32) -> &mut Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Compress the snapshot's data segments into passive data segments that a
+    /// synthesized start function decompresses at instantiation time.
+    ///
+    /// The output requires the bulk memory proposal. Wizening fails if a
+    /// memory's minimum size has no zeroed room for its compressed image. This
+    /// is not supported with module linking.
+    ///
+    /// Defaults to `false`.
+    pub fn compress_data(&mut self, enable: bool) -> &mut Self {
+        self.compress_data = enable;
+        self
+    }
+
+    /// Emit identical data segment payloads only once.
+    ///
+    /// Without module linking, repeated payloads become passive data segments
+    /// that a synthesized start function copies into place, which requires the
+    /// bulk memory proposal. With module linking, nested instantiations with
+    /// identical state share a single state module.
+    ///
+    /// Defaults to `false`.
+    pub fn dedupe_data_segments(&mut self, enable: bool) -> &mut Self {
+        self.dedupe_data_segments = enable;
+        self
+    }
+
+    /// Write the snapshotted memories as raw image files to the given
+    /// directory, and leave them out of the wizened module.
+    ///
+    /// Each memory's image, `memory<index>.img`, holds its contents up to the
+    /// end of the Wasm page holding its last non-zero byte, and everything
+    /// above that is zero. A `manifest.toml` describes each memory's image and
+    /// minimum size, and the globals' snapshotted values. The wizened module
+    /// has no data section. Other `memory<index>.img` files in the directory
+    /// are removed.
+    ///
+    /// Not supported with module linking, or when compressing or
+    /// deduplicating data segments.
+    ///
+    /// Defaults to including memory in the wizened module as data segments.
+    pub fn heap_image_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.heap_image_dir = Some(dir.into());
+        self
+    }
+
+    /// Record how the module was wizened in a `wizer` custom section, which
+    /// can be read back with [`Provenance::from_wasm`].
+    ///
+    /// Defaults to `false`.
+    pub fn provenance(&mut self, enable: bool) -> &mut Self {
+        self.provenance = enable;
+        self
+    }
+
+    /// Allow wizening a module that has a `wizer` custom section, because it
+    /// was already wizened.
+    ///
+    /// Defaults to `false`.
+    pub fn allow_rewizening(&mut self, allow: bool) -> &mut Self {
+        self.allow_rewizening = allow;
+        self
+    }
+
+    /// Find every disallowed import that initialization calls, rather than
+    /// stopping at the first one.
+    ///
+    /// When enabled, calls to imports that are not allowed during
+    /// initialization return zeroes instead of trapping. If there were any
+    /// such calls, [`Wizer::run`] returns
+    /// [`WizerError::DisallowedImports`] listing them, and otherwise
+    /// wizening continues as usual.
+    ///
+    /// Defaults to `false`.
+    pub fn dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// A directory to cache wizened modules in.
+    ///
+    /// Wizened modules are stored under a hash of the input Wasm, all of the
+    /// options, the Wizer version, and the WASI inputs, such as stdin and the
+    /// contents of preopened directories. If the cache already has a module
+    /// for the same hash, it is returned instead of wizening again.
+    ///
+    /// Caching is disabled when initialization can observe inputs that can't
+    /// be hashed, or have side effects that wouldn't happen again: when WASI
+    /// stdio or environment variables are inherited, when preopened
+    /// directories are writable, when writing heap images, when a Wasmtime
+    /// config hook is set, with [`Wizer::run_and_capture_stdio`], and with
+    /// [`Wizer::run_async`].
+    ///
+    /// Defaults to no cache.
+    pub fn cache_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    /// The target triple to precompile for in [`Wizer::precompile`].
+    ///
+    /// Defaults to the host.
+    pub fn target(&mut self, target: impl Into<String>) -> &mut Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// The Cranelift optimization level to precompile with in
+    /// [`Wizer::precompile`]: `"none"`, `"speed"`, or `"speed_and_size"`.
+    ///
+    /// Defaults to Wasmtime's default.
+    pub fn opt_level(&mut self, level: impl Into<String>) -> &mut Self {
+        self.opt_level = Some(level.into());
+        self
+    }
+
+    /// Enable a Cranelift boolean setting when precompiling in
+    /// [`Wizer::precompile`].
+    ///
+    /// # Safety
+    ///
+    /// This has the same requirements as
+    /// `wasmtime::Config::cranelift_flag_enable`.
+    pub unsafe fn cranelift_flag_enable(&mut self, flag: impl Into<String>) -> &mut Self {
+        self.cranelift_enable.push(flag.into());
+        self
+    }
+
+    /// Set a Cranelift setting when precompiling in [`Wizer::precompile`].
+    ///
+    /// # Safety
+    ///
+    /// This has the same requirements as `wasmtime::Config::cranelift_flag_set`.
+    pub unsafe fn cranelift_flag_set(
+        &mut self,
+        name: impl Display,
+        value: impl Display,
+    ) -> &mut Self {
+        self.cranelift_set.push(format!("{}={}", name, value));
+        self
+    }
+
+    /// Use Wasmtime's code cache when compiling the module for
+    /// initialization?
+    ///
+    /// Defaults to `true`, with the configuration from
+    /// [`Wizer::wasmtime_cache_config`], or Wasmtime's default one, which
+    /// keeps the cache under the user's home directory.
+    pub fn wasmtime_cache(&mut self, enable: bool) -> &mut Self {
+        self.wasmtime_cache = Some(enable);
+        self
+    }
+
+    /// The Wasmtime cache configuration file to use, instead of Wasmtime's
+    /// default one.
+    ///
+    /// It is an error to also disable the cache with
+    /// [`Wizer::wasmtime_cache`].
+    pub fn wasmtime_cache_config(&mut self, path: impl Into<PathBuf>) -> &mut Self {
+        self.wasmtime_cache_config = Some(path.into());
+        self
+    }
+
+    /// The Cranelift optimization level to compile the module for
+    /// initialization with: `"none"`, `"speed"`, or `"speed_and_size"`.
+    ///
+    /// Defaults to Wasmtime's default.
+    pub fn init_opt_level(&mut self, level: impl Into<String>) -> &mut Self {
+        self.init_opt_level = Some(level.into());
+        self
+    }
+
+    /// Canonicalize NaNs produced during initialization, so that the snapshot
+    /// doesn't depend on the host's NaN bit patterns?
+    ///
+    /// Defaults to `false`.
+    pub fn nan_canonicalization(&mut self, enable: bool) -> &mut Self {
+        self.nan_canonicalization = enable;
+        self
+    }
+
+    /// Generate native debug info when compiling the module for
+    /// initialization?
+    ///
+    /// Defaults to `false`.
+    pub fn debug_info(&mut self, enable: bool) -> &mut Self {
+        self.debug_info = enable;
+        self
+    }
+
+    /// Adjust the Wasmtime configuration that initialization uses, before its
+    /// engine is built.
+    ///
+    /// The hook runs before Wizer applies its own options, so Wizer's options
+    /// take precedence. Wizer always sets async support to match how
+    /// initialization is run, and disables fuel consumption, whatever the hook
+    /// sets them to. The Wasm proposals that Wizer depends on are set before
+    /// the hook runs, and changing them in the hook is an error.
+    pub fn wasmtime_config_hook(
+        &mut self,
+        hook: impl Fn(&mut wasmtime::Config) -> anyhow::Result<()> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.wasmtime_config_hook = Some(ConfigHook(Arc::new(hook)));
+        self
+    }
+
+    /// Initialize the given Wasm, snapshot it, and return the serialized
+    /// snapshot as a new, pre-initialized Wasm module.
+    ///
+    /// See [`WizerError`] for the ways this can fail.
+    pub fn run(&self, wasm: &[u8]) -> Result<Vec<u8>, WizerError> {
+        let engine = self.engine()?;
+        self.run_with_engine(&engine, wasm)
+    }
+
+    /// Like [`Wizer::run`], but capture what initialization writes to WASI's
+    /// stdout and stderr, rather than inheriting them, and return it along with
+    /// the wizened module.
+    ///
+    /// Nothing is captured unless WASI is allowed.
+    pub fn run_and_capture_stdio(&self, wasm: &[u8]) -> Result<CapturedOutput, WizerError> {
+        let engine = self.engine()?;
+        let capture = CapturedStdio::default();
+        let wasm = self.wizen_to_vec(&engine, wasm, Some(&capture))?;
+        let stdout = std::mem::take(&mut *capture.stdout.write().unwrap());
+        let stderr = std::mem::take(&mut *capture.stderr.write().unwrap());
+        Ok(CapturedOutput {
+            wasm,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Compile the given wizened Wasm module to native code, and return the
+    /// serialized `wasmtime::Module`, which can be loaded with
+    /// `wasmtime::Module::deserialize`.
+    ///
+    /// The module is compiled for the configured target, optimization level,
+    /// and Cranelift settings, with the Wasm features that this `Wizer` enables
+    /// and Wasmtime's defaults for everything else. The engine that loads the
+    /// module must be configured the same way.
+    ///
+    /// This doesn't reuse the engine that initialization runs in, since that
+    /// engine is configured for snapshotting, not for running the wizened
+    /// module.
+    pub fn precompile(&self, wasm: &[u8]) -> anyhow::Result<Vec<u8>> {
+        log::debug!("Precompiling the wizened Wasm");
+
+        let mut config = wasmtime::Config::new();
+        if let Some(target) = &self.target {
+            config.target(target)?;
+        }
+        config.wasm_multi_memory(self.wasm_multi_memory.unwrap_or(DEFAULT_WASM_MULTI_MEMORY));
+        config.wasm_multi_value(self.wasm_multi_value.unwrap_or(DEFAULT_WASM_MULTI_VALUE));
+        config.wasm_module_linking(
+            self.wasm_module_linking
+                .unwrap_or(DEFAULT_WASM_MODULE_LINKING),
+        );
+
+        if let Some(level) = &self.opt_level {
+            config.cranelift_opt_level(parse_opt_level(level)?);
+        }
+
+        // Safety: the user asked for these settings, and is responsible for
+        // them being correct for the target, just like with `wasmtime
+        // compile`.
+        for flag in &self.cranelift_enable {
+            unsafe {
+                config.cranelift_flag_enable(flag)?;
+            }
+        }
+        for setting in &self.cranelift_set {
+            let equal = setting
+                .find('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid Cranelift setting: {}", setting))?;
+            unsafe {
+                config.cranelift_flag_set(&setting[..equal], &setting[equal + 1..])?;
+            }
+        }
+
+        let engine = wasmtime::Engine::new(&config)?;
+        engine
+            .precompile_module(wasm)
+            .context("failed to precompile the wizened Wasm module")
+    }
+
+    /// Create a Wasmtime engine for initializing Wasm modules with this
+    /// `Wizer`'s options.
+    ///
+    /// Creating an engine is relatively expensive, so when wizening many
+    /// modules, create one engine and pass it to
+    /// [`Wizer::run_with_engine`] for each of them.
+    pub fn engine(&self) -> anyhow::Result<wasmtime::Engine> {
+        self.new_engine(false)
+    }
+
+    /// Like [`Wizer::run`], but initialize the given Wasm in the given engine,
+    /// rather than creating a new one.
+    ///
+    /// The engine must come from [`Wizer::engine`], called on a `Wizer` whose
+    /// `wasm_multi_memory`, `wasm_multi_value`, `wasm_module_linking`, and
+    /// `dirty_page_tracking` options are the same as this one's.
+    pub fn run_with_engine(
+        &self,
+        engine: &wasmtime::Engine,
+        wasm: &[u8],
+    ) -> Result<Vec<u8>, WizerError> {
+        Ok(self.wizen_to_vec(engine, wasm, None)?)
+    }
+
+    /// Like [`Wizer::run`], but write the wizened module to `output` rather
+    /// than returning it.
+    ///
+    /// Sections are written as soon as they are encoded, and data segments are
+    /// written straight from the snapshotted memories, so the whole wizened
+    /// module is never held in memory. The exceptions are when compressing or
+    /// deduplicating data segments, and when module linking is used, which
+    /// need the whole module before writing any of it.
+    ///
+    /// If this fails, `output` may have been partially written to.
+    pub fn run_to_writer(&self, wasm: &[u8], mut output: impl Write) -> Result<(), WizerError> {
+        let engine = self.engine()?;
+        Ok(self.wizen(&engine, wasm, None, &mut output)?)
+    }
+
+    /// Like [`Wizer::run`], but run initialization asynchronously, so that it
+    /// can call async host functions.
+    ///
+    /// This uses Wasmtime's async support. `define_imports` is called with the
+    /// linker that instantiates the module, after WASI is added to it and
+    /// before dummy imports are, so it can define async host functions with
+    /// `Linker::func_wrap*_async` and the like. Its definitions take
+    /// precedence over WASI's. The wizened module is the same as `run` would
+    /// produce if the host functions behaved the same.
+    ///
+    /// Requires the `async` cargo feature.
+    #[cfg(feature = "async")]
+    pub async fn run_async(
+        &self,
+        wasm: &[u8],
+        define_imports: impl FnOnce(&mut wasmtime::Linker<Option<WasiCtx>>) -> anyhow::Result<()> + Send,
+    ) -> Result<Vec<u8>, WizerError> {
+        let engine = self.new_engine(true)?;
+        // The embedder's host functions can't be hashed, so this never uses
+        // the cache directory.
+        let vfs = vfs::Vfs::new(&self.vfs_tars, &self.vfs_files)?;
+        let mut prepared = self.prepare(&engine, wasm, None, vfs)?;
+        let initialized = self
+            .initialize_async(
+                &prepared.cx,
+                &mut prepared.store,
+                &prepared.module,
+                &prepared.init_args,
+                Box::new(define_imports),
+            )
+            .await?;
+        let mut rewritten_wasm = vec![];
+        self.finish(wasm, prepared, initialized, &mut rewritten_wasm)?;
+        self.debug_validate(wasm, &rewritten_wasm);
+        Ok(rewritten_wasm)
+    }
+
+    /// Wizen the given Wasm, and return the wizened module.
+    ///
+    /// See `wizen` for details.
+    fn wizen_to_vec(
+        &self,
+        engine: &wasmtime::Engine,
+        wasm: &[u8],
+        capture: Option<&CapturedStdio>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut rewritten_wasm = vec![];
+        self.wizen(engine, wasm, capture, &mut rewritten_wasm)?;
+        self.debug_validate(wasm, &rewritten_wasm);
+        Ok(rewritten_wasm)
+    }
+
+    /// In debug builds, check that the wizened Wasm is valid.
+    #[cfg_attr(not(feature = "wasmprinter"), allow(unused_variables))]
+    fn debug_validate(&self, wasm: &[u8], rewritten_wasm: &[u8]) {
+        if cfg!(debug_assertions) {
+            if let Err(error) = self.wasm_validate(rewritten_wasm) {
+                #[cfg(feature = "wasmprinter")]
+                let wat = wasmprinter::print_bytes(wasm)
+                    .unwrap_or_else(|e| format!("Disassembling to WAT failed: {}", e));
+                #[cfg(not(feature = "wasmprinter"))]
                 let wat = "`wasmprinter` cargo feature is not enabled".to_string();
-                panic!(
-                    "instrumented Wasm is not valid: {:?}\n\nWAT:\n{}",
-                    error, wat
+                panic!("rewritten Wasm is not valid: {:?}\n\nWAT:\n{}", error, wat);
+            }
+        }
+    }
+
+    /// Wizen the given Wasm, and write the wizened module to `output`.
+    ///
+    /// Uses the cache directory, if any.
+    fn wizen(
+        &self,
+        engine: &wasmtime::Engine,
+        wasm: &[u8],
+        capture: Option<&CapturedStdio>,
+        output: &mut (impl Write + ?Sized),
+    ) -> anyhow::Result<()> {
+        let vfs = vfs::Vfs::new(&self.vfs_tars, &self.vfs_files)?;
+
+        // Captured stdio can't be cached.
+        let cache = match &self.cache_dir {
+            Some(dir) if capture.is_none() => cache::Entry::new(self, dir, wasm, vfs.as_ref())?,
+            _ => None,
+        };
+        let cache = match cache {
+            None => return self.wizen_uncached(engine, wasm, capture, vfs, output),
+            Some(cache) => cache,
+        };
+        if let Some(cached) = cache.get() {
+            match self.wasm_validate(&cached) {
+                Ok(()) => {
+                    output
+                        .write_all(&cached)
+                        .context("failed to write the wizened Wasm")?;
+                    return Ok(());
+                }
+                Err(e) => log::warn!("Ignoring the invalid cached wizened module: {}", e),
+            }
+        }
+
+        let mut tee = cache.tee(output);
+        self.wizen_uncached(engine, wasm, capture, vfs, &mut tee)?;
+        tee.finish();
+        Ok(())
+    }
+
+    /// Like `wizen`, but always wizen, without using the cache directory.
+    fn wizen_uncached(
+        &self,
+        engine: &wasmtime::Engine,
+        wasm: &[u8],
+        capture: Option<&CapturedStdio>,
+        vfs: Option<vfs::Vfs>,
+        output: &mut (impl Write + ?Sized),
+    ) -> anyhow::Result<()> {
+        let mut prepared = self.prepare(engine, wasm, capture, vfs)?;
+        let initialized = self.initialize(
+            &prepared.cx,
+            &mut prepared.store,
+            &prepared.module,
+            &prepared.init_args,
+        )?;
+        self.finish(wasm, prepared, initialized, output)
+    }
+
+    /// Check the options and the given Wasm, and compile the instrumented Wasm
+    /// into a store that is ready to initialize it.
+    fn prepare<'a>(
+        &self,
+        engine: &wasmtime::Engine,
+        wasm: &'a [u8],
+        capture: Option<&CapturedStdio>,
+        vfs: Option<vfs::Vfs>,
+    ) -> anyhow::Result<Prepared<'a>> {
+        // Parse rename spec.
+        let renames = FuncRenames::parse(&self.func_renames)?;
+
+        // Parse excluded memory ranges.
+        let excluded_ranges = ExcludedRange::parse(
+            &self.exclude_memory_ranges,
+            &self.exclude_memory_range_globals,
+        )?;
+
+        if self.compress_data
+            && self
+                .wasm_module_linking
+                .unwrap_or(DEFAULT_WASM_MODULE_LINKING)
+        {
+            anyhow::bail!("compressing data segments is not supported with module linking");
+        }
+        if let Some(page_size) = self.data_segment_page_size {
+            if !page_size.is_power_of_two() || page_size > 65536 {
+                anyhow::bail!(
+                    "invalid data segment page size {}: must be a power of two no larger than \
+                     65536",
+                    page_size
+                );
+            }
+        }
+        if self.heap_image_dir.is_some() {
+            if self
+                .wasm_module_linking
+                .unwrap_or(DEFAULT_WASM_MODULE_LINKING)
+            {
+                anyhow::bail!("heap images are not supported with module linking");
+            }
+            if self.compress_data || self.dedupe_data_segments {
+                anyhow::bail!(
+                    "heap images cannot be combined with compressing or deduplicating data \
+                     segments"
                 );
             }
         }
+        if self.shrink_memory_mins && self.heap_end_export.is_none() {
+            log::warn!(
+                "Shrinking memory minimums without a heap end export: a memory whose \
+                 live heap ends in zeroed pages will be shrunk below it; use \
+                 `--heap-end-export` to keep the live heap in bounds"
+            );
+        }
 
-        let config = self.wasmtime_config()?;
-        let engine = wasmtime::Engine::new(&config)?;
-        let wasi_ctx = self.wasi_context()?;
-        let mut store = wasmtime::Store::new(&engine, wasi_ctx);
-        let module = wasmtime::Module::new(&engine, &instrumented_wasm)
-            .context("failed to compile the Wasm module")?;
-        self.validate_init_func(&module)?;
+        // Make sure we're given valid Wasm from the get go.
+        self.wasm_validate(&wasm)?;
+        self.reject_unsupported_bulk_memory(wasm)?;
 
-        let (instance, has_wasi_initialize) = self.initialize(&mut store, &module)?;
-        let snapshot = snapshot::snapshot(&mut store, &instance);
-        let rewritten_wasm = self.rewrite(
-            &mut cx,
-            &mut store,
-            &snapshot,
-            &renames,
-            has_wasi_initialize,
-        );
+        let previous_provenance = Provenance::from_wasm(wasm)?;
+        if let Some(previous) = &previous_provenance {
+            if !self.allow_rewizening {
+                anyhow::bail!(
+                    "the Wasm module was already wizened by Wizer {}; use \
+                     `--allow-rewizening` to wizen it again",
+                    previous.wizer_version
+                );
+            }
+            log::warn!(
+                "Re-wizening a module that was already wizened by Wizer {}",
+                previous.wizer_version
+            );
+        }
+
+        let cx = parse::parse(wasm)?;
+        let instrumented_wasm = instrument::instrument(&cx);
 
         if cfg!(debug_assertions) {
-            if let Err(error) = self.wasm_validate(&rewritten_wasm) {
+            if let Err(error) = self.wasm_validate(&instrumented_wasm) {
                 #[cfg(feature = "wasmprinter")]
                 let wat = wasmprinter::print_bytes(&wasm)
                     .unwrap_or_else(|e| format!("Disassembling to WAT failed: {}", e));
                 #[cfg(not(feature = "wasmprinter"))]
                 let wat = "`wasmprinter` cargo feature is not enabled".to_string();
-                panic!("rewritten Wasm is not valid: {:?}\n\nWAT:\n{}", error, wat);
+                panic!(
+                    "instrumented Wasm is not valid: {:?}\n\nWAT:\n{}",
+                    error, wat
+                );
             }
         }
 
-        Ok(rewritten_wasm)
+        let wasi_ctx = self.wasi_context(capture, vfs.as_ref())?;
+        let store = wasmtime::Store::new(engine, wasi_ctx);
+        let module = wasmtime::Module::new(engine, &instrumented_wasm)
+            .context("failed to compile the Wasm module")?;
+        let init_args = self.validate_init_func(&module)?;
+
+        Ok(Prepared {
+            cx,
+            store,
+            module,
+            init_args,
+            renames,
+            excluded_ranges,
+            previous_provenance,
+            vfs,
+        })
+    }
+
+    /// Snapshot the initialized instance, and write the wizened module to
+    /// `output`.
+    fn finish(
+        &self,
+        wasm: &[u8],
+        prepared: Prepared<'_>,
+        initialized: Initialized,
+        output: &mut (impl Write + ?Sized),
+    ) -> anyhow::Result<()> {
+        let Prepared {
+            mut cx,
+            mut store,
+            renames,
+            excluded_ranges,
+            previous_provenance,
+            vfs,
+            ..
+        } = prepared;
+        let Initialized {
+            instance,
+            has_wasi_initialize,
+            shadow_stack,
+            heap_end,
+        } = initialized;
+
+        let zeroed_ranges =
+            self.zero_excluded_ranges(&mut store, &instance, &excluded_ranges, shadow_stack)?;
+        if let Some(heap_end) = heap_end {
+            self.check_heap_end(&mut store, &instance, heap_end)?;
+        }
+        let mut snapshot =
+            snapshot::snapshot(&mut store, &instance, &self.snapshot_options(heap_end));
+        if self.heap_image_dir.is_none() {
+            self.check_data_segments(&snapshot)?;
+        }
+        log::info!(
+            "Merging and aligning data segments added {} bytes of zero padding",
+            snapshot.total_zero_padding()
+        );
+        let provenance = if self.provenance {
+            Some(self.make_provenance(
+                wasm,
+                &store,
+                &snapshot,
+                has_wasi_initialize,
+                vfs.as_ref(),
+                zeroed_ranges,
+            ))
+        } else {
+            None
+        };
+        if let Some(dir) = &self.heap_image_dir {
+            heap_image::write(dir, &store, &snapshot).context("failed to write heap images")?;
+            snapshot.data_segments.clear();
+        }
+        // Unless the whole module is needed to post-process it, stream it
+        // straight to the output.
+        if !cx.uses_module_linking() && !self.compress_data && !self.dedupe_data_segments {
+            self.rewrite(
+                &mut cx,
+                &store,
+                &snapshot,
+                &rewrite::RewriteOptions {
+                    renames: &renames,
+                    has_wasi_initialize,
+                    provenance: provenance.as_ref(),
+                },
+                output,
+            )
+            .context("failed to write the wizened Wasm")?;
+            return Ok(());
+        }
+
+        let mut rewritten_wasm = vec![];
+        self.rewrite(
+            &mut cx,
+            &store,
+            &snapshot,
+            &rewrite::RewriteOptions {
+                renames: &renames,
+                has_wasi_initialize,
+                provenance: None,
+            },
+            &mut rewritten_wasm,
+        )?;
+        let rewritten_wasm =
+            if self.compress_data || (self.dedupe_data_segments && !cx.uses_module_linking()) {
+                passive_data::rewrite(
+                    &rewritten_wasm,
+                    &passive_data::PassiveDataOptions {
+                        compress: self.compress_data,
+                        dedupe: self.dedupe_data_segments,
+                    },
+                )?
+            } else {
+                rewritten_wasm
+            };
+        let rewritten_wasm = if provenance.is_some() || previous_provenance.is_some() {
+            provenance::set_provenance(&rewritten_wasm, provenance.as_ref())?
+        } else {
+            rewritten_wasm
+        };
+        output
+            .write_all(&rewritten_wasm)
+            .context("failed to write the wizened Wasm")?;
+        Ok(())
+    }
+
+    fn make_provenance(
+        &self,
+        wasm: &[u8],
+        store: &Store,
+        snapshot: &snapshot::Snapshot,
+        has_wasi_initialize: bool,
+        vfs: Option<&vfs::Vfs>,
+        zeroed_ranges: Vec<Range<u64>>,
+    ) -> Provenance {
+        let mut init_funcs = vec![];
+        if has_wasi_initialize {
+            init_funcs.push("_initialize".to_string());
+        }
+        init_funcs.push(self.init_func.clone());
+
+        Provenance {
+            wizer_version: env!("CARGO_PKG_VERSION").to_string(),
+            init_funcs,
+            init_args: self
+                .init_args
+                .iter()
+                .cloned()
+                .chain(self.init_arg_vals.iter().map(format_number))
+                .collect(),
+            func_renames: self.func_renames.clone(),
+            stub_imports: self
+                .stub_imports
+                .iter()
+                .cloned()
+                .chain(self.stub_import_vals.iter().map(|(module, name, results)| {
+                    let mut spec = format!("{}::{}", module, name);
+                    for (i, result) in results.iter().enumerate() {
+                        spec.push(if i == 0 { '=' } else { ',' });
+                        spec.push_str(&format_number(result));
+                    }
+                    spec
+                }))
+                .collect(),
+            allow_wasi: self.allow_wasi,
+            inherit_stdio: self.inherit_stdio.unwrap_or(DEFAULT_INHERIT_STDIO),
+            inherit_env: self.inherit_env.unwrap_or(DEFAULT_INHERIT_ENV),
+            wasi_args: self.wasi_args.clone(),
+            wasi_envs: self.wasi_envs.clone(),
+            dirs: self.dirs.clone(),
+            map_dirs: self.map_dirs.clone(),
+            read_only_dirs: self.read_only_dirs,
+            vfs_tars: self.vfs_tars.clone(),
+            vfs_sha256: vfs.map(|vfs| vfs.sha256_hex()),
+            zeroed_ranges,
+            zero_padding: snapshot.total_zero_padding(),
+            input_sha256: provenance::sha256_hex(wasm),
+            snapshot_sha256: provenance::snapshot_sha256_hex(store, snapshot),
+        }
+    }
+
+    fn snapshot_options(&self, heap_end: Option<u64>) -> snapshot::SnapshotOptions {
+        snapshot::SnapshotOptions {
+            max_data_segments: self
+                .max_data_segments
+                .unwrap_or(snapshot::DEFAULT_MAX_DATA_SEGMENTS),
+            merge_gap: self
+                .data_segment_merge_gap
+                .unwrap_or(snapshot::MIN_ACTIVE_SEGMENT_OVERHEAD),
+            single_segment_per_memory: self.single_data_segment_per_memory,
+            shrink_memory_mins: self.shrink_memory_mins || self.heap_end_export.is_some(),
+            heap_end,
+            page_size: if self.page_aligned_data_segments {
+                Some(
+                    self.data_segment_page_size
+                        .unwrap_or(DEFAULT_DATA_SEGMENT_PAGE_SIZE),
+                )
+            } else {
+                None
+            },
+            skip_untouched_pages: self.dirty_page_tracking
+                && dirty_pages::memory_creator().is_some(),
+        }
+    }
+
+    /// Check that the snapshot's data segments fit within the configured
+    /// maximum.
+    ///
+    /// Segments for different memories are never merged together, so a
+    /// maximum that is smaller than the number of non-empty memories cannot be
+    /// satisfied.
+    fn check_data_segments(&self, snapshot: &snapshot::Snapshot) -> anyhow::Result<()> {
+        let max = self
+            .max_data_segments
+            .unwrap_or(snapshot::DEFAULT_MAX_DATA_SEGMENTS);
+        if snapshot.data_segments.len() > max {
+            anyhow::bail!(
+                "the snapshot requires {} data segments, but at most {} are allowed",
+                snapshot.data_segments.len(),
+                max
+            );
+        }
+        for snapshot in &snapshot.instantiations {
+            self.check_data_segments(snapshot)?;
+        }
+        Ok(())
     }
 
     // NB: keep this in sync with the wasmparser features.
-    fn wasmtime_config(&self) -> anyhow::Result<wasmtime::Config> {
+    /// Create the engine for initialization, with or without async support.
+    fn new_engine(&self, async_support: bool) -> anyhow::Result<wasmtime::Engine> {
+        let config = self.wasmtime_config(async_support)?;
+        let engine = wasmtime::Engine::new(&config)?;
+        if self.wasmtime_config_hook.is_some() {
+            let mismatched = proposals::mismatched(&engine, &self.wasm_proposals());
+            if !mismatched.is_empty() {
+                anyhow::bail!(
+                    "the Wasmtime config hook changed the {} proposal(s), which conflicts \
+                     with Wizer's options",
+                    mismatched.join(", ")
+                );
+            }
+        }
+        Ok(engine)
+    }
+
+    fn wasmtime_config(&self, async_support: bool) -> anyhow::Result<wasmtime::Config> {
         let mut config = wasmtime::Config::new();
+        for (proposal, enable) in self.wasm_proposals() {
+            proposal.set(&mut config, enable);
+        }
+
+        if let Some(hook) = &self.wasmtime_config_hook {
+            (hook.0)(&mut config).context("the Wasmtime config hook failed")?;
+        }
 
         // Enable Wasmtime's code cache. This makes it so that repeated
         // wizenings of the same Wasm module (e.g. with different WASI inputs)
         // doesn't require re-compiling the Wasm to native code every time.
-        config.cache_config_load_default()?;
+        match (
+            self.wasmtime_cache.unwrap_or(true),
+            &self.wasmtime_cache_config,
+        ) {
+            (true, None) => {
+                config.cache_config_load_default()?;
+            }
+            (true, Some(path)) => {
+                config.cache_config_load(path)?;
+            }
+            (false, None) => {}
+            (false, Some(_)) => anyhow::bail!(
+                "cannot use a Wasmtime cache configuration with the Wasmtime cache disabled"
+            ),
+        }
 
-        // Proposals we support.
-        config.wasm_multi_memory(self.wasm_multi_memory.unwrap_or(DEFAULT_WASM_MULTI_MEMORY));
-        config.wasm_multi_value(self.wasm_multi_value.unwrap_or(DEFAULT_WASM_MULTI_VALUE));
-        config.wasm_module_linking(
-            self.wasm_module_linking
-                .unwrap_or(DEFAULT_WASM_MODULE_LINKING),
-        );
+        if let Some(level) = &self.init_opt_level {
+            config.cranelift_opt_level(parse_opt_level(level)?);
+        }
+        if self.nan_canonicalization {
+            config.cranelift_nan_canonicalization(true);
+        }
+        if self.debug_info {
+            config.debug_info(true);
+        }
+        if self.dirty_page_tracking {
+            match dirty_pages::memory_creator() {
+                Some(creator) => {
+                    config.with_host_memory(creator);
+                }
+                None => log::warn!(
+                    "Dirty page tracking is not supported on this platform; \
+                     scanning all of memory instead"
+                ),
+            }
+        }
 
-        // Proposoals that we should add support for.
-        config.wasm_reference_types(false);
-        config.wasm_simd(false);
-        config.wasm_threads(false);
-        config.wasm_bulk_memory(false);
+        // Initialization calls functions without fuel, synchronously unless
+        // it is run asynchronously.
+        config.async_support(async_support);
+        config.consume_fuel(false);
 
         Ok(config)
     }
 
-    // NB: keep this in sync with the Wasmtime config.
+    // NB: keep this in sync with `wasm_features`.
+    fn wasm_proposals(&self) -> [(proposals::Proposal, bool); 8] {
+        use proposals::Proposal;
+        [
+            // Proposals we support.
+            (
+                Proposal::MultiMemory,
+                self.wasm_multi_memory.unwrap_or(DEFAULT_WASM_MULTI_MEMORY),
+            ),
+            (
+                Proposal::MultiValue,
+                self.wasm_multi_value.unwrap_or(DEFAULT_WASM_MULTI_VALUE),
+            ),
+            (
+                Proposal::ModuleLinking,
+                self.wasm_module_linking
+                    .unwrap_or(DEFAULT_WASM_MODULE_LINKING),
+            ),
+            // Proposoals that we should add support for.
+            (Proposal::ReferenceTypes, false),
+            (Proposal::Simd, false),
+            (Proposal::Threads, false),
+            (Proposal::BulkMemory, false),
+            (Proposal::Memory64, false),
+        ]
+    }
+
+    // NB: keep this in sync with `wasm_proposals`.
     fn wasm_features(&self) -> wasmparser::WasmFeatures {
         wasmparser::WasmFeatures {
             // Proposals that we support.
@@ -440,8 +2152,17 @@ impl Wizer {
 
         let mut validator = wasmparser::Validator::new();
         validator.wasm_features(self.wasm_features());
-        validator.validate_all(wasm)?;
+        validator
+            .validate_all(wasm)
+            .map_err(|e| WizerError::Validation {
+                offset: e.offset(),
+                instruction: None,
+                message: e.message().to_string(),
+            })?;
+        Ok(())
+    }
 
+    fn reject_unsupported_bulk_memory(&self, wasm: &[u8]) -> anyhow::Result<()> {
         // Reject bulk memory stuff that manipulates state we don't
         // snapshot. See the comment inside `wasm_features`.
         let mut wasm = wasm;
@@ -458,24 +2179,22 @@ impl Wizer {
                 wasmparser::Payload::CodeSectionEntry(code) => {
                     let mut ops = code.get_operators_reader().unwrap();
                     while !ops.eof() {
-                        match ops.read().unwrap() {
-                            wasmparser::Operator::TableCopy { .. } => {
-                                anyhow::bail!("unsupported `table.copy` instruction")
-                            }
-                            wasmparser::Operator::TableInit { .. } => {
-                                anyhow::bail!("unsupported `table.init` instruction")
-                            }
-                            wasmparser::Operator::ElemDrop { .. } => {
-                                anyhow::bail!("unsupported `elem.drop` instruction")
-                            }
-                            wasmparser::Operator::DataDrop { .. } => {
-                                anyhow::bail!("unsupported `data.drop` instruction")
-                            }
+                        let (op, offset) = ops.read_with_offset().unwrap();
+                        let instruction = match op {
+                            wasmparser::Operator::TableCopy { .. } => "table.copy",
+                            wasmparser::Operator::TableInit { .. } => "table.init",
+                            wasmparser::Operator::ElemDrop { .. } => "elem.drop",
+                            wasmparser::Operator::DataDrop { .. } => "data.drop",
                             wasmparser::Operator::TableSet { .. } => {
                                 unreachable!("part of reference types")
                             }
                             _ => continue,
-                        }
+                        };
+                        anyhow::bail!(WizerError::Validation {
+                            offset,
+                            instruction: Some(instruction.to_string()),
+                            message: format!("unsupported `{}` instruction", instruction),
+                        });
                     }
                 }
                 wasmparser::Payload::ModuleSectionEntry { parser, .. } => {
@@ -484,8 +2203,13 @@ impl Wizer {
                 wasmparser::Payload::DataSection(mut data) => {
                     let count = data.get_count();
                     for _ in 0..count {
+                        let offset = data.original_position();
                         if let wasmparser::DataKind::Passive = data.read().unwrap().kind {
-                            anyhow::bail!("unsupported passive data segment");
+                            anyhow::bail!(WizerError::Validation {
+                                offset,
+                                instruction: None,
+                                message: "unsupported passive data segment".to_string(),
+                            });
                         }
                     }
                 }
@@ -499,32 +2223,111 @@ impl Wizer {
         Ok(())
     }
 
-    /// Check that the module exports an initialization function, and that the
-    /// function has the correct type.
-    fn validate_init_func(&self, module: &wasmtime::Module) -> anyhow::Result<()> {
+    /// Check that the initialization function exists and has a supported
+    /// type, and parse its arguments.
+    fn validate_init_func(&self, module: &wasmtime::Module) -> anyhow::Result<Vec<wasmtime::Val>> {
         log::debug!("Validating the exported initialization function");
-        match module.get_export(&self.init_func) {
-            Some(wasmtime::ExternType::Func(func_ty)) => {
-                if func_ty.params().len() != 0 || func_ty.results().len() != 0 {
-                    anyhow::bail!(
-                        "the Wasm module's `{}` function export does not have type `[] -> []`",
-                        &self.init_func
-                    );
+        let init_func_error = |message| -> anyhow::Error {
+            WizerError::InitFunc {
+                name: self.init_func.clone(),
+                message,
+            }
+            .into()
+        };
+
+        let func_ty = match module.get_export(&self.init_func) {
+            Some(wasmtime::ExternType::Func(func_ty)) => func_ty,
+            Some(_) => {
+                return Err(init_func_error(format!(
+                    "the Wasm module's `{}` export is not a function",
+                    &self.init_func
+                )))
+            }
+            None => {
+                return Err(init_func_error(format!(
+                    "the Wasm module does not have a `{}` export",
+                    &self.init_func
+                )))
+            }
+        };
+
+        let results: Vec<_> = func_ty.results().collect();
+        let valid_results = match results.as_slice() {
+            [] => !self.check_init_status,
+            [wasmtime::ValType::I32] | [wasmtime::ValType::I64] => true,
+            _ => false,
+        };
+        if !func_ty.params().all(|ty| dummy::is_number(&ty)) || !valid_results {
+            return Err(init_func_error(format!(
+                "the Wasm module's `{}` function export must take only `i32`, `i64`, `f32`, \
+                 and `f64` parameters, and return {}",
+                &self.init_func,
+                if self.check_init_status {
+                    "an `i32` or `i64` status"
+                } else {
+                    "nothing, an `i32`, or an `i64`"
                 }
+            )));
+        }
+
+        let num_args = self.init_args.len() + self.init_arg_vals.len();
+        if func_ty.params().len() != num_args {
+            return Err(init_func_error(format!(
+                "the Wasm module's `{}` function export takes {} parameter(s), but {} \
+                 argument(s) were given",
+                &self.init_func,
+                func_ty.params().len(),
+                num_args
+            )));
+        }
+        let mut params = func_ty.params();
+        let mut args = vec![];
+        // Zip the arguments first, so that `params` isn't advanced past them.
+        for (arg, ty) in self.init_args.iter().zip(params.by_ref()) {
+            args.push(dummy::parse_value(ty, arg).map_err(|e| {
+                init_func_error(format!("invalid initialization function argument: {}", e))
+            })?);
+        }
+        for (ty, arg) in params.zip(&self.init_arg_vals) {
+            if arg.ty() != ty {
+                return Err(init_func_error(format!(
+                    "invalid initialization function argument: expected a value of type {}, \
+                     found one of type {}",
+                    ty,
+                    arg.ty()
+                )));
             }
-            Some(_) => anyhow::bail!(
-                "the Wasm module's `{}` export is not a function",
-                &self.init_func
-            ),
-            None => anyhow::bail!(
-                "the Wasm module does not have a `{}` export",
-                &self.init_func
-            ),
+            args.push(arg.clone());
         }
-        Ok(())
+        Ok(args)
+    }
+
+    /// Get the directories to preopen, as `(guest, host)` paths.
+    fn preopens(&self) -> anyhow::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut preopens = vec![];
+        for dir in &self.dirs {
+            preopens.push((dir.clone(), dir.clone()));
+        }
+        for map_dir in &self.map_dirs {
+            let colons = map_dir.find("::").ok_or_else(|| {
+                anyhow::anyhow!(
+                    "invalid directory mapping `{}`: expected `GUEST::HOST`",
+                    map_dir
+                )
+            })?;
+            preopens.push((
+                PathBuf::from(&map_dir[..colons]),
+                PathBuf::from(&map_dir[colons + 2..]),
+            ));
+        }
+        Ok(preopens)
     }
 
-    fn wasi_context(&self) -> anyhow::Result<Option<WasiCtx>> {
+    fn wasi_context(
+        &self,
+        capture: Option<&CapturedStdio>,
+        vfs: Option<&vfs::Vfs>,
+    ) -> anyhow::Result<Option<WasiCtx>> {
         if !self.allow_wasi {
             return Ok(None);
         }
@@ -533,29 +2336,207 @@ impl Wizer {
         if self.inherit_stdio.unwrap_or(DEFAULT_INHERIT_STDIO) {
             ctx = ctx.inherit_stdio();
         }
+        let stdin =
+            match (&self.wasi_stdin, &self.wasi_stdin_bytes) {
+                (Some(path), _) => Some(std::fs::read(path).with_context(|| {
+                    format!("failed to read stdin from file: {}", path.display())
+                })?),
+                (None, Some(bytes)) => Some(bytes.clone()),
+                (None, None) => None,
+            };
+        if let Some(stdin) = stdin {
+            ctx = ctx.stdin(Box::new(ReadPipe::from(stdin)));
+        }
+        if let Some(capture) = capture {
+            ctx = ctx
+                .stdout(Box::new(WritePipe::from_shared(capture.stdout.clone())))
+                .stderr(Box::new(WritePipe::from_shared(capture.stderr.clone())));
+        }
+
+        ctx = ctx.args(&self.wasi_args)?;
+        let mut envs = vec![];
         if self.inherit_env.unwrap_or(DEFAULT_INHERIT_ENV) {
-            ctx = ctx.inherit_env()?;
+            envs.extend(std::env::vars());
         }
-        for dir in &self.dirs {
-            log::debug!("Preopening directory: {}", dir.display());
+        for env in &self.wasi_envs {
+            let equal = env
+                .find('=')
+                .ok_or_else(|| anyhow::anyhow!("invalid environment variable: {}", env))?;
+            let key = &env[..equal];
+            envs.retain(|(k, _)| k != key);
+            envs.push((key.to_string(), env[equal + 1..].to_string()));
+        }
+        ctx = ctx.envs(&envs)?;
+
+        let preopens = self.preopens()?;
+
+        let (dir_caps, file_caps) = if self.read_only_dirs {
+            read_only_caps()
+        } else {
+            (DirCaps::all(), FileCaps::all())
+        };
+
+        let mut ctx = ctx.build();
+        // File descriptors 0, 1, and 2 are stdio, and preopens come next.
+        let vfs_fd = 3 + u32::try_from(preopens.len()).unwrap();
+        for (fd, (guest, host)) in (3..).zip(preopens) {
+            log::debug!(
+                "Preopening directory {} as {}{}",
+                host.display(),
+                guest.display(),
+                if self.read_only_dirs {
+                    " (read-only)"
+                } else {
+                    ""
+                }
+            );
             let preopened = wasmtime_wasi::sync::Dir::open_ambient_dir(
-                dir,
+                &host,
                 wasmtime_wasi::sync::ambient_authority(),
             )
-            .with_context(|| format!("failed to open directory: {}", dir.display()))?;
-            ctx = ctx.preopened_dir(preopened, dir)?;
+            .with_context(|| format!("failed to open directory: {}", host.display()))?;
+            ctx.insert_dir(
+                fd,
+                Box::new(wasi_cap_std_sync::dir::Dir::from_cap_std(preopened)),
+                dir_caps,
+                file_caps,
+                guest,
+            );
         }
-        Ok(Some(ctx.build()))
+        if let Some(vfs) = vfs {
+            log::debug!("Preopening the virtual file system as {}", vfs::GUEST_PATH);
+            let (dir_caps, file_caps) = read_only_caps();
+            ctx.insert_dir(
+                vfs_fd,
+                vfs.root_dir(),
+                dir_caps,
+                file_caps,
+                PathBuf::from(vfs::GUEST_PATH),
+            );
+        }
+        Ok(Some(ctx))
     }
 
-    /// Instantiate the module and call its initialization function.
+    /// Instantiate the module, call its initialization function, and read the
+    /// heap end, if we were asked to.
     fn initialize(
         &self,
+        cx: &ModuleContext<'_>,
         store: &mut Store,
         module: &wasmtime::Module,
-    ) -> anyhow::Result<(wasmtime::Instance, bool)> {
+        init_args: &[wasmtime::Val],
+    ) -> anyhow::Result<Initialized> {
         log::debug!("Calling the initialization function");
 
+        let (linker, dummy_options) = self.linker(store, module, None)?;
+        let instance = linker
+            .instantiate(&mut *store, module)
+            .context("failed to instantiate the Wasm module")?;
+        let stack_pointer = self.stack_pointer(cx, store, &instance)?;
+
+        let reactor = self.reactor_initialize(store, &instance)?;
+        if let Some(func) = &reactor {
+            func.call(&mut *store, ())
+                .map_err(|trap| init_error(&dummy_options, "_initialize", trap))?;
+        }
+
+        let (init_func, mut results) = self.get_init_func(store, &instance);
+        init_func
+            .call(&mut *store, init_args, &mut results)
+            .map_err(|e| self.init_func_error(&dummy_options, e))?;
+        let shadow_stack = self.check_init_results(
+            cx,
+            store,
+            &instance,
+            &dummy_options,
+            &results,
+            stack_pointer,
+        )?;
+
+        // Read the heap end before zeroing anything, since doing so can call
+        // into the instance, which could write to the zeroed ranges again.
+        let heap_end = match self.heap_end(store, &instance)? {
+            None => None,
+            Some(HeapEnd::Value(heap_end)) => Some(heap_end),
+            Some(HeapEnd::Func(func)) => Some(self.called_heap_end(func.call(&mut *store, ()))?),
+        };
+
+        Ok(Initialized {
+            instance,
+            has_wasi_initialize: reactor.is_some(),
+            shadow_stack,
+            heap_end,
+        })
+    }
+
+    /// Like `initialize`, but run initialization asynchronously, with the
+    /// embedder's definitions added to the linker.
+    ///
+    /// The store's engine must have Wasmtime's async support enabled.
+    #[cfg(feature = "async")]
+    async fn initialize_async(
+        &self,
+        cx: &ModuleContext<'_>,
+        store: &mut Store,
+        module: &wasmtime::Module,
+        init_args: &[wasmtime::Val],
+        define_imports: DefineImports<'_>,
+    ) -> anyhow::Result<Initialized> {
+        log::debug!("Calling the initialization function asynchronously");
+
+        let (linker, dummy_options) = self.linker(store, module, Some(define_imports))?;
+        let instance = linker
+            .instantiate_async(&mut *store, module)
+            .await
+            .context("failed to instantiate the Wasm module")?;
+        let stack_pointer = self.stack_pointer(cx, store, &instance)?;
+
+        let reactor = self.reactor_initialize(store, &instance)?;
+        if let Some(func) = &reactor {
+            func.call_async(&mut *store, ())
+                .await
+                .map_err(|trap| init_error(&dummy_options, "_initialize", trap))?;
+        }
+
+        let (init_func, mut results) = self.get_init_func(store, &instance);
+        init_func
+            .call_async(&mut *store, init_args, &mut results)
+            .await
+            .map_err(|e| self.init_func_error(&dummy_options, e))?;
+        let shadow_stack = self.check_init_results(
+            cx,
+            store,
+            &instance,
+            &dummy_options,
+            &results,
+            stack_pointer,
+        )?;
+
+        let heap_end = match self.heap_end(store, &instance)? {
+            None => None,
+            Some(HeapEnd::Value(heap_end)) => Some(heap_end),
+            Some(HeapEnd::Func(func)) => {
+                Some(self.called_heap_end(func.call_async(&mut *store, ()).await)?)
+            }
+        };
+
+        Ok(Initialized {
+            instance,
+            has_wasi_initialize: reactor.is_some(),
+            shadow_stack,
+            heap_end,
+        })
+    }
+
+    /// Create the linker that instantiates the module: WASI, if it is allowed,
+    /// then the embedder's definitions, if any, and then dummy imports for
+    /// everything else.
+    fn linker(
+        &self,
+        store: &mut Store,
+        module: &wasmtime::Module,
+        define_imports: Option<DefineImports<'_>>,
+    ) -> anyhow::Result<(Linker, DummyOptions)> {
         let mut linker = wasmtime::Linker::new(store.engine());
 
         if self.allow_wasi {
@@ -564,32 +2545,302 @@ impl Wizer {
             })?;
         }
 
-        dummy_imports(&mut *store, &module, &mut linker)?;
+        if let Some(define_imports) = define_imports {
+            linker.allow_shadowing(true);
+            define_imports(&mut linker)?;
+            linker.allow_shadowing(false);
+        }
 
-        let instance = linker
-            .instantiate(&mut *store, module)
-            .context("failed to instantiate the Wasm module")?;
+        let dummy_options = DummyOptions {
+            allow_wasi: self.allow_wasi,
+            stubs: StubImports::parse(&self.stub_imports, &self.stub_import_vals)?,
+            record: if self.dry_run {
+                Some(Default::default())
+            } else {
+                None
+            },
+        };
+        dummy_imports(&mut *store, module, &mut linker, &dummy_options)?;
+        Ok((linker, dummy_options))
+    }
 
-        let mut has_wasi_initialize = false;
+    /// Find the stack pointer, if we were asked to zero the shadow stack or to
+    /// check the stack pointer.
+    fn stack_pointer(
+        &self,
+        cx: &ModuleContext<'_>,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+    ) -> anyhow::Result<Option<StackPointer>> {
+        if self.zero_shadow_stack || self.stack_pointer_export.is_some() {
+            Ok(Some(self.find_stack_pointer(cx, &mut *store, instance)?))
+        } else {
+            Ok(None)
+        }
+    }
 
-        if let Some(export) = instance.get_export(&mut *store, "_initialize") {
-            if let Extern::Func(func) = export {
-                func.typed::<(), (), _>(&store)
-                    .and_then(|f| {
-                        has_wasi_initialize = true;
-                        f.call(&mut *store, ()).map_err(Into::into)
-                    })
-                    .context("calling the Reactor initialization function")?;
-            }
+    /// Get the WASI reactor `_initialize` function, if the module has one.
+    fn reactor_initialize(
+        &self,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+    ) -> anyhow::Result<Option<wasmtime::TypedFunc<(), ()>>> {
+        match instance.get_export(&mut *store, "_initialize") {
+            Some(Extern::Func(func)) => Ok(Some(
+                func.typed::<(), (), _>(&*store)
+                    .context("calling the Reactor initialization function")?,
+            )),
+            _ => Ok(None),
         }
+    }
 
+    /// Get the initialization function, and space for its results.
+    fn get_init_func(
+        &self,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+    ) -> (wasmtime::Func, Vec<wasmtime::Val>) {
         let init_func = instance
-            .get_typed_func::<(), (), _>(&mut *store, &self.init_func)
+            .get_func(&mut *store, &self.init_func)
             .expect("checked by `validate_init_func`");
-        init_func
-            .call(&mut *store, ())
-            .with_context(|| format!("the `{}` function trapped", self.init_func))?;
+        let results = dummy::dummy_values(init_func.ty(&*store).results());
+        (init_func, results)
+    }
+
+    fn init_func_error(&self, dummy_options: &DummyOptions, e: anyhow::Error) -> anyhow::Error {
+        match e.downcast::<wasmtime::Trap>() {
+            Ok(trap) => init_error(dummy_options, &self.init_func, trap).into(),
+            Err(e) => e,
+        }
+    }
+
+    /// Check what the initialization function did: that it didn't call any
+    /// disallowed imports, and that it returned a zero status, if we were asked
+    /// to check it.
+    ///
+    /// Returns the range of memory that the shadow stack occupies, if we were
+    /// asked to zero it.
+    fn check_init_results(
+        &self,
+        cx: &ModuleContext<'_>,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+        dummy_options: &DummyOptions,
+        results: &[wasmtime::Val],
+        stack_pointer: Option<StackPointer>,
+    ) -> anyhow::Result<Option<Range<u64>>> {
+        if let Some(error) = disallowed_imports(dummy_options) {
+            return Err(error.into());
+        }
+        if let Some(result) = results.first() {
+            let status = match result {
+                wasmtime::Val::I32(x) => i64::from(*x),
+                wasmtime::Val::I64(x) => *x,
+                _ => unreachable!("checked by `validate_init_func`"),
+            };
+            log::debug!("The initialization function returned {}", status);
+            if self.check_init_status && status != 0 {
+                return Err(WizerError::InitStatus {
+                    name: self.init_func.clone(),
+                    status,
+                }
+                .into());
+            }
+        }
+
+        match stack_pointer {
+            Some(sp) => Ok(Some(self.shadow_stack(cx, &mut *store, instance, sp)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Zero out the ranges of memory that should be excluded from the
+    /// snapshot, including the shadow stack, if any, and return the zeroed
+    /// ranges.
+    fn zero_excluded_ranges(
+        &self,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+        excluded_ranges: &[ExcludedRange],
+        shadow_stack: Option<Range<u64>>,
+    ) -> anyhow::Result<Vec<Range<u64>>> {
+        let mut ranges = vec![];
+        for range in excluded_ranges {
+            ranges.push(range.resolve(&mut *store, instance)?);
+        }
+        ranges.extend(shadow_stack);
+        if ranges.is_empty() {
+            return Ok(ranges);
+        }
+
+        let memory = instance
+            .get_memory(&mut *store, "__wizer_memory_0")
+            .ok_or_else(|| anyhow::anyhow!("the Wasm module does not define a memory"))?;
+        for range in &ranges {
+            log::info!(
+                "Excluding memory range {:#x}..{:#x} from the snapshot",
+                range.start,
+                range.end
+            );
+            let data = memory.data_mut(&mut *store);
+            let start = usize::try_from(range.start).unwrap();
+            let end = usize::try_from(range.end).unwrap();
+            if end > data.len() {
+                anyhow::bail!(
+                    "excluded memory range {:#x}..{:#x} is beyond the end of memory",
+                    range.start,
+                    range.end
+                );
+            }
+            data[start..end].fill(0);
+        }
+        Ok(ranges)
+    }
+
+    /// Get the heap end export, if we were asked to read the end of the live
+    /// heap from one.
+    fn heap_end(
+        &self,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+    ) -> anyhow::Result<Option<HeapEnd>> {
+        let name = match &self.heap_end_export {
+            None => return Ok(None),
+            Some(name) => name,
+        };
+
+        log::debug!("Reading the heap end from `{}`", name);
+        let heap_end = match instance.get_export(&mut *store, name) {
+            Some(Extern::Global(global)) => match global.get(&mut *store) {
+                wasmtime::Val::I32(x) => HeapEnd::Value(u64::from(x as u32)),
+                _ => anyhow::bail!("the Wasm module's `{}` global export is not an `i32`", name),
+            },
+            Some(Extern::Func(func)) => {
+                HeapEnd::Func(func.typed::<(), i32, _>(&*store).with_context(|| {
+                    format!(
+                        "the Wasm module's `{}` function export does not have type `[] -> i32`",
+                        name
+                    )
+                })?)
+            }
+            Some(_) => anyhow::bail!(
+                "the Wasm module's `{}` export is not a global or function",
+                name
+            ),
+            None => anyhow::bail!("the Wasm module does not have a `{}` export", name),
+        };
+        Ok(Some(heap_end))
+    }
 
-        Ok((instance, has_wasi_initialize))
+    /// Get the heap end from the result of calling the heap end function.
+    fn called_heap_end(&self, result: Result<i32, wasmtime::Trap>) -> anyhow::Result<u64> {
+        let name = self.heap_end_export.as_ref().unwrap();
+        let heap_end = result.with_context(|| format!("the `{}` function trapped", name))?;
+        Ok(u64::from(heap_end as u32))
     }
+
+    /// Check that no non-zero data lives above the given heap end.
+    fn check_heap_end(
+        &self,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+        heap_end: u64,
+    ) -> anyhow::Result<()> {
+        let name = self.heap_end_export.as_ref().unwrap();
+        let memory = instance
+            .get_memory(&mut *store, "__wizer_memory_0")
+            .ok_or_else(|| anyhow::anyhow!("the Wasm module does not define a memory"))?;
+        let data = memory.data(&*store);
+        let start = usize::try_from(heap_end).unwrap();
+        if start > data.len() {
+            anyhow::bail!(
+                "the heap end {:#x} reported by `{}` is beyond the end of memory",
+                heap_end,
+                name
+            );
+        }
+        if let Some(i) = data[start..].iter().position(|b| *b != 0) {
+            anyhow::bail!(
+                "found non-zero memory at {:#x}, above the heap end {:#x} reported by `{}`; \
+                 it would be lost when the memory is grown again at runtime",
+                start + i,
+                heap_end,
+                name
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The error for an initialization function that trapped.
+///
+/// In a dry run, the trap is most likely caused by the zeroes that disallowed
+/// imports returned, so report those instead.
+fn init_error(dummy_options: &DummyOptions, func: &str, trap: wasmtime::Trap) -> WizerError {
+    disallowed_imports(dummy_options).unwrap_or_else(|| WizerError::Trap {
+        func: func.to_string(),
+        backtrace: error::backtrace(&trap),
+        trap,
+    })
+}
+
+/// The error for the disallowed imports that a dry run's initialization
+/// called, if any.
+fn disallowed_imports(dummy_options: &DummyOptions) -> Option<WizerError> {
+    let record = dummy_options.record.as_ref()?;
+    let imports = std::mem::take(&mut *record.lock().unwrap());
+    if imports.is_empty() {
+        None
+    } else {
+        Some(WizerError::DisallowedImports { imports })
+    }
+}
+
+/// Parse a Cranelift optimization level option.
+fn parse_opt_level(level: &str) -> anyhow::Result<wasmtime::OptLevel> {
+    Ok(match level {
+        "none" => wasmtime::OptLevel::None,
+        "speed" => wasmtime::OptLevel::Speed,
+        "speed_and_size" => wasmtime::OptLevel::SpeedAndSize,
+        _ => anyhow::bail!("invalid optimization level: {}", level),
+    })
+}
+
+/// Format a number as `dummy::parse_value` parses it, exactly, including NaN
+/// payloads.
+pub(crate) fn format_number(val: &wasmtime::Val) -> String {
+    let nan = |negative: bool, payload: u64| {
+        format!("{}nan:{:#x}", if negative { "-" } else { "" }, payload)
+    };
+    match *val {
+        wasmtime::Val::I32(x) => x.to_string(),
+        wasmtime::Val::I64(x) => x.to_string(),
+        wasmtime::Val::F32(x) if f32::from_bits(x).is_nan() => {
+            nan(x >> 31 != 0, u64::from(x & ((1 << 23) - 1)))
+        }
+        wasmtime::Val::F64(x) if f64::from_bits(x).is_nan() => {
+            nan(x >> 63 != 0, x & ((1 << 52) - 1))
+        }
+        wasmtime::Val::F32(x) => f32::from_bits(x).to_string(),
+        wasmtime::Val::F64(x) => f64::from_bits(x).to_string(),
+        ref val => format!("{:?}", val),
+    }
+}
+
+/// The capabilities of read-only preopened directories and the files opened
+/// in them.
+fn read_only_caps() -> (DirCaps, FileCaps) {
+    (
+        DirCaps::OPEN
+            | DirCaps::READDIR
+            | DirCaps::READLINK
+            | DirCaps::PATH_FILESTAT_GET
+            | DirCaps::FILESTAT_GET,
+        FileCaps::READ
+            | FileCaps::SEEK
+            | FileCaps::TELL
+            | FileCaps::ADVISE
+            | FileCaps::FILESTAT_GET
+            | FileCaps::POLL_READWRITE,
+    )
 }
@@ -3,6 +3,7 @@ use crate::info::{
     Module, ModuleContext,
 };
 use crate::stack_ext::StackExt;
+use crate::WizerError;
 use anyhow::{Context, Result};
 use std::convert::TryFrom;
 use wasm_encoder::SectionId;
@@ -251,12 +252,19 @@ fn import_section<'a>(
     for _ in 0..count {
         let imp = imports.read()?;
 
+        let import_error = |message: String| WizerError::Import {
+            module: imp.module.to_string(),
+            name: imp.field.map(|f| f.to_string()),
+            message,
+        };
+
         if imp.module.starts_with("__wizer_")
             || imp.field.map_or(false, |f| f.starts_with("__wizer_"))
         {
-            anyhow::bail!(
+            anyhow::bail!(import_error(
                 "input Wasm module already imports entities named with the `__wizer_*` prefix"
-            );
+                    .to_string()
+            ));
         }
 
         match (implicit_instance_import.as_mut(), imp.field) {
@@ -287,7 +295,8 @@ fn import_section<'a>(
             stack.top().module.types(cx),
             stack.top().module.is_root(),
             &module.entity_type(cx, imp.ty),
-        )?;
+        )
+        .map_err(|e| import_error(e.to_string()))?;
         if let wasmparser::ImportSectionEntryType::Instance(_) = imp.ty {
             instance_import_count += 1;
         }
@@ -0,0 +1,485 @@
+//! Moving the snapshot's data segments into passive data segments.
+//!
+//! This is an optional pass that runs after the rewrite pass. It replaces some
+//! of the rewritten module's active data segments with passive data segments,
+//! and injects a start function that copies them into memory with
+//! `memory.init` when the module is instantiated. This lets us
+//!
+//! * compress each memory's initialized region (see the `compress` module),
+//!   and
+//!
+//! * emit payloads that are repeated across memories, or at different offsets
+//!   within the same memory, only once.
+//!
+//! The output relies on the bulk memory proposal.
+
+use crate::compress;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use wasm_encoder::{Instruction, SectionId};
+use wasmparser::SectionReader;
+
+/// An upper bound on the number of bytes that adding the start function costs:
+/// its type, function, and code entries, the start section, and the data count
+/// section.
+const START_FUNC_COST: usize = 32;
+
+/// Options for the passive data pass.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PassiveDataOptions {
+    /// Compress each memory's initialized region.
+    pub compress: bool,
+
+    /// Emit identical data segment payloads only once.
+    pub dedupe: bool,
+}
+
+/// An active data segment from the rewritten module.
+pub(crate) struct ActiveSegment<'a> {
+    pub memory_index: u32,
+    pub offset: u32,
+    pub data: &'a [u8],
+}
+
+/// A payload that is shared by multiple active data segments.
+struct SharedPayload {
+    /// The indices of the active segments with this payload.
+    uses: Vec<usize>,
+}
+
+/// Move the data segments of the given rewritten Wasm module into passive
+/// data segments, as configured by the given options.
+///
+/// The module may not use module linking.
+pub(crate) fn rewrite(wasm: &[u8], options: &PassiveDataOptions) -> anyhow::Result<Vec<u8>> {
+    log::debug!("Rewriting data segments into passive data segments");
+
+    let mut sections = vec![];
+    let mut num_types = 0;
+    let mut num_funcs = 0;
+    let mut memories = vec![];
+    let mut segments = vec![];
+
+    let mut parser = wasmparser::Parser::new(0);
+    let mut rest = wasm;
+    loop {
+        let (payload, consumed) = match parser.parse(rest, true)? {
+            wasmparser::Chunk::NeedMoreData(_) => unreachable!(),
+            wasmparser::Chunk::Parsed { payload, consumed } => (payload, consumed),
+        };
+        rest = &rest[consumed..];
+
+        use wasmparser::Payload::*;
+        let (id, range) = match payload {
+            Version { .. } => continue,
+            End => break,
+            TypeSection(types) => {
+                num_types = types.get_count();
+                (SectionId::Type, types.range())
+            }
+            ImportSection(mut imports) => {
+                for _ in 0..imports.get_count() {
+                    if let wasmparser::ImportSectionEntryType::Function(_) = imports.read()?.ty {
+                        num_funcs += 1;
+                    }
+                }
+                (SectionId::Import, imports.range())
+            }
+            FunctionSection(funcs) => {
+                num_funcs += funcs.get_count();
+                (SectionId::Function, funcs.range())
+            }
+            TableSection(tables) => (SectionId::Table, tables.range()),
+            MemorySection(mut mems) => {
+                for _ in 0..mems.get_count() {
+                    memories.push(mems.read()?);
+                }
+                (SectionId::Memory, mems.range())
+            }
+            GlobalSection(globals) => (SectionId::Global, globals.range()),
+            ExportSection(exports) => (SectionId::Export, exports.range()),
+            StartSection { .. } => anyhow::bail!("the rewritten module has a start function"),
+            ElementSection(elems) => (SectionId::Element, elems.range()),
+            DataCountSection { range, .. } => (SectionId::DataCount, range),
+            DataSection(mut data) => {
+                for _ in 0..data.get_count() {
+                    let segment = data.read()?;
+                    let (memory_index, init_expr) = match segment.kind {
+                        wasmparser::DataKind::Active {
+                            memory_index,
+                            init_expr,
+                        } => (memory_index, init_expr),
+                        wasmparser::DataKind::Passive => unreachable!(),
+                    };
+                    let offset = match init_expr.get_operators_reader().read()? {
+                        wasmparser::Operator::I32Const { value } => value as u32,
+                        _ => unreachable!("we only emit constant offsets"),
+                    };
+                    segments.push(ActiveSegment {
+                        memory_index,
+                        offset,
+                        data: segment.data,
+                    });
+                }
+                (SectionId::Data, data.range())
+            }
+            CodeSectionStart { range, size, .. } => {
+                rest = &rest[usize::try_from(size).unwrap()..];
+                parser.skip_section();
+                (SectionId::Code, range)
+            }
+            CustomSection { range, .. } => (SectionId::Custom, range),
+            ModuleSectionStart { .. }
+            | AliasSection(_)
+            | InstanceSection(_)
+            | ModuleSectionEntry { .. } => {
+                anyhow::bail!("passive data segments are not supported with module linking")
+            }
+            CodeSectionEntry(_) | UnknownSection { .. } | EventSection(_) => unreachable!(),
+        };
+        sections.push(wasm_encoder::RawSection {
+            id: id as u8,
+            data: &wasm[range.start..range.end],
+        });
+    }
+
+    let images = if options.compress {
+        compress::compress_images(&segments, &memories)?
+    } else {
+        vec![]
+    };
+    let shared = if options.dedupe {
+        shared_payloads(&segments, &images)
+    } else {
+        vec![]
+    };
+    if images.is_empty() && shared.is_empty() {
+        return Ok(wasm.to_vec());
+    }
+
+    // The new functions' types are appended to the type section.
+    let start_type = num_types;
+    let decompress_type = num_types + 1;
+    let mut types = wasm_encoder::TypeSection::new();
+    types.function(vec![], vec![]);
+    types.function(vec![wasm_encoder::ValType::I32; 3], vec![]);
+
+    // One decompressor per compressed memory, because the memory index is an
+    // immediate of the memory instructions, and then the start function.
+    let mut funcs = wasm_encoder::FunctionSection::new();
+    let mut code = wasm_encoder::CodeSection::new();
+    for image in &images {
+        funcs.function(decompress_type);
+        code.function(&compress::decompressor(image.memory_index));
+    }
+    funcs.function(start_type);
+
+    // Passive data segments go after the remaining active segments.
+    let shared_segments: HashSet<usize> =
+        shared.iter().flat_map(|s| s.uses.iter().copied()).collect();
+    let mut data = wasm_encoder::DataSection::new();
+    let mut num_data = 0;
+    for (i, seg) in segments.iter().enumerate() {
+        if images
+            .iter()
+            .all(|img| img.memory_index != seg.memory_index)
+            && !shared_segments.contains(&i)
+        {
+            data.active(
+                seg.memory_index,
+                Instruction::I32Const(seg.offset as i32),
+                seg.data.iter().copied(),
+            );
+            num_data += 1;
+        }
+    }
+
+    let mut start = wasm_encoder::Function::new(vec![]);
+    for (i, image) in images.iter().enumerate() {
+        let mem = image.memory_index;
+        let len = u32::try_from(image.compressed.len()).unwrap() as i32;
+        data.passive(image.compressed.iter().copied());
+        let data_index = num_data;
+        num_data += 1;
+
+        start.instruction(Instruction::I32Const(image.scratch as i32));
+        start.instruction(Instruction::I32Const(0));
+        start.instruction(Instruction::I32Const(len));
+        start.instruction(Instruction::MemoryInit {
+            mem,
+            data: data_index,
+        });
+        start.instruction(Instruction::I32Const(image.scratch as i32));
+        start.instruction(Instruction::I32Const(len));
+        start.instruction(Instruction::I32Const(image.offset as i32));
+        start.instruction(Instruction::Call(num_funcs + u32::try_from(i).unwrap()));
+        start.instruction(Instruction::I32Const(image.scratch as i32));
+        start.instruction(Instruction::I32Const(0));
+        start.instruction(Instruction::I32Const(len));
+        start.instruction(Instruction::MemoryFill(mem));
+        start.instruction(Instruction::DataDrop(data_index));
+    }
+    for payload in &shared {
+        let first = &segments[payload.uses[0]];
+        let len = u32::try_from(first.data.len()).unwrap() as i32;
+        data.passive(first.data.iter().copied());
+        let data_index = num_data;
+        num_data += 1;
+
+        for &i in &payload.uses {
+            start.instruction(Instruction::I32Const(segments[i].offset as i32));
+            start.instruction(Instruction::I32Const(0));
+            start.instruction(Instruction::I32Const(len));
+            start.instruction(Instruction::MemoryInit {
+                mem: segments[i].memory_index,
+                data: data_index,
+            });
+        }
+        start.instruction(Instruction::DataDrop(data_index));
+    }
+    start.instruction(Instruction::End);
+    code.function(&start);
+    let start_func = num_funcs + u32::try_from(images.len()).unwrap();
+
+    let mut data_count = vec![];
+    data_count.extend(wasm_encoder::encoders::u32(num_data));
+
+    let mut start_section = vec![];
+    start_section.extend(wasm_encoder::encoders::u32(start_func));
+
+    // Now emit the module, replacing or adding the sections we changed. Every
+    // changed section is keyed by its position in the required section order.
+    let existing = |id: SectionId| sections.iter().find(|s| s.id == id as u8).map(|s| s.data);
+    let mut pending: Vec<(SectionId, Vec<u8>)> = vec![
+        (
+            SectionId::Type,
+            append_entries(existing(SectionId::Type), &types),
+        ),
+        (
+            SectionId::Function,
+            append_entries(existing(SectionId::Function), &funcs),
+        ),
+        (SectionId::Start, start_section),
+        (SectionId::DataCount, data_count),
+        (
+            SectionId::Code,
+            append_entries(existing(SectionId::Code), &code),
+        ),
+        (SectionId::Data, encode_contents(&data)),
+    ];
+    pending.reverse();
+
+    let mut module = wasm_encoder::Module::new();
+    let flush = |module: &mut wasm_encoder::Module,
+                 pending: &mut Vec<(SectionId, Vec<u8>)>,
+                 before: Option<u32>| {
+        while let Some((id, _)) = pending.last() {
+            if matches!(before, Some(order) if section_order(*id) >= order) {
+                break;
+            }
+            let (id, data) = pending.pop().unwrap();
+            module.section(&wasm_encoder::RawSection {
+                id: id as u8,
+                data: &data,
+            });
+        }
+    };
+    for section in &sections {
+        if section.id == SectionId::Custom as u8 {
+            // Keep the name section last, as some tools expect.
+            let mut reader = wasmparser::BinaryReader::new(section.data);
+            if matches!(reader.read_string(), Ok("name")) {
+                flush(&mut module, &mut pending, None);
+            }
+            module.section(section);
+            continue;
+        }
+
+        let order = section_order_of_raw(section.id);
+        flush(&mut module, &mut pending, Some(order));
+        match pending.last() {
+            Some((id, _)) if *id as u8 == section.id => {
+                flush(&mut module, &mut pending, Some(order + 1));
+            }
+            _ => {
+                module.section(section);
+            }
+        }
+    }
+    flush(&mut module, &mut pending, None);
+
+    Ok(module.finish())
+}
+
+/// Find the payloads that are repeated across the active segments that are not
+/// part of a compressed image, and that are smaller when shared.
+fn shared_payloads(
+    segments: &[ActiveSegment],
+    images: &[compress::CompressedImage],
+) -> Vec<SharedPayload> {
+    let mut shared: Vec<SharedPayload> = vec![];
+    let mut payload_to_shared = HashMap::new();
+    for (i, seg) in segments.iter().enumerate() {
+        if images
+            .iter()
+            .any(|img| img.memory_index == seg.memory_index)
+        {
+            continue;
+        }
+        let index = *payload_to_shared.entry(seg.data).or_insert_with(|| {
+            shared.push(SharedPayload { uses: vec![] });
+            shared.len() - 1
+        });
+        shared[index].uses.push(i);
+    }
+
+    // Every passive segment's index is less than the number of segments.
+    let max_data_index = u32::try_from(segments.len() + images.len()).unwrap();
+    let mut saved = 0;
+    shared.retain(|s| {
+        let active: usize = s.uses.iter().map(|&i| active_cost(&segments[i])).sum();
+        let passive = shared_cost(segments, s, max_data_index);
+        if s.uses.len() > 1 && passive < active {
+            saved += active - passive;
+            true
+        } else {
+            false
+        }
+    });
+
+    // Without compressed images, sharing also has to pay for the start
+    // function.
+    if images.is_empty() && saved <= START_FUNC_COST {
+        return vec![];
+    }
+    if !shared.is_empty() {
+        log::info!(
+            "Sharing {} data segment payloads, saving about {} bytes",
+            shared.len(),
+            saved
+        );
+    }
+    shared
+}
+
+/// The number of bytes that the given active data segment is encoded in.
+fn active_cost(seg: &ActiveSegment) -> usize {
+    let len = u32::try_from(seg.data.len()).unwrap();
+    let memory = if seg.memory_index == 0 {
+        0
+    } else {
+        wasm_encoder::encoders::u32(seg.memory_index).len()
+    };
+    // The flags, the memory index, `i32.const <offset> end`, and the payload.
+    1 + memory
+        + 1
+        + wasm_encoder::encoders::s32(seg.offset as i32).len()
+        + 1
+        + wasm_encoder::encoders::u32(len).len()
+        + seg.data.len()
+}
+
+/// An upper bound on the number of bytes that the given shared payload is
+/// encoded in: its passive data segment, and the start function instructions
+/// that copy it to each of its uses and then drop it.
+fn shared_cost(segments: &[ActiveSegment], shared: &SharedPayload, max_data_index: u32) -> usize {
+    let data = segments[shared.uses[0]].data;
+    let len = u32::try_from(data.len()).unwrap();
+    let data_index = wasm_encoder::encoders::u32(max_data_index).len();
+
+    let passive = 1 + wasm_encoder::encoders::u32(len).len() + data.len();
+    let drop = 2 + data_index;
+    let inits: usize = shared
+        .uses
+        .iter()
+        .map(|&i| {
+            // `i32.const <offset>`, `i32.const 0`, `i32.const <len>`, and
+            // `memory.init <data> <mem>`.
+            1 + wasm_encoder::encoders::s32(segments[i].offset as i32).len()
+                + 2
+                + 1
+                + wasm_encoder::encoders::s32(len as i32).len()
+                + 2
+                + data_index
+                + wasm_encoder::encoders::u32(segments[i].memory_index).len()
+        })
+        .sum();
+    passive + drop + inits
+}
+
+/// The position of a non-custom section in the order that sections must
+/// appear in.
+fn section_order(id: SectionId) -> u32 {
+    match id {
+        SectionId::Type => 1,
+        SectionId::Import => 2,
+        SectionId::Function => 3,
+        SectionId::Table => 4,
+        SectionId::Memory => 5,
+        SectionId::Global => 6,
+        SectionId::Export => 7,
+        SectionId::Start => 8,
+        SectionId::Element => 9,
+        SectionId::DataCount => 10,
+        SectionId::Code => 11,
+        SectionId::Data => 12,
+        SectionId::Custom | SectionId::Module | SectionId::Instance | SectionId::Alias => {
+            unreachable!()
+        }
+    }
+}
+
+fn section_order_of_raw(id: u8) -> u32 {
+    let id = [
+        SectionId::Type,
+        SectionId::Import,
+        SectionId::Function,
+        SectionId::Table,
+        SectionId::Memory,
+        SectionId::Global,
+        SectionId::Export,
+        SectionId::Start,
+        SectionId::Element,
+        SectionId::DataCount,
+        SectionId::Code,
+        SectionId::Data,
+    ]
+    .iter()
+    .copied()
+    .find(|s| *s as u8 == id)
+    .unwrap();
+    section_order(id)
+}
+
+/// Encode a section's contents, without the leading section size.
+fn encode_contents(section: &impl wasm_encoder::Section) -> Vec<u8> {
+    let mut encoded = vec![];
+    section.encode(&mut encoded);
+    let mut reader = wasmparser::BinaryReader::new(&encoded);
+    reader.read_var_u32().unwrap();
+    encoded[reader.original_position()..].to_vec()
+}
+
+/// Append the entries of `extra` to the entries of the `existing` raw section
+/// contents, if any.
+fn append_entries(existing: Option<&[u8]>, extra: &impl wasm_encoder::Section) -> Vec<u8> {
+    let extra = encode_contents(extra);
+    let mut reader = wasmparser::BinaryReader::new(&extra);
+    let extra_count = reader.read_var_u32().unwrap();
+    let extra_entries = &extra[reader.original_position()..];
+
+    let (count, entries) = match existing {
+        None => (0, &[][..]),
+        Some(data) => {
+            let mut reader = wasmparser::BinaryReader::new(data);
+            let count = reader.read_var_u32().unwrap();
+            (count, &data[reader.original_position()..])
+        }
+    };
+
+    let mut data = vec![];
+    data.extend(wasm_encoder::encoders::u32(count + extra_count));
+    data.extend_from_slice(entries);
+    data.extend_from_slice(extra_entries);
+    data
+}
@@ -0,0 +1,127 @@
+//! The Wasm proposals that the initialization engine enables or disables.
+//!
+//! Wasmtime doesn't let us read which proposals a `Config` enables, so to tell
+//! whether a Wasmtime config hook changed them, we validate a tiny module that
+//! uses each proposal with the built engine.
+
+use wasm_encoder::{Module, SectionId, ValType};
+
+/// A Wasm proposal that Wizer depends on being enabled or disabled.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Proposal {
+    MultiMemory,
+    MultiValue,
+    ModuleLinking,
+    ReferenceTypes,
+    Simd,
+    Threads,
+    BulkMemory,
+    Memory64,
+}
+
+impl Proposal {
+    fn name(self) -> &'static str {
+        match self {
+            Proposal::MultiMemory => "multi-memory",
+            Proposal::MultiValue => "multi-value",
+            Proposal::ModuleLinking => "module linking",
+            Proposal::ReferenceTypes => "reference types",
+            Proposal::Simd => "SIMD",
+            Proposal::Threads => "threads",
+            Proposal::BulkMemory => "bulk memory",
+            Proposal::Memory64 => "memory64",
+        }
+    }
+
+    /// Enable or disable this proposal in the given config.
+    pub(crate) fn set(self, config: &mut wasmtime::Config, enable: bool) {
+        match self {
+            Proposal::MultiMemory => config.wasm_multi_memory(enable),
+            Proposal::MultiValue => config.wasm_multi_value(enable),
+            Proposal::ModuleLinking => config.wasm_module_linking(enable),
+            Proposal::ReferenceTypes => config.wasm_reference_types(enable),
+            Proposal::Simd => config.wasm_simd(enable),
+            Proposal::Threads => config.wasm_threads(enable),
+            Proposal::BulkMemory => config.wasm_bulk_memory(enable),
+            Proposal::Memory64 => config.wasm_memory64(enable),
+        };
+    }
+
+    /// A module that is only valid when this proposal is enabled.
+    fn probe(self) -> Vec<u8> {
+        let mut module = Module::new();
+        match self {
+            Proposal::MultiMemory => {
+                let mut memories = wasm_encoder::MemorySection::new();
+                for _ in 0..2 {
+                    memories.memory(wasm_encoder::MemoryType {
+                        minimum: 0,
+                        maximum: None,
+                        memory64: false,
+                    });
+                }
+                module.section(&memories);
+            }
+            Proposal::MultiValue => {
+                let mut types = wasm_encoder::TypeSection::new();
+                types.function(vec![], vec![ValType::I32, ValType::I32]);
+                module.section(&types);
+            }
+            Proposal::ModuleLinking => {
+                let mut modules = wasm_encoder::ModuleSection::new();
+                modules.module(&Module::new());
+                module.section(&modules);
+            }
+            Proposal::ReferenceTypes => {
+                let mut types = wasm_encoder::TypeSection::new();
+                types.function(vec![ValType::ExternRef], vec![]);
+                module.section(&types);
+            }
+            Proposal::Simd => {
+                let mut types = wasm_encoder::TypeSection::new();
+                types.function(vec![ValType::V128], vec![]);
+                module.section(&types);
+            }
+            Proposal::Threads => {
+                // `wasm-encoder` can't encode shared memories, so this is one
+                // shared memory with a minimum and maximum of one page.
+                module.section(&wasm_encoder::RawSection {
+                    id: SectionId::Memory as u8,
+                    data: &[1, 0x03, 1, 1],
+                });
+            }
+            Proposal::BulkMemory => {
+                let mut data = wasm_encoder::DataSection::new();
+                data.passive(vec![]);
+                module.section(&data);
+            }
+            Proposal::Memory64 => {
+                let mut memories = wasm_encoder::MemorySection::new();
+                memories.memory(wasm_encoder::MemoryType {
+                    minimum: 0,
+                    maximum: None,
+                    memory64: true,
+                });
+                module.section(&memories);
+            }
+        }
+        module.finish()
+    }
+}
+
+/// Check that the given engine enables or disables each of the given
+/// proposals as given.
+///
+/// Returns the names of the proposals that it doesn't.
+pub(crate) fn mismatched(
+    engine: &wasmtime::Engine,
+    proposals: &[(Proposal, bool)],
+) -> Vec<&'static str> {
+    proposals
+        .iter()
+        .filter(|(proposal, enable)| {
+            wasmtime::Module::validate(engine, &proposal.probe()).is_ok() != *enable
+        })
+        .map(|(proposal, _)| proposal.name())
+        .collect()
+}
@@ -0,0 +1,400 @@
+//! The `wizer` custom section, which records how a module was wizened.
+//!
+//! The section's contents are UTF-8 text, with one `key: value` pair per line.
+//! Backslashes, newlines, and carriage returns in values are escaped as `\\`,
+//! `\n`, and `\r`. Keys that hold lists are repeated once per list element.
+//! Readers ignore keys that they don't recognize.
+
+use crate::snapshot::Snapshot;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
+
+/// The name of the custom section that holds the provenance.
+pub(crate) const SECTION_NAME: &str = "wizer";
+
+/// A record of how a Wasm module was wizened, stored in its `wizer` custom
+/// section.
+///
+/// See [`Wizer::provenance`][crate::Wizer::provenance].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// The version of Wizer that wizened the module.
+    pub wizer_version: String,
+
+    /// The initialization functions that were called, in the order they were
+    /// called.
+    pub init_funcs: Vec<String>,
+
+    /// The arguments that were passed to the initialization function.
+    pub init_args: Vec<String>,
+
+    /// The function renames that were applied, as `dst=src` specifications.
+    pub func_renames: Vec<String>,
+
+    /// The function imports that were stubbed during initialization, as
+    /// `module::name[=values]` specs.
+    pub stub_imports: Vec<String>,
+
+    /// Whether WASI imports were allowed during initialization.
+    pub allow_wasi: bool,
+
+    /// Whether stdio was inherited during initialization.
+    pub inherit_stdio: bool,
+
+    /// Whether environment variables were inherited during initialization.
+    pub inherit_env: bool,
+
+    /// The WASI command-line arguments that initialization was given.
+    pub wasi_args: Vec<String>,
+
+    /// The environment variables that were explicitly set during
+    /// initialization, as `KEY=VALUE`.
+    pub wasi_envs: Vec<String>,
+
+    /// The directories that were preopened during initialization.
+    pub dirs: Vec<PathBuf>,
+
+    /// The directories that were preopened under a different path during
+    /// initialization, as `GUEST::HOST` mappings.
+    pub map_dirs: Vec<String>,
+
+    /// Whether the preopened directories were read-only.
+    pub read_only_dirs: bool,
+
+    /// The tar archives whose contents made up the in-memory file system
+    /// during initialization.
+    pub vfs_tars: Vec<PathBuf>,
+
+    /// The hex-encoded SHA-256 hash of the in-memory file system's paths and
+    /// contents, if there was one.
+    pub vfs_sha256: Option<String>,
+
+    /// The ranges of memory that were zeroed before the snapshot was taken:
+    /// the excluded memory ranges, and the shadow stack, if it was zeroed.
+    pub zeroed_ranges: Vec<Range<u64>>,
+
+    /// The number of zero bytes that merging and aligning data segments added
+    /// to the snapshot's data segments.
+    pub zero_padding: u64,
+
+    /// The hex-encoded SHA-256 hash of the input module.
+    pub input_sha256: String,
+
+    /// The hex-encoded SHA-256 hash of the snapshot of the module's state after
+    /// initialization.
+    pub snapshot_sha256: String,
+}
+
+impl Provenance {
+    /// Read the provenance from the given Wasm module's `wizer` custom section.
+    ///
+    /// Returns `Ok(None)` if the module doesn't have a `wizer` custom section.
+    pub fn from_wasm(wasm: &[u8]) -> anyhow::Result<Option<Provenance>> {
+        for section in top_level_sections(wasm)? {
+            if section.custom_name() == Some(SECTION_NAME) {
+                let (_, data) = section.custom_name_and_data().unwrap();
+                return Provenance::parse(data).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn parse(data: &[u8]) -> anyhow::Result<Provenance> {
+        let text = std::str::from_utf8(data)
+            .map_err(|_| anyhow::anyhow!("the `wizer` custom section is not valid UTF-8"))?;
+
+        let mut provenance = Provenance::default();
+        for line in text.lines() {
+            let colon = line.find(": ").ok_or_else(|| {
+                anyhow::anyhow!("invalid line in the `wizer` custom section: {}", line)
+            })?;
+            let key = &line[..colon];
+            let value = unescape(&line[colon + 2..]).ok_or_else(|| {
+                anyhow::anyhow!("invalid `{}` in the `wizer` custom section", key)
+            })?;
+            let value = &*value;
+            let parse_bool = |value: &str| -> anyhow::Result<bool> {
+                value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("invalid `{}` in the `wizer` custom section", key))
+            };
+            match key {
+                "wizer-version" => provenance.wizer_version = value.to_string(),
+                "init-func" => provenance.init_funcs.push(value.to_string()),
+                "init-arg" => provenance.init_args.push(value.to_string()),
+                "rename-func" => provenance.func_renames.push(value.to_string()),
+                "stub-import" => provenance.stub_imports.push(value.to_string()),
+                "allow-wasi" => provenance.allow_wasi = parse_bool(value)?,
+                "inherit-stdio" => provenance.inherit_stdio = parse_bool(value)?,
+                "inherit-env" => provenance.inherit_env = parse_bool(value)?,
+                "wasi-arg" => provenance.wasi_args.push(value.to_string()),
+                "wasi-env" => provenance.wasi_envs.push(value.to_string()),
+                "dir" => provenance.dirs.push(value.into()),
+                "mapdir" => provenance.map_dirs.push(value.to_string()),
+                "read-only-dirs" => provenance.read_only_dirs = parse_bool(value)?,
+                "vfs-tar" => provenance.vfs_tars.push(value.into()),
+                "vfs-sha256" => provenance.vfs_sha256 = Some(value.to_string()),
+                "zeroed-range" => {
+                    provenance
+                        .zeroed_ranges
+                        .push(parse_range(value).ok_or_else(|| {
+                            anyhow::anyhow!("invalid `{}` in the `wizer` custom section", key)
+                        })?)
+                }
+                "zero-padding" => {
+                    provenance.zero_padding = value.parse().map_err(|_| {
+                        anyhow::anyhow!("invalid `{}` in the `wizer` custom section", key)
+                    })?
+                }
+                "input-sha256" => provenance.input_sha256 = value.to_string(),
+                "snapshot-sha256" => provenance.snapshot_sha256 = value.to_string(),
+                _ => continue,
+            }
+        }
+        Ok(provenance)
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut line = |key: &str, value: &dyn fmt::Display| {
+            writeln!(f, "{}: {}", key, escape(&value.to_string()))
+        };
+        line("wizer-version", &self.wizer_version)?;
+        for init_func in &self.init_funcs {
+            line("init-func", init_func)?;
+        }
+        for arg in &self.init_args {
+            line("init-arg", arg)?;
+        }
+        for rename in &self.func_renames {
+            line("rename-func", rename)?;
+        }
+        for stub in &self.stub_imports {
+            line("stub-import", stub)?;
+        }
+        line("allow-wasi", &self.allow_wasi)?;
+        line("inherit-stdio", &self.inherit_stdio)?;
+        line("inherit-env", &self.inherit_env)?;
+        for arg in &self.wasi_args {
+            line("wasi-arg", arg)?;
+        }
+        for env in &self.wasi_envs {
+            line("wasi-env", env)?;
+        }
+        for dir in &self.dirs {
+            line("dir", &dir.display())?;
+        }
+        for map_dir in &self.map_dirs {
+            line("mapdir", map_dir)?;
+        }
+        line("read-only-dirs", &self.read_only_dirs)?;
+        for tar in &self.vfs_tars {
+            line("vfs-tar", &tar.display())?;
+        }
+        if let Some(vfs_sha256) = &self.vfs_sha256 {
+            line("vfs-sha256", vfs_sha256)?;
+        }
+        for range in &self.zeroed_ranges {
+            line(
+                "zeroed-range",
+                &format_args!("{:#x}..{:#x}", range.start, range.end),
+            )?;
+        }
+        line("zero-padding", &self.zero_padding)?;
+        line("input-sha256", &self.input_sha256)?;
+        line("snapshot-sha256", &self.snapshot_sha256)
+    }
+}
+
+/// Escape a value so that it fits on one line.
+fn escape(value: &str) -> Cow<'_, str> {
+    if !value.contains(['\\', '\n', '\r']) {
+        return Cow::Borrowed(value);
+    }
+    let mut escaped = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Undo `escape`, or return `None` if the value has an invalid escape.
+fn unescape(value: &str) -> Option<Cow<'_, str>> {
+    if !value.contains('\\') {
+        return Some(Cow::Borrowed(value));
+    }
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(Cow::Owned(unescaped))
+}
+
+/// Parse a `0xSTART..0xEND` memory range.
+fn parse_range(value: &str) -> Option<Range<u64>> {
+    let parse = |x: &str| u64::from_str_radix(x.strip_prefix("0x")?, 16).ok();
+    let dots = value.find("..")?;
+    Some(parse(&value[..dots])?..parse(&value[dots + 2..])?)
+}
+
+/// Hash the given bytes with SHA-256 and hex-encode the result.
+pub(crate) fn sha256_hex(bytes: &[u8]) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+/// Hash the snapshot with SHA-256 and hex-encode the result.
+pub(crate) fn snapshot_sha256_hex(store: &crate::Store, snapshot: &Snapshot) -> String {
+    fn hash_snapshot(hasher: &mut Sha256, store: &crate::Store, snapshot: &Snapshot) {
+        hasher.update((snapshot.globals.len() as u64).to_le_bytes());
+        for global in &snapshot.globals {
+            match global {
+                wasmtime::Val::I32(x) => hasher.update(i64::from(*x).to_le_bytes()),
+                wasmtime::Val::I64(x) => hasher.update(x.to_le_bytes()),
+                wasmtime::Val::F32(x) => hasher.update(u64::from(*x).to_le_bytes()),
+                wasmtime::Val::F64(x) => hasher.update(x.to_le_bytes()),
+                _ => unreachable!(),
+            }
+        }
+
+        hasher.update((snapshot.memory_mins.len() as u64).to_le_bytes());
+        for min in &snapshot.memory_mins {
+            hasher.update(min.to_le_bytes());
+        }
+
+        hasher.update((snapshot.data_segments.len() as u64).to_le_bytes());
+        for seg in &snapshot.data_segments {
+            hasher.update(seg.memory_index.to_le_bytes());
+            hasher.update(seg.offset.to_le_bytes());
+            hasher.update(seg.len.to_le_bytes());
+            hasher.update(seg.data(store));
+        }
+
+        hasher.update((snapshot.instantiations.len() as u64).to_le_bytes());
+        for instantiation in &snapshot.instantiations {
+            hash_snapshot(hasher, store, instantiation);
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    hash_snapshot(&mut hasher, store, snapshot);
+    hex(&hasher.finalize())
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Encode the given provenance as a `wizer` custom section, including its id
+/// and size.
+pub(crate) fn encode_section(provenance: &Provenance) -> Vec<u8> {
+    let mut bytes = vec![wasm_encoder::SectionId::Custom as u8];
+    let contents = provenance.to_string();
+    wasm_encoder::Section::encode(
+        &wasm_encoder::CustomSection {
+            name: SECTION_NAME,
+            data: contents.as_bytes(),
+        },
+        &mut bytes,
+    );
+    bytes
+}
+
+/// Replace the given module's `wizer` custom section, if any, with the given
+/// provenance, or just remove it if there is no provenance.
+///
+/// The new section is placed before the name section, since some tools expect
+/// the name section to come last.
+pub(crate) fn set_provenance(
+    wasm: &[u8],
+    provenance: Option<&Provenance>,
+) -> anyhow::Result<Vec<u8>> {
+    let section = provenance.map(encode_section);
+    let mut section = section.as_deref();
+
+    let mut module = wasm[..8].to_vec();
+    for s in top_level_sections(wasm)? {
+        match s.custom_name() {
+            Some(SECTION_NAME) => continue,
+            Some("name") => {
+                if let Some(section) = section.take() {
+                    module.extend_from_slice(section);
+                }
+            }
+            _ => {}
+        }
+        module.extend_from_slice(s.raw);
+    }
+    if let Some(section) = section {
+        module.extend_from_slice(section);
+    }
+    Ok(module)
+}
+
+/// A top-level section of a Wasm module.
+struct RawSection<'a> {
+    id: u8,
+
+    /// The section's contents.
+    data: &'a [u8],
+
+    /// The whole section, including its id and size.
+    raw: &'a [u8],
+}
+
+impl<'a> RawSection<'a> {
+    fn custom_name_and_data(&self) -> Option<(&'a str, &'a [u8])> {
+        if self.id != wasm_encoder::SectionId::Custom as u8 {
+            return None;
+        }
+        let mut reader = wasmparser::BinaryReader::new(self.data);
+        let name = reader.read_string().ok()?;
+        Some((name, &self.data[reader.original_position()..]))
+    }
+
+    fn custom_name(&self) -> Option<&'a str> {
+        self.custom_name_and_data().map(|(name, _)| name)
+    }
+}
+
+/// Split a Wasm module into its top-level sections, without parsing their
+/// contents.
+fn top_level_sections(wasm: &[u8]) -> anyhow::Result<Vec<RawSection<'_>>> {
+    let mut reader = wasmparser::BinaryReader::new(wasm);
+    if reader.read_bytes(4)? != b"\0asm" {
+        anyhow::bail!("not a Wasm module");
+    }
+    reader.read_bytes(4)?;
+
+    let mut sections = vec![];
+    while !reader.eof() {
+        let start = reader.original_position();
+        let id = reader.read_u8()? as u8;
+        let size = reader.read_var_u32()? as usize;
+        let data = reader.read_bytes(size)?;
+        sections.push(RawSection {
+            id,
+            data,
+            raw: &wasm[start..reader.original_position()],
+        });
+    }
+    Ok(sections)
+}
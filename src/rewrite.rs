@@ -7,64 +7,98 @@ use crate::{
         types_interner::{EntityType, Type},
         Module, ModuleContext,
     },
-    snapshot::Snapshot,
+    provenance::{self, Provenance},
+    snapshot::{DataSegment, Snapshot},
     translate, FuncRenames, Wizer,
 };
 use renumbering::Renumbering;
-use std::{convert::TryFrom, iter};
-use wasm_encoder::SectionId;
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+    iter,
+};
+use wasm_encoder::{encoders, SectionId};
+
+/// What the rewrite pass does besides encoding the snapshot.
+pub(crate) struct RewriteOptions<'a> {
+    /// The function renames to perform.
+    pub renames: &'a FuncRenames,
+
+    /// Whether the input module's `_initialize` function was called, and
+    /// should be removed like the initialization function.
+    pub has_wasi_initialize: bool,
+
+    /// The provenance to write in a `wizer` custom section, if any.
+    pub provenance: Option<&'a Provenance>,
+}
 
 impl Wizer {
     /// Given the initialized snapshot, rewrite the Wasm so that it is already
-    /// initialized.
+    /// initialized, and write it to `output`.
     ///
+    /// Any `wizer` custom section in the input is dropped. Without module
+    /// linking, sections are written as soon as they are encoded, data
+    /// segments are written straight from the instance's memories, and the
+    /// given provenance is written in a new `wizer` custom section. With module
+    /// linking, the whole module is encoded in memory first, and the
+    /// provenance must be `None`.
     pub(crate) fn rewrite(
         &self,
         cx: &mut ModuleContext<'_>,
         store: &crate::Store,
         snapshot: &Snapshot,
-        renames: &FuncRenames,
-        has_wasi_initialize: bool,
-    ) -> Vec<u8> {
+        options: &RewriteOptions<'_>,
+        output: &mut (impl Write + ?Sized),
+    ) -> io::Result<()> {
         log::debug!("Rewriting input Wasm to pre-initialized state");
 
         if cx.uses_module_linking() {
-            self.rewrite_with_module_linking(cx, store, snapshot, renames, has_wasi_initialize)
+            assert!(options.provenance.is_none());
+            output.write_all(&self.rewrite_with_module_linking(
+                cx,
+                store,
+                snapshot,
+                options.renames,
+                options.has_wasi_initialize,
+            ))
         } else {
-            self.rewrite_without_module_linking(cx, store, snapshot, renames, has_wasi_initialize)
+            self.rewrite_without_module_linking(
+                cx,
+                store,
+                snapshot,
+                options,
+                &mut ModuleWriter::new(output)?,
+            )
         }
     }
 
     /// Rewrite a root Wasm module that has no children and doesn't use module
     /// linking at all.
-    fn rewrite_without_module_linking(
+    fn rewrite_without_module_linking<W: Write + ?Sized>(
         &self,
         cx: &ModuleContext<'_>,
         store: &crate::Store,
         snapshot: &Snapshot,
-        renames: &FuncRenames,
-        has_wasi_initialize: bool,
-    ) -> Vec<u8> {
+        options: &RewriteOptions<'_>,
+        encoder: &mut ModuleWriter<'_, W>,
+    ) -> io::Result<()> {
         assert!(snapshot.instantiations.is_empty());
+        let RewriteOptions {
+            renames,
+            has_wasi_initialize,
+            provenance,
+        } = *options;
 
-        let mut encoder = wasm_encoder::Module::new();
         let module = cx.root();
 
         // Encode the initialized data segments from the snapshot rather
         // than the original, uninitialized data segments.
-        let mut data_section = if snapshot.data_segments.is_empty() {
+        let mut data_segments = if snapshot.data_segments.is_empty() {
             None
         } else {
-            let mut data_section = wasm_encoder::DataSection::new();
-            for seg in &snapshot.data_segments {
-                data_section.active(
-                    seg.memory_index,
-                    wasm_encoder::Instruction::I32Const(seg.offset as i32),
-                    seg.data(store).iter().copied(),
-                );
-            }
-            Some(data_section)
+            Some(&snapshot.data_segments[..])
         };
+        let mut provenance = provenance.map(provenance::encode_section);
 
         // There are multiple places were we potentially need to check whether
         // we've added the data section already and if we haven't yet, then do
@@ -72,20 +106,31 @@ impl Wizer {
         // all, and so we have to potentially add it at the end of iterating
         // over the original sections. This closure encapsulates all that
         // add-it-if-we-haven't-already logic in one place.
-        let mut add_data_section = |module: &mut wasm_encoder::Module| {
-            if let Some(data_section) = data_section.take() {
-                module.section(&data_section);
+        let mut add_data_section = |encoder: &mut ModuleWriter<'_, W>| {
+            if let Some(data_segments) = data_segments.take() {
+                encoder.data_section(store, data_segments)?;
             }
+            io::Result::Ok(())
+        };
+
+        // Likewise for the provenance, which goes after the data section.
+        let mut add_provenance = |encoder: &mut ModuleWriter<'_, W>| match provenance.take() {
+            Some(section) => encoder.raw(&section),
+            None => Ok(()),
         };
 
         for section in module.raw_sections(cx) {
             match section {
+                // Drop the provenance of previous wizenings.
+                s if custom_section_name(s) == Some(provenance::SECTION_NAME) => continue,
+
                 // Some tools expect the name custom section to come last, even
                 // though custom sections are allowed in any order. Therefore,
                 // make sure we've added our data section by now.
                 s if is_name_section(s) => {
-                    add_data_section(&mut encoder);
-                    encoder.section(s);
+                    add_data_section(encoder)?;
+                    add_provenance(encoder)?;
+                    encoder.section(s)?;
                 }
 
                 // For the memory section, we update the minimum size of each
@@ -102,7 +147,7 @@ impl Wizer {
                         mem.minimum = new_min;
                         memories.memory(mem);
                     }
-                    encoder.section(&memories);
+                    encoder.section(&memories)?;
                 }
 
                 // Encode the initialized global values from the snapshot,
@@ -128,7 +173,7 @@ impl Wizer {
                             },
                         );
                     }
-                    encoder.section(&globals);
+                    encoder.section(&globals)?;
                 }
 
                 // Remove exports for the wizer initialization
@@ -159,7 +204,7 @@ impl Wizer {
                         let export = translate::export(export.kind, export.index);
                         exports.export(field, export);
                     }
-                    encoder.section(&exports);
+                    encoder.section(&exports)?;
                 }
 
                 // Skip the `start` function -- it's already been run!
@@ -170,7 +215,7 @@ impl Wizer {
                 s if s.id == SectionId::Data.into() => {
                     // TODO: supporting bulk memory will require copying over
                     // any passive and declared segments.
-                    add_data_section(&mut encoder);
+                    add_data_section(encoder)?;
                 }
 
                 s if s.id == SectionId::Module.into() => unreachable!(),
@@ -178,14 +223,15 @@ impl Wizer {
                 s if s.id == SectionId::Alias.into() => unreachable!(),
 
                 s => {
-                    encoder.section(s);
+                    encoder.section(s)?;
                 }
             }
         }
 
-        // Make sure that we've added our data section to the module.
-        add_data_section(&mut encoder);
-        encoder.finish()
+        // Make sure that we've added our data section and provenance to the
+        // module.
+        add_data_section(encoder)?;
+        add_provenance(encoder)
     }
 
     /// Rewrite a module linking bundle.
@@ -352,7 +398,14 @@ impl Wizer {
 
         let (code_modules, num_code_modules) = rewrite_code_modules(cx);
 
-        let root_state_module = rewrite_state_module(cx, store, cx.root(), &snapshot, 0);
+        let root_state_module = rewrite_state_module(
+            cx,
+            store,
+            cx.root(),
+            &snapshot,
+            0,
+            self.dedupe_data_segments,
+        );
         let mut modules = wasm_encoder::ModuleSection::new();
         modules.module(&root_state_module);
         let root_state_module_index = num_code_modules;
@@ -532,9 +585,81 @@ fn umbrella_type_section(cx: &ModuleContext<'_>) -> wasm_encoder::TypeSection {
 }
 
 fn is_name_section(s: &wasm_encoder::RawSection) -> bool {
-    s.id == SectionId::Custom.into() && {
-        let mut reader = wasmparser::BinaryReader::new(s.data);
-        matches!(reader.read_string(), Ok("name"))
+    custom_section_name(s) == Some("name")
+}
+
+fn custom_section_name<'a>(s: &wasm_encoder::RawSection<'a>) -> Option<&'a str> {
+    if s.id != SectionId::Custom.into() {
+        return None;
+    }
+    wasmparser::BinaryReader::new(s.data).read_string().ok()
+}
+
+/// Writes an encoded Wasm module's sections to a `Write` as they are encoded,
+/// rather than building the whole module in memory like
+/// `wasm_encoder::Module`, and produces the same bytes.
+struct ModuleWriter<'a, W: ?Sized> {
+    output: &'a mut W,
+}
+
+impl<'a, W: Write + ?Sized> ModuleWriter<'a, W> {
+    fn new(output: &'a mut W) -> io::Result<Self> {
+        output.write_all(wasm_encoder::Module::new().as_slice())?;
+        Ok(ModuleWriter { output })
+    }
+
+    fn section(&mut self, section: &impl wasm_encoder::Section) -> io::Result<()> {
+        let mut bytes = vec![section.id()];
+        section.encode(&mut bytes);
+        self.output.write_all(&bytes)
+    }
+
+    /// Write an already-encoded section, including its id and size.
+    fn raw(&mut self, section: &[u8]) -> io::Result<()> {
+        self.output.write_all(section)
+    }
+
+    /// Write a data section with the given active data segments, copying
+    /// their data straight from their memories.
+    fn data_section(&mut self, store: &crate::Store, segments: &[DataSegment]) -> io::Result<()> {
+        // The segments' headers are small, so encode them up front to get the
+        // section's size.
+        let headers: Vec<Vec<u8>> = segments
+            .iter()
+            .map(|seg| {
+                let mut header = vec![];
+                if seg.memory_index == 0 {
+                    header.push(0x00);
+                } else {
+                    header.push(0x02);
+                    header.extend(encoders::u32(seg.memory_index));
+                }
+                // `i32.const <offset>` followed by `end`.
+                header.push(0x41);
+                header.extend(encoders::s32(seg.offset as i32));
+                header.push(0x0b);
+                header.extend(encoders::u32(seg.len));
+                header
+            })
+            .collect();
+
+        let count: Vec<u8> = encoders::u32(u32::try_from(segments.len()).unwrap()).collect();
+        let size = count.len()
+            + headers
+                .iter()
+                .zip(segments)
+                .map(|(header, seg)| header.len() + seg.len as usize)
+                .sum::<usize>();
+
+        let mut prefix = vec![SectionId::Data.into()];
+        prefix.extend(encoders::u32(u32::try_from(size).unwrap()));
+        prefix.extend(count);
+        self.output.write_all(&prefix)?;
+        for (header, seg) in headers.iter().zip(segments) {
+            self.output.write_all(header)?;
+            self.output.write_all(seg.data(store))?;
+        }
+        Ok(())
     }
 }
 
@@ -838,12 +963,17 @@ fn make_state_import(
 
 /// Create the state module the given module instantiation and recursively do
 /// the same for its nested instantiations.
+///
+/// When `dedupe` is enabled, nested instantiations whose state modules are
+/// identical share the first such state module, rather than each defining its
+/// own copy.
 fn rewrite_state_module(
     cx: &ModuleContext<'_>,
     store: &crate::Store,
     info: Module,
     snapshot: &Snapshot,
     depth: u32,
+    dedupe: bool,
 ) -> wasm_encoder::Module {
     let mut state_module = wasm_encoder::Module::new();
     let mut exports = wasm_encoder::ExportSection::new();
@@ -868,6 +998,9 @@ fn rewrite_state_module(
         //
         // That is, the `i`th nested instantiation's code module is the `i`th
         // module in the index space, and its state module is at index `N+i`.
+        // Except when deduplicating, where an instantiation whose state module
+        // is identical to an earlier one's uses that earlier state module, and
+        // doesn't define its own.
         //
         // We define all the code module aliases up front. Nested instantiations
         // may require aliasing instance exports from earlier instantiations, so
@@ -898,6 +1031,11 @@ fn rewrite_state_module(
         let aliased_types = make_aliased_type_section(cx, info, depth);
         state_module.section(&aliased_types);
 
+        // The state modules defined so far, and their encodings if we are
+        // deduplicating them.
+        let mut num_state_modules = 0;
+        let mut state_module_bytes: Vec<Vec<u8>> = vec![];
+
         let mut instance_import_counts = info.instance_import_counts(cx).iter().copied();
         let mut aliases = info.aliases(cx).iter();
         let mut instantiations = info.instantiations(cx).values().enumerate();
@@ -973,15 +1111,37 @@ fn rewrite_state_module(
                         .by_ref()
                         .take(usize::try_from(count).unwrap())
                     {
-                        // Define the state module for this instantiation.
+                        // Define the state module for this instantiation,
+                        // unless an identical one is already defined.
                         let state_module = rewrite_state_module(
                             cx,
                             store,
                             *module,
                             &snapshot.instantiations[i],
                             depth + 1,
+                            dedupe,
                         );
-                        module_section.module(&state_module);
+                        let existing = state_module_bytes
+                            .iter()
+                            .position(|bytes| bytes.as_slice() == state_module.as_slice());
+                        let state_module_index = match existing {
+                            Some(index) => {
+                                log::debug!(
+                                    "Sharing state module {} for instantiation {}",
+                                    index,
+                                    i
+                                );
+                                index
+                            }
+                            None => {
+                                module_section.module(&state_module);
+                                if dedupe {
+                                    state_module_bytes.push(state_module.finish());
+                                }
+                                num_state_modules += 1;
+                                num_state_modules - 1
+                            }
+                        };
 
                         // Instantiate the state module to create the state
                         // instance.
@@ -997,7 +1157,8 @@ fn rewrite_state_module(
                             })
                             .collect();
                         instance_section.instantiate(
-                            u32::try_from(snapshot.instantiations.len() + i).unwrap(),
+                            u32::try_from(snapshot.instantiations.len() + state_module_index)
+                                .unwrap(),
                             args,
                         );
                         let state_instance_index = instance_renumbering.define_new();
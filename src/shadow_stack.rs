@@ -0,0 +1,113 @@
+//! Finding the shadow stack that LLVM-based toolchains keep in linear memory.
+//!
+//! Clang and Rust keep a stack pointer in a mutable `i32` global, named
+//! `__stack_pointer`, and the stack grows down from that global's initial value
+//! towards lower addresses. Once the initialization function returns, the stack
+//! pointer is back at its initial value, and everything below it is garbage
+//! left behind by dead stack frames.
+
+use crate::{info::ModuleContext, Store, Wizer};
+use std::ops::Range;
+
+/// The stack pointer global and its value before initialization.
+pub(crate) struct StackPointer {
+    global: wasmtime::Global,
+    initial: u32,
+}
+
+impl Wizer {
+    /// Find the stack pointer global, either the export that the user named or
+    /// the global that the name section calls `__stack_pointer`.
+    pub(crate) fn find_stack_pointer(
+        &self,
+        cx: &ModuleContext<'_>,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+    ) -> anyhow::Result<StackPointer> {
+        let global = match &self.stack_pointer_export {
+            Some(name) => instance.get_global(&mut *store, name).ok_or_else(|| {
+                anyhow::anyhow!("the Wasm module does not have a `{}` global export", name)
+            })?,
+            None => find_global(cx, store, instance, "__stack_pointer").ok_or_else(|| {
+                anyhow::anyhow!(
+                    "could not find the `__stack_pointer` global in the name section; \
+                     use `--stack-pointer-export` to name it instead"
+                )
+            })?,
+        };
+        let initial = match global.get(&mut *store) {
+            wasmtime::Val::I32(x) => x as u32,
+            _ => anyhow::bail!("the stack pointer global is not an `i32`"),
+        };
+        log::debug!("Found the stack pointer with initial value {:#x}", initial);
+        Ok(StackPointer { global, initial })
+    }
+
+    /// Check that initialization restored the stack pointer, and return the
+    /// range of memory that the shadow stack occupies.
+    pub(crate) fn shadow_stack(
+        &self,
+        cx: &ModuleContext<'_>,
+        store: &mut Store,
+        instance: &wasmtime::Instance,
+        stack_pointer: StackPointer,
+    ) -> anyhow::Result<Range<u64>> {
+        match stack_pointer.global.get(&mut *store) {
+            wasmtime::Val::I32(x) if x as u32 == stack_pointer.initial => {}
+            val => anyhow::bail!(
+                "the stack pointer is {:?} after initialization, but it was {:#x} before",
+                val,
+                stack_pointer.initial
+            ),
+        }
+
+        let end = u64::from(stack_pointer.initial);
+        let start = match self.stack_size {
+            Some(size) => end.checked_sub(u64::from(size)).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "the stack size {:#x} is larger than the stack pointer {:#x}",
+                    size,
+                    end
+                )
+            })?,
+            None => {
+                let data_end = find_global(cx, store, instance, "__data_end")
+                    .map(|g| g.get(&mut *store))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "could not determine where the shadow stack begins; \
+                             use `--stack-size` to give its size"
+                        )
+                    })?;
+                match data_end {
+                    // Without `--stack-first`, the stack is placed after the
+                    // static data.
+                    wasmtime::Val::I32(x) if u64::from(x as u32) <= end => u64::from(x as u32),
+                    // With `--stack-first`, the stack is placed before the
+                    // static data, at the start of memory.
+                    wasmtime::Val::I32(_) => 0,
+                    _ => anyhow::bail!("the `__data_end` global is not an `i32`"),
+                }
+            }
+        };
+        Ok(start..end)
+    }
+}
+
+/// Find a global by its export name, or by the name that the root module's name
+/// section gives it.
+fn find_global(
+    cx: &ModuleContext<'_>,
+    store: &mut Store,
+    instance: &wasmtime::Instance,
+    name: &str,
+) -> Option<wasmtime::Global> {
+    if let Some(global) = instance.get_global(&mut *store, name) {
+        return Some(global);
+    }
+
+    let root = cx.root();
+    let index = root.global_index_by_name(cx, name)?;
+    let defined_index = index.checked_sub(root.defined_globals_index(cx)?)?;
+    instance.get_global(&mut *store, &format!("__wizer_global_{}", defined_index))
+}
@@ -5,7 +5,49 @@ use wasmtime::{AsContext, AsContextMut};
 const WASM_PAGE_SIZE: u64 = 65_536;
 
 /// The maximum number of data segments that most engines support.
-const MAX_DATA_SEGMENTS: usize = 100_000;
+pub(crate) const DEFAULT_MAX_DATA_SEGMENTS: usize = 100_000;
+
+/// The minimum overhead of defining a new active data segment: one for the
+/// memory index LEB, two for the memory offset init expression (one for the
+/// `i32.const` opcode and another for the constant immediate LEB), and finally
+/// one for the data length LEB.
+pub(crate) const MIN_ACTIVE_SEGMENT_OVERHEAD: u32 = 4;
+
+/// Options that control how an instance's state is snapshotted.
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotOptions {
+    /// The maximum number of data segments to emit for this instance.
+    pub max_data_segments: usize,
+
+    /// Merge two segments of the same memory when the zero gap between them
+    /// is at most this many bytes.
+    pub merge_gap: u32,
+
+    /// Emit a single data segment spanning all non-zero bytes of each memory.
+    pub single_segment_per_memory: bool,
+
+    /// Set each memory's minimum size to the page holding its last non-zero
+    /// byte, rather than its current size.
+    pub shrink_memory_mins: bool,
+
+    /// The end of the live heap in memory 0, as reported by the module. When
+    /// shrinking memory minimums, memory 0 always keeps the pages below this
+    /// address.
+    ///
+    /// This only applies to the instance it was read from, not to its nested
+    /// instantiations.
+    pub heap_end: Option<u64>,
+
+    /// Skip the Wasm pages that were never touched, rather than scanning them
+    /// for non-zero bytes. The memories must have been created by
+    /// `dirty_pages::memory_creator`.
+    pub skip_untouched_pages: bool,
+
+    /// Align each data segment's start and end to a multiple of this many
+    /// bytes, which must be a power of two no larger than a Wasm page, by
+    /// padding it out with zeros.
+    pub page_size: Option<u32>,
+}
 
 /// A "snapshot" of Wasm state from its default value after having been initialized.
 pub struct Snapshot {
@@ -18,6 +60,10 @@ pub struct Snapshot {
     /// Segments of non-zero memory.
     pub data_segments: Vec<DataSegment>,
 
+    /// How many zero bytes were included in `data_segments` because of
+    /// merging segments together or aligning them to pages.
+    pub zero_padding: u64,
+
     /// Snapshots for each nested instantiation.
     pub instantiations: Vec<Snapshot>,
 }
@@ -76,21 +122,44 @@ impl DataSegment {
 /// defaults.
 //
 // TODO: when we support reference types, we will have to snapshot tables.
-pub fn snapshot(ctx: &mut impl AsContextMut, instance: &wasmtime::Instance) -> Snapshot {
+pub fn snapshot(
+    ctx: &mut impl AsContextMut,
+    instance: &wasmtime::Instance,
+    options: &SnapshotOptions,
+) -> Snapshot {
     log::debug!("Snapshotting the initialized state");
 
     let globals = snapshot_globals(&mut *ctx, instance);
-    let (memory_mins, data_segments) = snapshot_memories(&mut *ctx, instance);
-    let instantiations = snapshot_instantiations(&mut *ctx, instance);
+    let (memory_mins, data_segments, zero_padding) =
+        snapshot_memories(&mut *ctx, instance, options);
+    let nested_options = SnapshotOptions {
+        heap_end: None,
+        ..*options
+    };
+    let instantiations = snapshot_instantiations(&mut *ctx, instance, &nested_options);
 
     Snapshot {
         globals,
         memory_mins,
         data_segments,
+        zero_padding,
         instantiations,
     }
 }
 
+impl Snapshot {
+    /// The total number of zero bytes included in this snapshot's data
+    /// segments, and those of its nested instantiations, because of merging.
+    pub fn total_zero_padding(&self) -> u64 {
+        self.zero_padding
+            + self
+                .instantiations
+                .iter()
+                .map(|s| s.total_zero_padding())
+                .sum::<u64>()
+    }
+}
+
 /// Get the initialized values of all globals.
 fn snapshot_globals(
     ctx: &mut impl AsContextMut,
@@ -117,11 +186,13 @@ fn snapshot_globals(
 fn snapshot_memories(
     ctx: &mut impl AsContextMut,
     instance: &wasmtime::Instance,
-) -> (Vec<u64>, Vec<DataSegment>) {
+    options: &SnapshotOptions,
+) -> (Vec<u64>, Vec<DataSegment>, u64) {
     log::debug!("Snapshotting memories");
 
     // Find and record non-zero regions of memory (in parallel).
     let mut memory_mins = vec![];
+    let mut declared_mins = vec![];
     let mut data_segments = vec![];
     let mut memory_index = 0;
     loop {
@@ -131,14 +202,33 @@ fn snapshot_memories(
             Some(memory) => memory,
         };
         memory_mins.push(memory.size(&*ctx));
+        declared_mins.push(memory.ty(&*ctx).minimum());
 
         let num_wasm_pages = memory.size(&*ctx);
 
         let memory_data = memory.data(&*ctx);
 
+        let touched = if options.skip_untouched_pages {
+            crate::dirty_pages::touched_wasm_pages(memory_data)
+        } else {
+            None
+        };
+        if let Some(touched) = &touched {
+            log::debug!(
+                "Skipping {} untouched pages of memory {}",
+                touched.iter().filter(|t| !**t).count(),
+                memory_index
+            );
+        }
+
         // Consider each Wasm page in parallel. Create data segments for each
         // region of non-zero memory.
         data_segments.par_extend((0..num_wasm_pages).into_par_iter().flat_map(|i| {
+            if let Some(touched) = &touched {
+                if !touched[usize::try_from(i).unwrap()] {
+                    return vec![];
+                }
+            }
             let page_end = ((i + 1) * WASM_PAGE_SIZE) as usize;
             let mut start = (i * WASM_PAGE_SIZE) as usize;
             let mut segments = vec![];
@@ -169,22 +259,38 @@ fn snapshot_memories(
         memory_index += 1;
     }
 
+    if options.shrink_memory_mins {
+        shrink_memory_mins(&mut memory_mins, &declared_mins, &data_segments);
+        if let (Some(heap_end), Some(min)) = (options.heap_end, memory_mins.first_mut()) {
+            *min = std::cmp::max(*min, pages_for_bytes(heap_end));
+        }
+    }
+
     if data_segments.is_empty() {
-        return (memory_mins, data_segments);
+        return (memory_mins, data_segments, 0);
     }
 
     // Sort data segments to enforce determinism in the face of the
     // parallelism above.
     data_segments.sort_by_key(|s| (s.memory_index, s.offset));
 
+    let nonzero_bytes: u64 = data_segments.iter().map(|s| u64::from(s.len)).sum();
+
+    if let Some(page_size) = options.page_size {
+        data_segments = align_segments(&data_segments, page_size);
+        let aligned_bytes: u64 = data_segments.iter().map(|s| u64::from(s.len)).sum();
+        log::info!(
+            "Aligning data segments to {}-byte pages added {} bytes of zero padding",
+            page_size,
+            aligned_bytes - nonzero_bytes
+        );
+    }
+
     // Merge any contiguous segments (caused by spanning a Wasm page boundary,
     // and therefore created in separate logical threads above) or pages that
-    // are within four bytes of each other. Four because this is the minimum
-    // overhead of defining a new active data segment: one for the memory index
-    // LEB, two for the memory offset init expression (one for the `i32.const`
-    // opcode and another for the constant immediate LEB), and finally one for
-    // the data length LEB).
-    const MIN_ACTIVE_SEGMENT_OVERHEAD: u32 = 4;
+    // are within `merge_gap` bytes of each other. This defaults to
+    // `MIN_ACTIVE_SEGMENT_OVERHEAD`, because below that it is always more size
+    // efficient to merge than to start a new segment.
     let mut merged_data_segments = Vec::with_capacity(data_segments.len());
     merged_data_segments.push(data_segments[0]);
     for b in &data_segments[1..] {
@@ -197,9 +303,10 @@ fn snapshot_memories(
         }
 
         // Only merge segments if they are contiguous or if it is definitely
-        // more size efficient than leaving them apart.
+        // more size efficient than leaving them apart, unless we were asked to
+        // emit just one segment per memory.
         let gap = a.gap(b);
-        if gap > MIN_ACTIVE_SEGMENT_OVERHEAD {
+        if !options.single_segment_per_memory && gap > options.merge_gap {
             merged_data_segments.push(*b);
             continue;
         }
@@ -210,21 +317,85 @@ fn snapshot_memories(
         *a = merged;
     }
 
-    remove_excess_segments(&mut merged_data_segments);
+    remove_excess_segments(&mut merged_data_segments, options.max_data_segments);
+
+    let segment_bytes: u64 = merged_data_segments.iter().map(|s| u64::from(s.len)).sum();
+    let zero_padding = segment_bytes - nonzero_bytes;
+
+    (memory_mins, merged_data_segments, zero_padding)
+}
+
+/// Pad each of the given sorted data segments out to page boundaries, merging
+/// those that then touch or overlap.
+///
+/// Memories' sizes are multiples of the Wasm page size, and so of `page_size`,
+/// so padding never goes past the end of memory. Merging aligned segments, as
+/// is done afterwards, keeps them aligned.
+fn align_segments(data_segments: &[DataSegment], page_size: u32) -> Vec<DataSegment> {
+    debug_assert!(page_size.is_power_of_two() && u64::from(page_size) <= WASM_PAGE_SIZE);
+    let page_size = u64::from(page_size);
+    let mut aligned: Vec<DataSegment> = Vec::with_capacity(data_segments.len());
+    for seg in data_segments {
+        let start = u64::from(seg.offset) & !(page_size - 1);
+        let end = (u64::from(seg.offset) + u64::from(seg.len) + page_size - 1) & !(page_size - 1);
+        if let Some(last) = aligned.last_mut() {
+            let last_end = u64::from(last.offset) + u64::from(last.len);
+            if last.memory_index == seg.memory_index && start <= last_end {
+                last.len = u32::try_from(end - u64::from(last.offset)).unwrap();
+                continue;
+            }
+        }
+        aligned.push(DataSegment {
+            offset: u32::try_from(start).unwrap(),
+            len: u32::try_from(end - start).unwrap(),
+            ..*seg
+        });
+    }
+    aligned
+}
+
+/// Shrink each memory's minimum size down to the pages that hold non-zero
+/// bytes, without going below the minimum that the memory was declared with.
+///
+/// Any pages above the new minimum are all zeroes, so when the module grows its
+/// memory again at runtime, it gets back exactly the state we snapshotted.
+fn shrink_memory_mins(
+    memory_mins: &mut [u64],
+    declared_mins: &[u64],
+    data_segments: &[DataSegment],
+) {
+    let mut high_water_marks = vec![0; memory_mins.len()];
+    for seg in data_segments {
+        let end = u64::from(seg.offset) + u64::from(seg.len);
+        let mark = &mut high_water_marks[usize::try_from(seg.memory_index).unwrap()];
+        *mark = std::cmp::max(*mark, end);
+    }
+
+    for (i, (min, mark)) in memory_mins.iter_mut().zip(high_water_marks).enumerate() {
+        let pages = std::cmp::max(declared_mins[i], pages_for_bytes(mark));
+        debug_assert!(pages <= *min);
+        log::debug!("Shrinking memory {} from {} to {} pages", i, *min, pages);
+        *min = pages;
+    }
+}
 
-    (memory_mins, merged_data_segments)
+/// The number of Wasm pages needed to hold `bytes` bytes.
+// `u64::div_ceil` needs a newer Rust than the rest of the crate does.
+#[allow(clippy::manual_div_ceil)]
+pub(crate) fn pages_for_bytes(bytes: u64) -> u64 {
+    (bytes + WASM_PAGE_SIZE - 1) / WASM_PAGE_SIZE
 }
 
 /// Engines apply a limit on how many segments a module may contain, and Wizer
 /// can run afoul of it. When that happens, we need to merge data segments
 /// together until our number of data segments fits within the limit.
-fn remove_excess_segments(merged_data_segments: &mut Vec<DataSegment>) {
-    if merged_data_segments.len() < MAX_DATA_SEGMENTS {
+fn remove_excess_segments(merged_data_segments: &mut Vec<DataSegment>, max_data_segments: usize) {
+    if merged_data_segments.len() <= max_data_segments {
         return;
     }
 
     // We need to remove `excess` number of data segments.
-    let excess = merged_data_segments.len() - MAX_DATA_SEGMENTS;
+    let excess = merged_data_segments.len() - max_data_segments;
 
     #[derive(Clone, Copy, PartialEq, Eq)]
     struct GapIndex {
@@ -275,6 +446,7 @@ fn remove_excess_segments(merged_data_segments: &mut Vec<DataSegment>) {
 fn snapshot_instantiations(
     ctx: &mut impl AsContextMut,
     instance: &wasmtime::Instance,
+    options: &SnapshotOptions,
 ) -> Vec<Snapshot> {
     log::debug!("Snapshotting nested instantiations");
     let mut instantiations = vec![];
@@ -283,7 +455,7 @@ fn snapshot_instantiations(
         match instance.get_export(&mut *ctx, &name) {
             None => break,
             Some(wasmtime::Extern::Instance(instance)) => {
-                instantiations.push(snapshot(&mut *ctx, &instance));
+                instantiations.push(snapshot(&mut *ctx, &instance, options));
             }
             Some(_) => unreachable!(),
         }
@@ -0,0 +1,817 @@
+//! An in-memory, read-only file system that initialization can see through
+//! WASI.
+//!
+//! Its contents come from tar archives and from files given through the library
+//! API, so initialization never touches the host file system, and the exact
+//! contents can be hashed into the provenance.
+
+use sha2::{Digest, Sha256};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use wasi_common::dir::{ReaddirCursor, ReaddirEntity};
+use wasi_common::file::{Advice, FdFlags, FileType, Filestat, OFlags};
+use wasi_common::{Error, ErrorExt, ErrorKind, SystemTimeSpec, WasiDir, WasiFile};
+
+/// The guest path that the file system is preopened at.
+pub(crate) const GUEST_PATH: &str = "/";
+
+/// The size of a tar header and of the blocks that entries are padded to.
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// An in-memory file system.
+pub(crate) struct Vfs {
+    root: Arc<Node>,
+}
+
+enum Node {
+    File {
+        inode: u64,
+        contents: Arc<[u8]>,
+    },
+    Dir {
+        inode: u64,
+        entries: BTreeMap<String, Node>,
+    },
+}
+
+impl Vfs {
+    /// Build a file system from the given tar archives, in order, and then the
+    /// given files.
+    ///
+    /// Later entries replace earlier entries with the same path. Returns
+    /// `Ok(None)` if there are no archives or files.
+    pub(crate) fn new(
+        tars: &[PathBuf],
+        files: &BTreeMap<String, Vec<u8>>,
+    ) -> anyhow::Result<Option<Vfs>> {
+        if tars.is_empty() && files.is_empty() {
+            return Ok(None);
+        }
+
+        let mut root = Node::Dir {
+            inode: 0,
+            entries: BTreeMap::new(),
+        };
+        for tar in tars {
+            log::debug!("Reading virtual file system archive {}", tar.display());
+            let data = std::fs::read(tar).map_err(|e| {
+                anyhow::anyhow!("failed to read tar archive {}: {}", tar.display(), e)
+            })?;
+            add_tar(&mut root, &data)
+                .map_err(|e| e.context(format!("invalid tar archive: {}", tar.display())))?;
+        }
+        for (path, contents) in files {
+            root.insert(path, Some(contents.as_slice().into()))?;
+        }
+
+        root.assign_inodes(&mut 1);
+        Ok(Some(Vfs {
+            root: Arc::new(root),
+        }))
+    }
+
+    /// Get the root directory, for preopening.
+    pub(crate) fn root_dir(&self) -> Box<dyn WasiDir> {
+        Box::new(VfsDir {
+            root: self.root.clone(),
+            path: vec![],
+        })
+    }
+
+    /// Hash the file system's paths and contents with SHA-256 and hex-encode
+    /// the result.
+    pub(crate) fn sha256_hex(&self) -> String {
+        fn hash_node(hasher: &mut Sha256, path: &str, node: &Node) {
+            match node {
+                Node::File { contents, .. } => {
+                    hasher.update(b"f");
+                    hash_bytes(hasher, path.as_bytes());
+                    hash_bytes(hasher, contents);
+                }
+                Node::Dir { entries, .. } => {
+                    hasher.update(b"d");
+                    hash_bytes(hasher, path.as_bytes());
+                    for (name, entry) in entries {
+                        hash_node(hasher, &format!("{}/{}", path, name), entry);
+                    }
+                }
+            }
+        }
+
+        fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+
+        let mut hasher = Sha256::new();
+        hash_node(&mut hasher, "", &self.root);
+        crate::provenance::hex(&hasher.finalize())
+    }
+}
+
+impl Node {
+    /// Insert a file, or a directory if `contents` is `None`, creating its
+    /// parent directories as needed.
+    fn insert(&mut self, path: &str, contents: Option<Arc<[u8]>>) -> anyhow::Result<()> {
+        let mut components = vec![];
+        for component in path.split('/') {
+            match component {
+                "" | "." => continue,
+                ".." => anyhow::bail!("invalid virtual file system path `{}`: contains `..`", path),
+                _ => components.push(component),
+            }
+        }
+
+        let (last, parents) = match components.split_last() {
+            Some(split) => split,
+            // The root directory always exists.
+            None if contents.is_none() => return Ok(()),
+            None => anyhow::bail!("invalid virtual file system path `{}`: no file name", path),
+        };
+
+        let mut dir = self;
+        for parent in parents {
+            dir = match dir {
+                Node::Dir { entries, .. } => {
+                    entries
+                        .entry(parent.to_string())
+                        .or_insert_with(|| Node::Dir {
+                            inode: 0,
+                            entries: BTreeMap::new(),
+                        })
+                }
+                Node::File { .. } => unreachable!(),
+            };
+            if let Node::File { .. } = dir {
+                anyhow::bail!(
+                    "invalid virtual file system path `{}`: `{}` is a file",
+                    path,
+                    parent
+                );
+            }
+        }
+
+        let entries = match dir {
+            Node::Dir { entries, .. } => entries,
+            Node::File { .. } => unreachable!(),
+        };
+        match contents {
+            Some(contents) => {
+                if let Some(Node::Dir { .. }) = entries.get(*last) {
+                    anyhow::bail!(
+                        "invalid virtual file system path `{}`: it is a directory",
+                        path
+                    );
+                }
+                entries.insert(last.to_string(), Node::File { inode: 0, contents });
+            }
+            None => match entries.get(*last) {
+                Some(Node::Dir { .. }) => {}
+                Some(Node::File { .. }) => {
+                    anyhow::bail!("invalid virtual file system path `{}`: it is a file", path)
+                }
+                None => {
+                    entries.insert(
+                        last.to_string(),
+                        Node::Dir {
+                            inode: 0,
+                            entries: BTreeMap::new(),
+                        },
+                    );
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn assign_inodes(&mut self, next: &mut u64) {
+        match self {
+            Node::File { inode, .. } => {
+                *inode = *next;
+                *next += 1;
+            }
+            Node::Dir { inode, entries } => {
+                *inode = *next;
+                *next += 1;
+                for entry in entries.values_mut() {
+                    entry.assign_inodes(next);
+                }
+            }
+        }
+    }
+
+    fn inode(&self) -> u64 {
+        match self {
+            Node::File { inode, .. } | Node::Dir { inode, .. } => *inode,
+        }
+    }
+
+    fn filetype(&self) -> FileType {
+        match self {
+            Node::File { .. } => FileType::RegularFile,
+            Node::Dir { .. } => FileType::Directory,
+        }
+    }
+
+    fn filestat(&self) -> Filestat {
+        Filestat {
+            device_id: 0,
+            inode: self.inode(),
+            filetype: self.filetype(),
+            nlink: 1,
+            size: match self {
+                Node::File { contents, .. } => contents.len() as u64,
+                Node::Dir { .. } => 0,
+            },
+            atim: None,
+            mtim: None,
+            ctim: None,
+        }
+    }
+}
+
+/// Add the entries of the given ustar archive, including GNU long names and
+/// pax path headers.
+fn add_tar(root: &mut Node, tar: &[u8]) -> anyhow::Result<()> {
+    let mut offset = 0;
+    let mut long_name = None;
+    while offset + TAR_BLOCK_SIZE <= tar.len() {
+        let header = &tar[offset..offset + TAR_BLOCK_SIZE];
+        // The archive ends with zero blocks.
+        if header.iter().all(|b| *b == 0) {
+            return Ok(());
+        }
+
+        let checksum = parse_octal(&header[148..156])
+            .ok_or_else(|| anyhow::anyhow!("invalid header checksum at offset {:#x}", offset))?;
+        let actual: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, b)| if (148..156).contains(&i) { b' ' } else { *b } as u64)
+            .sum();
+        anyhow::ensure!(
+            checksum == actual,
+            "header checksum mismatch at offset {:#x}",
+            offset
+        );
+
+        let size = parse_octal(&header[124..136])
+            .ok_or_else(|| anyhow::anyhow!("invalid entry size at offset {:#x}", offset))?;
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let data_end = usize::try_from(size)
+            .ok()
+            .and_then(|size| data_start.checked_add(size))
+            .filter(|end| *end <= tar.len())
+            .ok_or_else(|| anyhow::anyhow!("truncated entry at offset {:#x}", offset))?;
+        let data = &tar[data_start..data_end];
+        offset = data_end + padding(data.len());
+
+        let typeflag = header[156];
+        match typeflag {
+            // GNU long name: the data is the next entry's path.
+            b'L' => {
+                long_name = Some(String::from_utf8_lossy(until_nul(data)).into_owned());
+                continue;
+            }
+            // pax extended header: the `path` record is the next entry's path.
+            b'x' => {
+                if let Some(path) = pax_path(data)? {
+                    long_name = Some(path);
+                }
+                continue;
+            }
+            // pax global header.
+            b'g' => continue,
+            _ => {}
+        }
+
+        let path = match long_name.take() {
+            Some(path) => path,
+            None => {
+                let name = String::from_utf8_lossy(until_nul(&header[0..100]));
+                let prefix = until_nul(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    format!("{}/{}", String::from_utf8_lossy(prefix), name)
+                } else {
+                    name.into_owned()
+                }
+            }
+        };
+
+        match typeflag {
+            b'0' | b'\0' | b'7' => root.insert(&path, Some(data.into()))?,
+            b'5' => root.insert(&path, None)?,
+            _ => log::warn!(
+                "Skipping tar entry `{}` with unsupported type `{}`",
+                path,
+                typeflag as char
+            ),
+        }
+    }
+    anyhow::bail!("unexpected end of archive")
+}
+
+/// The number of bytes that pad an entry of `len` bytes to a whole block.
+fn padding(len: usize) -> usize {
+    (TAR_BLOCK_SIZE - len % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE
+}
+
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|b| *b == 0) {
+        Some(nul) => &bytes[..nul],
+        None => bytes,
+    }
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let field = std::str::from_utf8(until_nul(field)).ok()?.trim();
+    if field.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(field, 8).ok()
+}
+
+/// Get the `path` record of a pax extended header, whose records are
+/// `<length> <key>=<value>\n`.
+fn pax_path(mut data: &[u8]) -> anyhow::Result<Option<String>> {
+    let mut path = None;
+    while !data.is_empty() {
+        let invalid = || anyhow::anyhow!("invalid pax extended header");
+        let space = data.iter().position(|b| *b == b' ').ok_or_else(invalid)?;
+        let len: usize = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|len| *len > space && *len <= data.len())
+            .ok_or_else(invalid)?;
+        let record = &data[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        data = &data[len..];
+    }
+    Ok(path)
+}
+
+/// A directory in the file system, identified by its path from the root.
+struct VfsDir {
+    root: Arc<Node>,
+    path: Vec<String>,
+}
+
+impl VfsDir {
+    /// Resolve `path` relative to this directory, without leaving it.
+    fn resolve(&self, path: &str) -> Result<(&Node, Vec<String>), Error> {
+        if path.starts_with('/') {
+            return Err(Error::not_capable().context("absolute path"));
+        }
+        let mut components = self.path.clone();
+        for component in path.split('/') {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    if components.len() == self.path.len() {
+                        return Err(Error::not_capable().context("path escapes the directory"));
+                    }
+                    components.pop();
+                }
+                _ => components.push(component.to_string()),
+            }
+        }
+
+        let mut node = &*self.root;
+        for component in &components {
+            node = match node {
+                Node::Dir { entries, .. } => entries.get(component).ok_or_else(Error::not_found)?,
+                Node::File { .. } => return Err(Error::not_dir()),
+            };
+        }
+        Ok((node, components))
+    }
+
+    fn read_only() -> Error {
+        Error::not_capable().context("the virtual file system is read-only")
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiDir for VfsDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        _symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        _read: bool,
+        write: bool,
+        _fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let node = match self.resolve(path) {
+            Ok((node, _)) => node,
+            Err(e) => match e.downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::Noent) if oflags.contains(OFlags::CREATE) => {
+                    return Err(VfsDir::read_only())
+                }
+                _ => return Err(e),
+            },
+        };
+        if oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE) {
+            return Err(Error::exist());
+        }
+        if write || oflags.contains(OFlags::TRUNCATE) {
+            return Err(VfsDir::read_only());
+        }
+        match node {
+            Node::File { inode, contents } => Ok(Box::new(VfsFile {
+                inode: *inode,
+                contents: contents.clone(),
+                position: Mutex::new(0),
+            })),
+            Node::Dir { .. } => Err(Error::not_supported().context("is a directory")),
+        }
+    }
+
+    async fn open_dir(&self, _symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        match self.resolve(path)? {
+            (Node::Dir { .. }, path) => Ok(Box::new(VfsDir {
+                root: self.root.clone(),
+                path,
+            })),
+            (Node::File { .. }, _) => Err(Error::not_dir()),
+        }
+    }
+
+    async fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let (dir, _) = self.resolve(".")?;
+        let parent_inode = if self.path.is_empty() {
+            dir.inode()
+        } else {
+            let mut node = &*self.root;
+            for component in &self.path[..self.path.len() - 1] {
+                if let Node::Dir { entries, .. } = node {
+                    node = &entries[component];
+                }
+            }
+            node.inode()
+        };
+
+        let mut entries = vec![
+            (".".to_string(), dir.inode(), FileType::Directory),
+            ("..".to_string(), parent_inode, FileType::Directory),
+        ];
+        if let Node::Dir {
+            entries: children, ..
+        } = dir
+        {
+            entries.extend(
+                children
+                    .iter()
+                    .map(|(name, node)| (name.clone(), node.inode(), node.filetype())),
+            );
+        }
+
+        let entries: Vec<_> = entries
+            .into_iter()
+            .enumerate()
+            .skip(u64::from(cursor) as usize)
+            .map(|(i, (name, inode, filetype))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(i as u64 + 1),
+                    inode,
+                    name,
+                    filetype,
+                })
+            })
+            .collect();
+        Ok(Box::new(entries.into_iter()))
+    }
+
+    async fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        // There are no symlinks.
+        self.resolve(path)?;
+        Err(Error::invalid_argument())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(self.resolve(".")?.0.filestat())
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        _follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        Ok(self.resolve(path)?.0.filestat())
+    }
+
+    async fn rename(
+        &self,
+        _path: &str,
+        _dest_dir: &dyn WasiDir,
+        _dest_path: &str,
+    ) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+}
+
+/// An open file in the file system.
+struct VfsFile {
+    inode: u64,
+    contents: Arc<[u8]>,
+    position: Mutex<u64>,
+}
+
+impl VfsFile {
+    fn read_at(&self, bufs: &mut [IoSliceMut<'_>], offset: u64) -> u64 {
+        let mut offset = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(self.contents.len());
+        let start = offset;
+        for buf in bufs {
+            let n = buf.len().min(self.contents.len() - offset);
+            buf[..n].copy_from_slice(&self.contents[offset..offset + n]);
+            offset += n;
+        }
+        (offset - start) as u64
+    }
+}
+
+#[async_trait::async_trait]
+impl WasiFile for VfsFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn datasync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        Ok(FdFlags::empty())
+    }
+
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        if flags.contains(FdFlags::APPEND) {
+            return Err(VfsDir::read_only());
+        }
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: 0,
+            inode: self.inode,
+            filetype: FileType::RegularFile,
+            nlink: 1,
+            size: self.contents.len() as u64,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+
+    async fn set_filestat_size(&self, _size: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn advise(&self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn allocate(&self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn set_times(
+        &self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(VfsDir::read_only())
+    }
+
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let n = self.read_at(bufs, *position);
+        *position += n;
+        Ok(n)
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        Ok(self.read_at(bufs, offset))
+    }
+
+    async fn write_vectored<'a>(&self, _bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn write_vectored_at<'a>(
+        &self,
+        _bufs: &[IoSlice<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => offset_by(*position, delta),
+            SeekFrom::End(delta) => offset_by(self.contents.len() as u64, delta),
+        };
+        *position = new.ok_or_else(Error::invalid_argument)?;
+        Ok(*position)
+    }
+
+    async fn peek(&self, buf: &mut [u8]) -> Result<u64, Error> {
+        let position = *self.position.lock().unwrap();
+        Ok(self.read_at(&mut [IoSliceMut::new(buf)], position))
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        let position = *self.position.lock().unwrap();
+        Ok((self.contents.len() as u64).saturating_sub(position))
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+}
+
+fn offset_by(base: u64, delta: i64) -> Option<u64> {
+    if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tar_entry(tar: &mut Vec<u8>, name: &str, typeflag: u8, data: &[u8]) {
+        let mut header = [0; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", data.len()).as_bytes());
+        header[156] = typeflag;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = header.iter().map(|b| *b as u64).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        tar.extend_from_slice(&header);
+        tar.extend_from_slice(data);
+        tar.resize(tar.len() + padding(data.len()), 0);
+    }
+
+    fn lookup<'a>(root: &'a Node, path: &str) -> Option<&'a Node> {
+        let mut node = root;
+        for component in path.split('/') {
+            node = match node {
+                Node::Dir { entries, .. } => entries.get(component)?,
+                Node::File { .. } => return None,
+            };
+        }
+        Some(node)
+    }
+
+    fn contents<'a>(root: &'a Node, path: &str) -> Option<&'a [u8]> {
+        match lookup(root, path)? {
+            Node::File { contents, .. } => Some(contents),
+            Node::Dir { .. } => None,
+        }
+    }
+
+    #[test]
+    fn tar_archive() {
+        let long_name = format!("{}/long.txt", "d".repeat(120));
+        let mut tar = vec![];
+        tar_entry(&mut tar, "assets/", b'5', b"");
+        tar_entry(&mut tar, "assets/a.txt", b'0', b"hello");
+        tar_entry(
+            &mut tar,
+            "././@LongLink",
+            b'L',
+            format!("{}\0", long_name).as_bytes(),
+        );
+        tar_entry(&mut tar, "truncated", b'0', b"long");
+        tar_entry(&mut tar, "PaxHeader", b'x', b"18 path=pax/b.txt\n");
+        tar_entry(&mut tar, "ignored", b'0', b"pax");
+        tar_entry(&mut tar, "link", b'2', b"");
+        tar.extend_from_slice(&[0; 2 * TAR_BLOCK_SIZE]);
+
+        let mut root = Node::Dir {
+            inode: 0,
+            entries: BTreeMap::new(),
+        };
+        add_tar(&mut root, &tar).unwrap();
+        assert_eq!(contents(&root, "assets/a.txt"), Some(&b"hello"[..]));
+        assert_eq!(contents(&root, &long_name), Some(&b"long"[..]));
+        assert_eq!(contents(&root, "pax/b.txt"), Some(&b"pax"[..]));
+        assert!(lookup(&root, "truncated").is_none());
+        assert!(lookup(&root, "ignored").is_none());
+        assert!(lookup(&root, "link").is_none());
+
+        let end = tar.len() - 2 * TAR_BLOCK_SIZE;
+        assert!(add_tar(&mut root, &tar[..end]).is_err());
+        tar[0] = b'x';
+        assert!(add_tar(&mut root, &tar).is_err());
+    }
+
+    #[test]
+    fn conflicting_paths() {
+        let mut root = Node::Dir {
+            inode: 0,
+            entries: BTreeMap::new(),
+        };
+        root.insert("/a/b.txt", Some(b"b"[..].into())).unwrap();
+        assert!(root.insert("a/b.txt/c.txt", Some(b"c"[..].into())).is_err());
+        assert!(root.insert("a", Some(b"a"[..].into())).is_err());
+        assert!(root.insert("a/b.txt", None).is_err());
+        assert!(root.insert("../x", Some(b"x"[..].into())).is_err());
+        root.insert("./a/b.txt", Some(b"new"[..].into())).unwrap();
+        assert_eq!(contents(&root, "a/b.txt"), Some(&b"new"[..]));
+    }
+
+    #[test]
+    fn hash_depends_on_paths_and_contents() {
+        let hash = |files: &[(&str, &str)]| {
+            let files = files
+                .iter()
+                .map(|(path, contents)| (path.to_string(), contents.as_bytes().to_vec()))
+                .collect();
+            Vfs::new(&[], &files).unwrap().unwrap().sha256_hex()
+        };
+        assert_eq!(
+            hash(&[("a", "1"), ("b", "2")]),
+            hash(&[("b", "2"), ("a", "1")])
+        );
+        assert_ne!(hash(&[("a", "1")]), hash(&[("a", "2")]));
+        assert_ne!(hash(&[("a", "1")]), hash(&[("b", "1")]));
+        assert_ne!(hash(&[("a/b", "")]), hash(&[("a", ""), ("b", "")]));
+    }
+}
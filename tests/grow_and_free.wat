@@ -0,0 +1,25 @@
+(module
+  (memory 1)
+  (global $heap_end (export "heap_end") (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    ;; Grow to 10 pages, but only leave data behind in the third page.
+    (drop (memory.grow (i32.const 9)))
+    (i32.store8 (i32.const 131072) (i32.const 42))
+    (i32.store8 (i32.const 500000) (i32.const 0))
+    (global.set $heap_end (i32.const 327680)))
+  (func (export "run") (result i32)
+    (if (i32.ne (i32.load8_u (i32.const 131072)) (i32.const 42))
+      (then unreachable))
+    ;; Grow back to 10 pages, and reuse the re-grown pages, which must be
+    ;; zeroed.
+    (drop (memory.grow (i32.sub (i32.const 10) (memory.size))))
+    (if (i32.ne (memory.size) (i32.const 10))
+      (then unreachable))
+    (if (i32.load8_u (i32.const 500000))
+      (then unreachable))
+    (i32.store8 (i32.const 500000) (i32.const 40))
+    (i32.store8 (i32.const 655359) (i32.const 2))
+    (i32.add
+      (i32.load8_u (i32.const 500000))
+      (i32.load8_u (i32.const 655359))))
+)
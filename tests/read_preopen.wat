@@ -0,0 +1,52 @@
+;; Reads the file named by the only WASI argument from the first preopened
+;; directory, and tries to create a file next to it.
+(module
+  (import "wasi_snapshot_preview1" "args_sizes_get"
+    (func $args_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_get"
+    (func $args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_prestat_get"
+    (func $fd_prestat_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+    (func $fd_prestat_dir_name (param i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "path_open"
+    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_read"
+    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 200) "b.txt")
+
+  (func $write (param $ptr i32) (param $len i32)
+    (i32.store (i32.const 0) (local.get $ptr))
+    (i32.store (i32.const 4) (local.get $len))
+    (drop (call $fd_write (i32.const 1) (i32.const 0) (i32.const 1) (i32.const 8))))
+
+  (func (export "wizer.initialize")
+    ;; Read the path to open into memory at 100. Its length is the size of the
+    ;; argument buffer, less the terminating NUL.
+    (drop (call $args_sizes_get (i32.const 40) (i32.const 44)))
+    (drop (call $args_get (i32.const 48) (i32.const 100)))
+
+    ;; Write the preopen's guest path.
+    (drop (call $fd_prestat_get (i32.const 3) (i32.const 16)))
+    (drop (call $fd_prestat_dir_name (i32.const 3) (i32.const 1024) (i32.load (i32.const 20))))
+    (call $write (i32.const 1024) (i32.load (i32.const 20)))
+
+    ;; Write the contents of the file.
+    (drop (call $path_open (i32.const 3) (i32.const 0)
+      (i32.const 100) (i32.sub (i32.load (i32.const 44)) (i32.const 1))
+      (i32.const 0) (i64.const 0x1fffffff) (i64.const 0x1fffffff) (i32.const 0) (i32.const 24)))
+    (i32.store (i32.const 0) (i32.const 2048))
+    (i32.store (i32.const 4) (i32.const 64))
+    (drop (call $fd_read (i32.load (i32.const 24)) (i32.const 0) (i32.const 1) (i32.const 8)))
+    (call $write (i32.const 2048) (i32.load (i32.const 8)))
+
+    ;; Try to create `b.txt`, and write whether that failed.
+    (i32.store8 (i32.const 32)
+      (i32.ne
+        (call $path_open (i32.const 3) (i32.const 0) (i32.const 200) (i32.const 5)
+          (i32.const 1) (i64.const 0x1fffffff) (i64.const 0x1fffffff) (i32.const 0) (i32.const 24))
+        (i32.const 0)))
+    (call $write (i32.const 32) (i32.const 1))))
@@ -0,0 +1,15 @@
+(module
+  (memory 1)
+  (global (export "scratch_addr") (mut i32) (i32.const 0))
+  (global (export "scratch_len") (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    (i32.store8 (i32.const 16) (i32.const 42))
+    (i32.store (i32.const 1024) (i32.const 0xdeadbeef))
+    (i32.store (i32.const 2048) (i32.const 0xcafebabe))
+    (global.set 0 (i32.const 2048))
+    (global.set 1 (i32.const 4)))
+  (func (export "run") (result i32)
+    (i32.add
+      (i32.load8_u (i32.const 16))
+      (i32.add (i32.load (i32.const 1024)) (i32.load (i32.const 2048)))))
+)
@@ -0,0 +1,14 @@
+(module
+  (memory 1)
+  (global $__stack_pointer (mut i32) (i32.const 1024))
+  (global $__data_end i32 (i32.const 2048))
+  (func $push_frame
+    (global.set $__stack_pointer (i32.sub (global.get $__stack_pointer) (i32.const 16)))
+    (i32.store (global.get $__stack_pointer) (i32.const 0xdeadbeef))
+    (global.set $__stack_pointer (i32.add (global.get $__stack_pointer) (i32.const 16))))
+  (func (export "wizer.initialize")
+    (call $push_frame)
+    (i32.store8 (i32.const 2048) (i32.const 42)))
+  (func (export "run") (result i32)
+    (i32.load8_u (i32.const 2048)))
+)
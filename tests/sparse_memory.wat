@@ -0,0 +1,14 @@
+(module
+  (memory 1)
+  (func (export "wizer.initialize")
+    (i32.store8 (i32.const 0) (i32.const 1))
+    (i32.store8 (i32.const 100) (i32.const 2))
+    (i32.store8 (i32.const 200) (i32.const 3))
+    (i32.store8 (i32.const 60000) (i32.const 36))
+  )
+  (func (export "run") (result i32)
+    (i32.add
+      (i32.add (i32.load8_u (i32.const 0)) (i32.load8_u (i32.const 100)))
+      (i32.add (i32.load8_u (i32.const 200)) (i32.load8_u (i32.const 60000))))
+  )
+)
@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
 use wat::parse_str as wat_to_wasm;
-use wizer::Wizer;
+use wizer::{Provenance, Wizer, WizerError};
 
 fn run_wat(args: &[wasmtime::Val], expected: i32, wat: &str) -> Result<()> {
     let _ = env_logger::try_init();
@@ -17,9 +17,18 @@ fn get_wizer() -> Wizer {
 }
 
 fn run_wasm(args: &[wasmtime::Val], expected: i32, wasm: &[u8]) -> Result<()> {
+    wizen_and_run_wasm(args, expected, wasm, get_wizer())
+}
+
+fn wizen_and_run_wasm(
+    args: &[wasmtime::Val],
+    expected: i32,
+    wasm: &[u8],
+    wizer: Wizer,
+) -> Result<()> {
     let _ = env_logger::try_init();
 
-    let wasm = get_wizer().run(&wasm)?;
+    let wasm = wizer.run(&wasm)?;
     log::debug!(
         "=== Wizened Wasm ==========================================================\n\
          {}\n\
@@ -803,6 +812,1622 @@ fn too_many_data_segments_for_engines() -> Result<()> {
     )
 }
 
+fn count_data_segments(wasm: &[u8]) -> Result<u32> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::DataSection(data) = payload? {
+            return Ok(data.get_count());
+        }
+    }
+    Ok(0)
+}
+
+const SPARSE_MEMORY_WAT: &str = include_str!("./sparse_memory.wat");
+
+#[test]
+fn max_data_segments() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.max_data_segments(2);
+    assert_eq!(count_data_segments(&wizer.run(&wasm)?)?, 2);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn data_segment_merge_gap() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.data_segment_merge_gap(100);
+    assert_eq!(count_data_segments(&wizer.run(&wasm)?)?, 2);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn single_data_segment_per_memory() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.single_data_segment_per_memory(true);
+    wizer.provenance(true);
+    let wizened = wizer.run(&wasm)?;
+    assert_eq!(count_data_segments(&wizened)?, 1);
+
+    // The gaps between the four non-zero bytes are all padding.
+    let provenance = Provenance::from_wasm(&wizened)?.unwrap();
+    assert_eq!(provenance.zero_padding, 60001 - 4);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+fn data_segment_ranges(wasm: &[u8]) -> Result<Vec<(u32, u32)>> {
+    let mut ranges = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::DataSection(mut data) = payload? {
+            for _ in 0..data.get_count() {
+                let segment = data.read()?;
+                let offset = match segment.kind {
+                    wasmparser::DataKind::Active { init_expr, .. } => {
+                        match init_expr.get_operators_reader().read()? {
+                            wasmparser::Operator::I32Const { value } => value as u32,
+                            op => anyhow::bail!("unexpected offset {:?}", op),
+                        }
+                    }
+                    wasmparser::DataKind::Passive => anyhow::bail!("unexpected passive segment"),
+                };
+                ranges.push((offset, segment.data.len() as u32));
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+#[test]
+fn page_aligned_data_segments() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.page_aligned_data_segments(true);
+    assert_eq!(
+        data_segment_ranges(&wizer.run(&wasm)?)?,
+        vec![(0, 4096), (57344, 4096)]
+    );
+    wizen_and_run_wasm(&[], 42, &wasm, wizer.clone())?;
+
+    wizer.data_segment_page_size(65536);
+    assert_eq!(data_segment_ranges(&wizer.run(&wasm)?)?, vec![(0, 65536)]);
+
+    wizer.data_segment_page_size(1000);
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+#[test]
+fn max_data_segments_smaller_than_memories() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory $m1 1)
+  (memory $m2 1)
+  (func (export "wizer.initialize")
+    (i32.store8 (memory $m1) (i32.const 0) (i32.const 1))
+    (i32.store8 (memory $m2) (i32.const 0) (i32.const 1)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.max_data_segments(1);
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+fn memory_mins(wasm: &[u8]) -> Result<Vec<u64>> {
+    let mut mins = vec![];
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::MemorySection(mut mems) = payload? {
+            for _ in 0..mems.get_count() {
+                match mems.read()? {
+                    wasmparser::MemoryType::M32 { limits, .. } => mins.push(limits.initial.into()),
+                    wasmparser::MemoryType::M64 { limits, .. } => mins.push(limits.initial),
+                }
+            }
+        }
+    }
+    Ok(mins)
+}
+
+const GROW_AND_FREE_WAT: &str = include_str!("./grow_and_free.wat");
+
+#[test]
+fn shrink_memory_mins() -> Result<()> {
+    let wasm = wat_to_wasm(GROW_AND_FREE_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.shrink_memory_mins(true);
+    assert_eq!(memory_mins(&wizer.run(&wasm)?)?, vec![3]);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn heap_end_export() -> Result<()> {
+    let wasm = wat_to_wasm(GROW_AND_FREE_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.heap_end_export("heap_end");
+    assert_eq!(memory_mins(&wizer.run(&wasm)?)?, vec![5]);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn heap_end_export_function_runs_before_excluding_ranges() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (func (export "heap_end") (result i32)
+    (i32.store (i32.const 1024) (i32.const 0xdeadbeef))
+    i32.const 65536)
+  (func (export "wizer.initialize")
+    (i32.store8 (i32.const 16) (i32.const 42)))
+  (func (export "run") (result i32)
+    (i32.add (i32.load8_u (i32.const 16)) (i32.load (i32.const 1024))))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.heap_end_export("heap_end");
+    wizer.exclude_memory_range(1024..1028);
+    assert_eq!(count_data_segments(&wizer.run(&wasm)?)?, 1);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn reject_non_zero_memory_above_heap_end() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 2)
+  (func (export "heap_end") (result i32)
+    i32.const 65536)
+  (func (export "wizer.initialize")
+    (i32.store8 (i32.const 70000) (i32.const 1)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.heap_end_export("heap_end");
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+const SCRATCH_BUFFER_WAT: &str = include_str!("./scratch_buffer.wat");
+
+#[test]
+fn exclude_memory_ranges() -> Result<()> {
+    let wasm = wat_to_wasm(SCRATCH_BUFFER_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.exclude_memory_range(1024..1028);
+    wizer.exclude_memory_range_globals("scratch_addr", "scratch_len");
+    assert_eq!(count_data_segments(&wizer.run(&wasm)?)?, 1);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn reject_excluded_memory_range_out_of_bounds() -> Result<()> {
+    let wasm = wat_to_wasm(SCRATCH_BUFFER_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.exclude_memory_range(0..0x10001);
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+const SHADOW_STACK_WAT: &str = include_str!("./shadow_stack.wat");
+
+#[test]
+fn zero_shadow_stack() -> Result<()> {
+    let wasm = wat_to_wasm(SHADOW_STACK_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.zero_shadow_stack(true);
+    assert_eq!(count_data_segments(&wizer.run(&wasm)?)?, 1);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn provenance_records_zeroed_ranges() -> Result<()> {
+    let wasm = wat_to_wasm(SHADOW_STACK_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.provenance(true);
+    wizer.zero_shadow_stack(true);
+    wizer.exclude_memory_range(1500..1504);
+    let provenance = Provenance::from_wasm(&wizer.run(&wasm)?)?.unwrap();
+    assert_eq!(provenance.zeroed_ranges, vec![1500..1504, 0..1024]);
+    assert!(provenance
+        .to_string()
+        .contains("zeroed-range: 0x5dc..0x5e0\nzeroed-range: 0x0..0x400\n"));
+    Ok(())
+}
+
+#[test]
+fn zero_shadow_stack_with_exported_stack_pointer() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (global $sp (export "sp") (mut i32) (i32.const 4096))
+  (func (export "wizer.initialize")
+    (i32.store8 (i32.const 16) (i32.const 42))
+    (i32.store (i32.const 4000) (i32.const 0xdeadbeef)))
+  (func (export "run") (result i32)
+    (i32.load8_u (i32.const 16)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.stack_pointer_export("sp");
+    wizer.stack_size(1024);
+    assert_eq!(count_data_segments(&wizer.run(&wasm)?)?, 1);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn reject_stack_pointer_not_restored() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (global $__stack_pointer (mut i32) (i32.const 1024))
+  (func (export "wizer.initialize")
+    (global.set $__stack_pointer (i32.const 512)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.zero_shadow_stack(true);
+    wizer.stack_size(1024);
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+fn has_passive_data_segment(wasm: &[u8]) -> Result<bool> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::DataSection(mut data) = payload? {
+            for _ in 0..data.get_count() {
+                if let wasmparser::DataKind::Passive = data.read()?.kind {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+    Ok(false)
+}
+
+#[test]
+fn compress_data() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (func (export "wizer.initialize")
+    (local i32)
+    ;; Fill 16KiB with a repeating pattern.
+    (loop
+      (i32.store8 offset=1024 (local.get 0) (i32.rem_u (local.get 0) (i32.const 7)))
+      (br_if 0 (i32.lt_u
+        (local.tee 0 (i32.add (local.get 0) (i32.const 1)))
+        (i32.const 16384))))
+    (i32.store8 (i32.const 60000) (i32.const 33))
+  )
+  (func (export "run") (result i32)
+    (i32.add
+      (i32.add (i32.load8_u (i32.const 1024)) (i32.load8_u (i32.const 1030)))
+      (i32.add (i32.load8_u offset=1024 (i32.const 16383))
+               (i32.load8_u (i32.const 60000))))
+  )
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.wasm_module_linking(false);
+    wizer.compress_data(true);
+    let compressed = wizer.run(&wasm)?;
+    assert!(has_passive_data_segment(&compressed)?);
+
+    wizer.compress_data(false);
+    let uncompressed = wizer.run(&wasm)?;
+    assert!(compressed.len() < uncompressed.len());
+    assert_eq!(memory_mins(&compressed)?, memory_mins(&uncompressed)?);
+
+    // 0 + 6 + 3 + 33
+    wizer.compress_data(true);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn compress_data_without_room() -> Result<()> {
+    // The initialized region fills the whole memory, so there is no zeroed
+    // memory to decompress from.
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (func (export "wizer.initialize")
+    (local i32)
+    (loop
+      (i64.store (local.get 0) (i64.const 0x0707070707070707))
+      (br_if 0 (i32.lt_u
+        (local.tee 0 (i32.add (local.get 0) (i32.const 8)))
+        (i32.const 65536))))))
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.wasm_module_linking(false);
+    wizer.compress_data(true);
+    let err = wizer.run(&wasm).unwrap_err();
+    assert!(
+        format!("{:?}", err).contains("compressed image doesn't fit"),
+        "{:?}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn compress_data_rust_regex() -> Result<()> {
+    let mut wizer = get_wizer();
+    wizer.wasm_module_linking(false);
+    wizer.compress_data(true);
+    wizen_and_run_wasm(
+        &[wasmtime::Val::I32(13)],
+        42,
+        &include_bytes!("./regex_test.wasm")[..],
+        wizer,
+    )
+}
+
+#[test]
+fn reject_compress_data_with_module_linking() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.compress_data(true);
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+#[test]
+fn dedupe_data_segments() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory $m0 1)
+  (memory $m1 1)
+  (data (memory $m0) (i32.const 0) "abcdefghijklmnopqrstuvwxyz012345")
+  (data (memory $m0) (i32.const 1000) "abcdefghijklmnopqrstuvwxyz012345")
+  (func (export "wizer.initialize")
+    ;; Copy the payload into the second memory too.
+    (i64.store (memory $m1) (i32.const 0) (i64.load (memory $m0) (i32.const 0)))
+    (i64.store (memory $m1) (i32.const 8) (i64.load (memory $m0) (i32.const 8)))
+    (i64.store (memory $m1) (i32.const 16) (i64.load (memory $m0) (i32.const 16)))
+    (i64.store (memory $m1) (i32.const 24) (i64.load (memory $m0) (i32.const 24))))
+  (func (export "run") (result i32)
+    ;; 3 * '0' - 102
+    (i32.sub
+      (i32.add
+        (i32.add
+          (i32.load8_u (memory $m0) (i32.const 26))
+          (i32.load8_u (memory $m0) (i32.const 1026)))
+        (i32.load8_u (memory $m1) (i32.const 26)))
+      (i32.const 102)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    let duplicated = wizer.run(&wasm)?;
+    assert_eq!(count_data_segments(&duplicated)?, 3);
+
+    wizer.dedupe_data_segments(true);
+    let deduped = wizer.run(&wasm)?;
+    assert_eq!(count_data_segments(&deduped)?, 1);
+    assert!(has_passive_data_segment(&deduped)?);
+    assert!(deduped.len() < duplicated.len());
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn dedupe_data_segments_only_when_smaller() -> Result<()> {
+    // Copying these payloads with `memory.init` takes more bytes than they do.
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (data (i32.const 0) "abcdefghijklmnopqrst")
+  (data (i32.const 1000) "abcdefghijklmnopqrst")
+  (func (export "wizer.initialize"))
+  (func (export "run") (result i32)
+    (i32.sub (i32.load8_u (i32.const 1019)) (i32.const 74)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    let duplicated = wizer.run(&wasm)?;
+    wizer.dedupe_data_segments(true);
+    let deduped = wizer.run(&wasm)?;
+    assert_eq!(deduped, duplicated);
+    assert!(!has_passive_data_segment(&deduped)?);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn dedupe_data_segments_with_module_linking() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (module $A
+    (memory 1)
+    (func (export "init")
+      (i32.store (i32.const 0) (i32.const 21)))
+    (func (export "clear")
+      (i32.store (i32.const 0) (i32.const 0)))
+    (func (export "get") (result i32)
+      (i32.load (i32.const 0))))
+  (instance $a1 (instantiate $A))
+  (instance $a2 (instantiate $A))
+  (func (export "wizer.initialize")
+    (call (func $a1 "init"))
+    (call (func $a2 "init")))
+  (func (export "run") (result i32)
+    ;; The instantiations share a state module, but not their memories.
+    (call (func $a1 "clear"))
+    (i32.add
+      (i32.add (call (func $a1 "get")) (call (func $a2 "get")))
+      (i32.const 21)))
+)
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    let duplicated = wizer.run(&wasm)?;
+
+    wizer.dedupe_data_segments(true);
+    let deduped = wizer.run(&wasm)?;
+    assert!(deduped.len() < duplicated.len());
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn provenance() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    assert!(Provenance::from_wasm(&wizer.run(&wasm)?)?.is_none());
+
+    wizer.provenance(true);
+    wizer.func_rename("main", "run");
+    let wizened = wizer.run(&wasm)?;
+    let provenance = Provenance::from_wasm(&wizened)?.unwrap();
+    assert_eq!(provenance.wizer_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(provenance.init_funcs, vec!["wizer.initialize".to_string()]);
+    assert_eq!(provenance.func_renames, vec!["main=run".to_string()]);
+    assert!(provenance.allow_wasi);
+    assert_eq!(provenance.input_sha256.len(), 64);
+    assert_eq!(provenance.snapshot_sha256.len(), 64);
+
+    // Wizening the same input again gives the same snapshot.
+    let rewizened = Provenance::from_wasm(&wizer.run(&wasm)?)?.unwrap();
+    assert_eq!(provenance, rewizened);
+    Ok(())
+}
+
+#[test]
+fn provenance_with_newlines() -> Result<()> {
+    let wasm = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.provenance(true);
+    wizer.wasi_arg("two\nlines");
+    wizer.wasi_env("KEY", "back\\slash\r\n");
+    let wizened = wizer.run(&wasm)?;
+    let provenance = Provenance::from_wasm(&wizened)?.unwrap();
+    assert_eq!(provenance.wasi_args, vec!["two\nlines".to_string()]);
+    assert_eq!(
+        provenance.wasi_envs,
+        vec!["KEY=back\\slash\r\n".to_string()]
+    );
+
+    // The escaped provenance doesn't get in the way of re-wizening.
+    wizer.allow_rewizening(true);
+    wizer.init_func("run");
+    let rewizened = Provenance::from_wasm(&wizer.run(&wizened)?)?.unwrap();
+    assert_eq!(rewizened.wasi_args, provenance.wasi_args);
+    Ok(())
+}
+
+#[test]
+fn reject_rewizening() -> Result<()> {
+    let mut wizer = get_wizer();
+    wizer.provenance(true);
+    let wizened = wizer.run(&wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (func (export "wizer.initialize"))
+  (func (export "init_again"))
+)
+"#,
+    )?)?;
+    assert!(wizer.run(&wizened).is_err());
+
+    // When allowed, the new provenance replaces the old one.
+    wizer.allow_rewizening(true);
+    wizer.init_func("init_again");
+    let rewizened = wizer.run(&wizened)?;
+    let provenance = Provenance::from_wasm(&rewizened)?.unwrap();
+    assert_eq!(provenance.init_funcs, vec!["init_again".to_string()]);
+
+    // And without provenance, the old one is removed.
+    wizer.provenance(false);
+    assert!(Provenance::from_wasm(&wizer.run(&wizened)?)?.is_none());
+    Ok(())
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn wizer_from_toml() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (global $g (mut i32) (i32.const 0))
+  (func (export "init") (global.set $g (i32.const 42)))
+  (func (export "get") (result i32) (global.get $g))
+)
+"#,
+    )?;
+
+    let wizer: Wizer = toml::from_str(
+        r#"
+init-func = "init"
+func-renames = ["run=get"]
+wasm-module-linking = true
+"#,
+    )?;
+    wizen_and_run_wasm(&[], 42, &wasm, wizer.clone())?;
+
+    // Round trip through TOML.
+    let wizer: Wizer = toml::from_str(&toml::to_string(&wizer)?)?;
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)?;
+
+    assert!(toml::from_str::<Wizer>("no-such-option = true").is_err());
+    Ok(())
+}
+
+#[test]
+fn run_with_shared_engine() -> Result<()> {
+    let wizer = get_wizer();
+    let engine = wizer.engine()?;
+
+    let sparse = wat_to_wasm(SPARSE_MEMORY_WAT)?;
+    let scratch = wat_to_wasm(SCRATCH_BUFFER_WAT)?;
+    for wasm in &[&sparse, &scratch, &sparse] {
+        assert_eq!(wizer.run_with_engine(&engine, wasm)?, wizer.run(wasm)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn precompile() -> Result<()> {
+    let wat = r#"
+(module
+  (global $g (mut i32) i32.const 0)
+  (func (export "wizer.initialize")
+    i32.const 42
+    global.set $g)
+  (func (export "run") (result i32)
+    global.get $g))
+"#;
+
+    let mut wizer = get_wizer();
+    wizer.opt_level("speed_and_size");
+    let wasm = wizer.run(&wat_to_wasm(wat)?)?;
+    let cwasm = wizer.precompile(&wasm)?;
+
+    let mut config = wasmtime::Config::new();
+    config.wasm_multi_memory(true);
+    config.wasm_multi_value(true);
+    config.wasm_module_linking(true);
+    config.cranelift_opt_level(wasmtime::OptLevel::SpeedAndSize);
+    let engine = wasmtime::Engine::new(&config)?;
+    let module = unsafe { wasmtime::Module::deserialize(&engine, &cwasm)? };
+
+    let mut store = wasmtime::Store::new(&engine, ());
+    let instance = wasmtime::Instance::new(&mut store, &module, &[])?;
+    let run = instance.get_typed_func::<(), i32, _>(&mut store, "run")?;
+    assert_eq!(run.call(&mut store, ())?, 42);
+    Ok(())
+}
+
+#[test]
+fn precompile_invalid_settings() -> Result<()> {
+    let wasm = wat_to_wasm("(module)")?;
+
+    let mut wizer = get_wizer();
+    wizer.target("not-a-real-target");
+    assert!(wizer.precompile(&wasm).is_err());
+
+    let mut wizer = get_wizer();
+    wizer.opt_level("fastest");
+    assert!(wizer.precompile(&wasm).is_err());
+    Ok(())
+}
+
+#[test]
+fn typed_errors() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (table 1 funcref)
+  (func (export "wizer.initialize")
+    i32.const 0
+    i32.const 0
+    i32.const 0
+    table.copy))
+"#,
+    )?;
+    match get_wizer().run(&wasm) {
+        Err(WizerError::Validation {
+            instruction: Some(instruction),
+            offset,
+            ..
+        }) => {
+            assert_eq!(instruction, "table.copy");
+            assert!(offset > 0 && offset < wasm.len());
+        }
+        result => panic!("expected a validation error, got {:?}", result),
+    }
+
+    let wasm = wat_to_wasm(r#"(module (func (export "init") (param i32)))"#)?;
+    let mut wizer = get_wizer();
+    wizer.init_func("init");
+    match wizer.run(&wasm) {
+        Err(WizerError::InitFunc { name, .. }) => assert_eq!(name, "init"),
+        result => panic!("expected an init function error, got {:?}", result),
+    }
+
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (func $boom unreachable)
+  (func (export "wizer.initialize") call $boom))
+"#,
+    )?;
+    match get_wizer().run(&wasm) {
+        Err(WizerError::Trap {
+            func, backtrace, ..
+        }) => {
+            assert_eq!(func, "wizer.initialize");
+            assert_eq!(backtrace.len(), 2);
+            assert_eq!(backtrace[0].func_name.as_deref(), Some("boom"));
+        }
+        result => panic!("expected a trap, got {:?}", result),
+    }
+
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (import "env" "counter" (global i32))
+  (func (export "wizer.initialize")))
+"#,
+    )?;
+    match get_wizer().run(&wasm) {
+        Err(WizerError::Import { module, name, .. }) => {
+            assert_eq!(module, "env");
+            assert_eq!(name.as_deref(), Some("counter"));
+        }
+        result => panic!("expected an import error, got {:?}", result),
+    }
+
+    let wasm = wat_to_wasm(r#"(module (func (export "wizer.initialize")))"#)?;
+    let mut wizer = get_wizer();
+    wizer.func_rename("a", "b");
+    wizer.func_rename("a", "c");
+    match wizer.run(&wasm) {
+        Err(WizerError::Rename { spec, .. }) => assert_eq!(spec, "a=c"),
+        result => panic!("expected a rename error, got {:?}", result),
+    }
+
+    // Typed errors survive a round trip through `anyhow`.
+    let error = anyhow::Error::from(wizer.run(&wasm).unwrap_err());
+    assert!(matches!(WizerError::from(error), WizerError::Rename { .. }));
+
+    // Walking an untyped error's sources visits every level of its context
+    // exactly once.
+    let error = WizerError::from(anyhow::anyhow!("inner").context("middle").context("outer"));
+    let mut chain = vec![error.to_string()];
+    let mut source = std::error::Error::source(&error);
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    assert_eq!(chain, ["outer", "middle", "inner"]);
+    Ok(())
+}
+
+#[test]
+fn disallowed_import_backtrace() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (func $print
+    (drop (call $fd_write (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0))))
+  (func $init (export "wizer.initialize")
+    call $print))
+"#,
+    )?;
+
+    let mut wizer = Wizer::new();
+    wizer.allow_wasi(false);
+    match wizer.run(&wasm) {
+        Err(WizerError::Trap {
+            backtrace, trap, ..
+        }) => {
+            let names = backtrace
+                .iter()
+                .map(|f| f.func_name.as_deref())
+                .collect::<Vec<_>>();
+            assert_eq!(names, [Some("print"), Some("init")]);
+            let message = trap.to_string();
+            assert!(message.contains("'wasi_snapshot_preview1' 'fd_write'"));
+            assert!(message.contains("`--allow-wasi`"));
+        }
+        result => panic!("expected a trap, got {:?}", result),
+    }
+    Ok(())
+}
+
+#[test]
+fn dry_run() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (import "env" "log" (func $log (param i32)))
+  (import "env" "now" (func $now (result i64)))
+  (import "wasi_snapshot_preview1" "random_get"
+    (func $random_get (param i32 i32) (result i32)))
+  (global $g (mut i64) (i64.const 1))
+  (func $init (export "wizer.initialize")
+    (call $log (i32.const 1))
+    (call $log (i32.const 2))
+    (global.set $g (call $now))
+    (drop (call $random_get (i32.const 0) (i32.const 0)))))
+"#,
+    )?;
+
+    let mut wizer = Wizer::new();
+    wizer.dry_run(true);
+    let imports = match wizer.run(&wasm) {
+        Err(WizerError::DisallowedImports { imports }) => imports,
+        result => panic!("expected disallowed imports, got {:?}", result),
+    };
+    let summary = imports
+        .iter()
+        .map(|i| (i.module.as_str(), i.name.as_deref(), i.wasi, i.calls))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            ("env", Some("log"), false, 2),
+            ("env", Some("now"), false, 1),
+            ("wasi_snapshot_preview1", Some("random_get"), true, 1),
+        ]
+    );
+    assert_eq!(imports[0].backtrace[0].func_name.as_deref(), Some("init"));
+
+    // Without any disallowed calls, a dry run wizens as usual.
+    let wasm = wat_to_wasm(r#"(module (func (export "wizer.initialize")))"#)?;
+    wizer.run(&wasm)?;
+    Ok(())
+}
+
+/// Instantiate the given wizened Wasm with the host functions that `define`
+/// adds to the linker, and return the result of calling its `run` export.
+fn run_with_host_funcs(
+    wasm: &[u8],
+    define: impl FnOnce(&mut wasmtime::Linker<()>) -> Result<()>,
+) -> Result<i32> {
+    let mut config = wasmtime::Config::new();
+    config.wasm_multi_memory(true);
+    config.wasm_module_linking(true);
+    let engine = wasmtime::Engine::new(&config)?;
+    let mut store = wasmtime::Store::new(&engine, ());
+    let mut linker = wasmtime::Linker::new(&engine);
+    define(&mut linker)?;
+    let module = wasmtime::Module::new(&engine, wasm)?;
+    let instance = linker.instantiate(&mut store, &module)?;
+    let run = instance.get_typed_func::<(), i32, _>(&mut store, "run")?;
+    Ok(run.call(&mut store, ())?)
+}
+
+#[test]
+fn stub_imports() -> Result<()> {
+    let wat = r#"
+(module
+  (import "env" "log" (func $log (param i32)))
+  (import "env" "counter" (func $counter (result i32)))
+  (import "env" "pair" (func $pair (result i64 f64)))
+  (global $a (mut i32) (i32.const 0))
+  (global $b (mut i64) (i64.const 0))
+  (global $c (mut f64) (f64.const 0))
+  (func (export "wizer.initialize")
+    (call $log (i32.const 1))
+    (global.set $a (call $counter))
+    (call $pair)
+    (global.set $c)
+    (global.set $b))
+  (func (export "run") (result i32)
+    (i32.add
+      (global.get $a)
+      (i32.add
+        (i32.wrap_i64 (global.get $b))
+        (i32.trunc_f64_s (global.get $c))))))
+"#;
+
+    let mut wizer = get_wizer();
+    wizer.stub_import("env", "log", &[]);
+    wizer.stub_import("env", "counter", &[wasmtime::Val::I32(30)]);
+    wizer.stub_import(
+        "env",
+        "pair",
+        &[wasmtime::Val::I64(10), wasmtime::Val::F64(2.5f64.to_bits())],
+    );
+    let wasm = wizer.run(&wat_to_wasm(wat)?)?;
+    let result = run_with_host_funcs(&wasm, |linker| {
+        linker.func_wrap("env", "log", |_: i32| {})?;
+        linker.func_wrap("env", "counter", || 0i32)?;
+        linker.func_wrap("env", "pair", || (0i64, 0f64))?;
+        Ok(())
+    })?;
+    assert_eq!(result, 42);
+
+    // Without a stub, calling the import still traps.
+    let mut wizer = get_wizer();
+    wizer.stub_import("env", "log", &[]);
+    wizer.stub_import("env", "pair", &[]);
+    assert!(matches!(
+        wizer.run(&wat_to_wasm(wat)?),
+        Err(WizerError::Trap { .. })
+    ));
+
+    // The stub's values must match the import's results.
+    let mut wizer = get_wizer();
+    wizer.stub_import("env", "log", &[wasmtime::Val::I32(0)]);
+    assert!(wizer.run(&wat_to_wasm(wat)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn stub_import_exact_values() -> Result<()> {
+    let wat = r#"
+(module
+  (import "env" "nan" (func $nan (result f32)))
+  (global $bits (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    (global.set $bits (i32.reinterpret_f32 (call $nan))))
+  (func (export "run") (result i32)
+    (global.get $bits)))
+"#;
+
+    // NaN payloads are kept, and recorded exactly.
+    let mut wizer = get_wizer();
+    wizer.provenance(true);
+    wizer.stub_import("env", "nan", &[wasmtime::Val::F32(0xffa0_0001)]);
+    let wasm = wizer.run(&wat_to_wasm(wat)?)?;
+    let provenance = Provenance::from_wasm(&wasm)?.unwrap();
+    assert_eq!(provenance.stub_imports, vec!["env::nan=-nan:0x200001"]);
+    let result = run_with_host_funcs(&wasm, |linker| {
+        linker.func_wrap("env", "nan", || 0f32)?;
+        Ok(())
+    })?;
+    assert_eq!(result as u32, 0xffa0_0001);
+
+    // Non-number values are an error, rather than a panic.
+    let mut wizer = get_wizer();
+    wizer.stub_import("env", "nan", &[wasmtime::Val::ExternRef(None)]);
+    assert!(wizer.run(&wat_to_wasm(wat)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn wasi_args_env_and_stdio() -> Result<()> {
+    let wat = r#"
+(module
+  (import "wasi_snapshot_preview1" "fd_read"
+    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_sizes_get"
+    (func $args_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "args_get"
+    (func $args_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_sizes_get"
+    (func $environ_sizes_get (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "environ_get"
+    (func $environ_get (param i32 i32) (result i32)))
+  (memory (export "memory") 1)
+
+  ;; Write `len` bytes at `ptr` to the file descriptor `fd`.
+  (func $write (param $fd i32) (param $ptr i32) (param $len i32)
+    (i32.store (i32.const 0) (local.get $ptr))
+    (i32.store (i32.const 4) (local.get $len))
+    (drop (call $fd_write (local.get $fd) (i32.const 0) (i32.const 1) (i32.const 8))))
+
+  (func (export "wizer.initialize")
+    ;; Echo stdin to stdout.
+    (i32.store (i32.const 0) (i32.const 1024))
+    (i32.store (i32.const 4) (i32.const 1024))
+    (drop (call $fd_read (i32.const 0) (i32.const 0) (i32.const 1) (i32.const 8)))
+    (call $write (i32.const 1) (i32.const 1024) (i32.load (i32.const 8)))
+
+    ;; Write the environment to stdout too.
+    (drop (call $environ_sizes_get (i32.const 16) (i32.const 20)))
+    (drop (call $environ_get (i32.const 4096) (i32.const 8192)))
+    (call $write (i32.const 1) (i32.const 8192) (i32.load (i32.const 20)))
+
+    ;; Write the arguments to stderr.
+    (drop (call $args_sizes_get (i32.const 16) (i32.const 20)))
+    (drop (call $args_get (i32.const 4096) (i32.const 8192)))
+    (call $write (i32.const 2) (i32.const 8192) (i32.load (i32.const 20)))))
+"#;
+
+    let mut wizer = get_wizer();
+    wizer.wasi_arg("prog");
+    wizer.wasi_arg("--flag");
+    wizer.wasi_env("A", "0");
+    wizer.wasi_env("B", "2");
+    wizer.wasi_env("A", "1");
+    wizer.wasi_stdin("hello");
+    let wasm = wat_to_wasm(wat)?;
+    let output = wizer.run_and_capture_stdio(&wasm)?;
+    assert_eq!(output.stdout, b"helloB=2\0A=1\0");
+    assert_eq!(output.stderr, b"prog\0--flag\0");
+
+    // Stdin can come from a file instead.
+    let dir = empty_temp_dir("wasi-stdin")?;
+    std::fs::write(dir.join("stdin.txt"), "howdy")?;
+    wizer.wasi_stdin_file(dir.join("stdin.txt"));
+    let output = wizer.run_and_capture_stdio(&wasm)?;
+    assert_eq!(output.stdout, b"howdyB=2\0A=1\0");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Create an empty directory for the given test in the system's temporary
+/// directory.
+fn empty_temp_dir(test: &str) -> Result<std::path::PathBuf> {
+    let dir = std::env::temp_dir().join(format!("wizer-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+const READ_PREOPEN_WAT: &str = include_str!("./read_preopen.wat");
+
+#[test]
+fn map_dir_and_read_only_dirs() -> Result<()> {
+    let wasm = wat_to_wasm(READ_PREOPEN_WAT)?;
+
+    let host_dir = empty_temp_dir("map-dir")?;
+    std::fs::write(host_dir.join("a.txt"), "hello")?;
+
+    let mut wizer = get_wizer();
+    wizer.wasi_arg("a.txt");
+    wizer.map_dir("/assets", &host_dir);
+    wizer.read_only_dirs(true);
+    let output = wizer.run_and_capture_stdio(&wasm)?;
+    assert_eq!(output.stdout, b"/assetshello\x01");
+    assert!(!host_dir.join("b.txt").exists());
+
+    wizer.read_only_dirs(false);
+    let output = wizer.run_and_capture_stdio(&wasm)?;
+    assert_eq!(output.stdout, b"/assetshello\x00");
+    assert!(host_dir.join("b.txt").exists());
+
+    std::fs::remove_dir_all(&host_dir)?;
+    Ok(())
+}
+
+#[test]
+fn vfs_files() -> Result<()> {
+    let wasm = wat_to_wasm(READ_PREOPEN_WAT)?;
+
+    let mut wizer = get_wizer();
+    wizer.provenance(true);
+    wizer.wasi_arg("assets/a.txt");
+    wizer.vfs_file("/assets/a.txt", "hello");
+    let output = wizer.run_and_capture_stdio(&wasm)?;
+    assert_eq!(output.stdout, b"/hello\x01");
+
+    let provenance = Provenance::from_wasm(&output.wasm)?.unwrap();
+    let vfs_sha256 = provenance.vfs_sha256.unwrap();
+
+    wizer.vfs_file("/assets/a.txt", "howdy");
+    let output = wizer.run_and_capture_stdio(&wasm)?;
+    assert_eq!(output.stdout, b"/howdy\x01");
+    let provenance = Provenance::from_wasm(&output.wasm)?.unwrap();
+    assert_ne!(provenance.vfs_sha256.unwrap(), vfs_sha256);
+
+    wizer.vfs_tar("/nonexistent/assets.tar");
+    assert!(wizer.run(&wasm).is_err());
+    Ok(())
+}
+
+#[test]
+fn init_args_and_status() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (global $g (mut i64) (i64.const 0))
+  (func (export "init") (param i32 i64) (result i32)
+    (global.set $g (i64.add (i64.extend_i32_s (local.get 0)) (local.get 1)))
+    (i32.wrap_i64 (global.get $g)))
+  (func (export "run") (result i32)
+    (i32.wrap_i64 (global.get $g))))
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.init_func("init");
+    wizer.init_arg(wasmtime::Val::I32(-2));
+    wizer.init_arg(wasmtime::Val::I64(44));
+    wizen_and_run_wasm(&[], 42, &wasm, wizer.clone())?;
+
+    wizer.check_init_status(true);
+    match wizer.run(&wasm) {
+        Err(WizerError::InitStatus { name, status }) => {
+            assert_eq!(name, "init");
+            assert_eq!(status, 42);
+        }
+        result => panic!("expected an init status error, got {:?}", result),
+    }
+
+    let mut wizer = get_wizer();
+    wizer.init_func("init");
+    wizer.init_arg(wasmtime::Val::I32(-2));
+    wizer.init_arg(wasmtime::Val::I64(2));
+    wizer.check_init_status(true);
+    wizen_and_run_wasm(&[], 0, &wasm, wizer)?;
+
+    // Arguments that aren't numbers are an error, rather than a panic.
+    let mut wizer = get_wizer();
+    wizer.init_func("init");
+    wizer.init_arg(wasmtime::Val::ExternRef(None));
+    wizer.init_arg(wasmtime::Val::I64(2));
+    match wizer.run(&wasm) {
+        Err(WizerError::InitFunc { .. }) => {}
+        result => panic!("expected an init function error, got {:?}", result),
+    }
+
+    let wasm = wat_to_wasm(r#"(module (func (export "wizer.initialize")))"#)?;
+    let mut wizer = get_wizer();
+    wizer.check_init_status(true);
+    match wizer.run(&wasm) {
+        Err(WizerError::InitFunc { .. }) => {}
+        result => panic!("expected an init function error, got {:?}", result),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn run_async() -> Result<()> {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    struct ThreadWaker(std::thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    fn assert_send<T: Send>(t: T) -> T {
+        t
+    }
+
+    /// A future that waits once before resolving.
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (import "host" "get" (func $get (param i32) (result i32)))
+  (global $g (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    (global.set $g (call $get (i32.const 41))))
+  (func (export "run") (result i32)
+    (global.get $g)))
+"#,
+    )?;
+
+    let wizer = get_wizer();
+    let wizened = block_on(assert_send(wizer.run_async(&wasm, |linker| {
+        linker.func_wrap1_async("host", "get", |_caller, x: i32| {
+            Box::new(async move {
+                YieldOnce(false).await;
+                x + 1
+            })
+        })?;
+        Ok(())
+    })))?;
+
+    // The same initialization, run synchronously, produces the same module.
+    let mut sync_wizer = get_wizer();
+    sync_wizer.stub_import("host", "get", &[wasmtime::Val::I32(42)]);
+    assert_eq!(sync_wizer.run(&wasm)?, wizened);
+
+    let result = run_with_host_funcs(&wizened, |linker| {
+        linker.func_wrap("host", "get", |_: i32| -> i32 { unreachable!() })?;
+        Ok(())
+    })?;
+    assert_eq!(result, 42);
+    Ok(())
+}
+
+#[test]
+fn wasmtime_config() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (global $g (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    (global.set $g
+      (i32.reinterpret_f32 (f32.div (f32.const 0) (f32.const 0)))))
+  (func (export "run") (result i32)
+    (global.get $g)))
+"#,
+    )?;
+
+    let hook_calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let mut wizer = get_wizer();
+    wizer.wasmtime_cache(false);
+    wizer.init_opt_level("none");
+    wizer.nan_canonicalization(true);
+    wizer.wasmtime_config_hook({
+        let hook_calls = hook_calls.clone();
+        move |config| {
+            hook_calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            config.wasm_multi_memory(true);
+            Ok(())
+        }
+    });
+    wizen_and_run_wasm(&[], 0x7fc0_0000, &wasm, wizer.clone())?;
+    assert_eq!(hook_calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let bulk_memory = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (func (export "wizer.initialize")
+    (memory.copy (i32.const 0) (i32.const 1) (i32.const 1))))
+"#,
+    )?;
+    assert!(wizer.run(&bulk_memory).is_err());
+
+    // Wizer overrides async support and fuel consumption, which would
+    // otherwise break initialization.
+    let mut hooked = wizer.clone();
+    hooked.wasmtime_config_hook(|config| {
+        config.async_support(true);
+        Ok(())
+    });
+    wizen_and_run_wasm(&[], 0x7fc0_0000, &wasm, hooked)?;
+
+    let mut hooked = wizer.clone();
+    hooked.wasmtime_config_hook(|config| {
+        config.consume_fuel(true);
+        Ok(())
+    });
+    wizen_and_run_wasm(&[], 0x7fc0_0000, &wasm, hooked)?;
+
+    // Changing the proposals that Wizer depends on is an error.
+    for change in [
+        |config: &mut wasmtime::Config| {
+            config.wasm_multi_memory(false);
+        },
+        |config: &mut wasmtime::Config| {
+            config.wasm_bulk_memory(true);
+        },
+        |config: &mut wasmtime::Config| {
+            config.wasm_simd(true);
+        },
+        |config: &mut wasmtime::Config| {
+            config.wasm_module_linking(false);
+        },
+    ] {
+        let mut bad = wizer.clone();
+        bad.wasmtime_config_hook(move |config| {
+            change(config);
+            Ok(())
+        });
+        let error = bad.run(&wasm).unwrap_err().to_string();
+        assert!(error.contains("config hook changed"), "{}", error);
+    }
+
+    let mut bad = wizer.clone();
+    bad.wasmtime_config_hook(|_| anyhow::bail!("nope"));
+    assert!(bad.run(&wasm).is_err());
+
+    let mut bad = wizer.clone();
+    bad.wasmtime_cache_config("wasmtime-cache.toml");
+    assert!(bad.run(&wasm).is_err());
+
+    let mut bad = wizer.clone();
+    bad.init_opt_level("fastest");
+    assert!(bad.run(&wasm).is_err());
+    Ok(())
+}
+
+#[test]
+fn run_to_writer() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory $m1 1)
+  (memory $m2 1)
+  (func $init (export "wizer.initialize")
+    (i32.store (memory $m1) offset=1337 (i32.const 0) (i32.const 41))
+    (i32.store (memory $m2) offset=40000 (i32.const 0) (i32.const 1)))
+  (func (export "run") (result i32)
+    (i32.add
+      (i32.load (memory $m1) offset=1337 (i32.const 0))
+      (i32.load (memory $m2) offset=40000 (i32.const 0)))))
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.wasm_multi_memory(true);
+    wizer.provenance(true);
+    wizer.allow_rewizening(true);
+
+    let mut streamed = vec![];
+    wizer.run_to_writer(&wasm, &mut streamed)?;
+    assert_eq!(streamed, wizer.run(&wasm)?);
+
+    // Re-wizening replaces the previous provenance.
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 1)
+  (func (export "wizer.initialize")
+    (i32.store offset=1337 (i32.const 0) (i32.const 42)))
+  (func (export "run") (result i32)
+    (i32.load offset=1337 (i32.const 0))))
+"#,
+    )?;
+    let wizened = wizer.run(&wasm)?;
+    let mut rewizer = wizer.clone();
+    rewizer.init_func("run");
+    let mut rewizened = vec![];
+    rewizer.run_to_writer(&wizened, &mut rewizened)?;
+    assert_eq!(rewizened, rewizer.run(&wizened)?);
+
+    // Post-processing the data segments buffers the module first.
+    wizer.dedupe_data_segments(true);
+    let mut deduped = vec![];
+    wizer.run_to_writer(&wasm, &mut deduped)?;
+    assert_eq!(deduped, wizer.run(&wasm)?);
+    Ok(())
+}
+
+#[test]
+fn dirty_page_tracking() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory 100)
+  (func (export "wizer.initialize")
+    (drop (memory.grow (i32.const 10)))
+    (i32.store offset=70000 (i32.const 0) (i32.const 41))
+    (i32.store offset=7000000 (i32.const 0) (i32.const 1))
+    ;; Reading a page touches it, but it is still all zeros.
+    (drop (i32.load offset=3000000 (i32.const 0))))
+  (func (export "run") (result i32)
+    (i32.add
+      (i32.load offset=70000 (i32.const 0))
+      (i32.load offset=7000000 (i32.const 0)))))
+"#,
+    )?;
+
+    let mut wizer = get_wizer();
+    wizer.dirty_page_tracking(true);
+    let tracked = wizer.run(&wasm)?;
+    wizer.dirty_page_tracking(false);
+    assert_eq!(tracked, wizer.run(&wasm)?);
+
+    wizer.dirty_page_tracking(true);
+    wizen_and_run_wasm(&[], 42, &wasm, wizer)
+}
+
+#[test]
+fn cache_dir() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (global $g (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    (global.set $g (i32.const 42)))
+  (func (export "run") (result i32)
+    (global.get $g)))
+"#,
+    )?;
+
+    let tmp = empty_temp_dir("cache-dir")?;
+    let cache_dir = tmp.join("cache");
+    let host_dir = tmp.join("dir");
+    std::fs::create_dir_all(&host_dir)?;
+    std::fs::write(host_dir.join("a.txt"), "hello")?;
+    let entries = || -> Result<Vec<std::path::PathBuf>> {
+        let mut entries = std::fs::read_dir(&cache_dir)?
+            .map(|e| Ok(e?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        Ok(entries)
+    };
+
+    let mut wizer = get_wizer();
+    wizer.cache_dir(&cache_dir);
+    wizer.inherit_stdio(false);
+    wizer.dir(&host_dir);
+    wizer.read_only_dirs(true);
+    let wizened = wizer.run(&wasm)?;
+    let cached = entries()?;
+    assert_eq!(cached.len(), 1);
+    assert_eq!(std::fs::read(&cached[0])?, wizened);
+
+    // A hit returns the cached module without wizening, unless it is invalid.
+    let empty = wat_to_wasm("(module)")?;
+    std::fs::write(&cached[0], &empty)?;
+    assert_eq!(wizer.run(&wasm)?, empty);
+    let mut streamed = vec![];
+    wizer.run_to_writer(&wasm, &mut streamed)?;
+    assert_eq!(streamed, empty);
+    std::fs::write(&cached[0], b"invalid")?;
+    assert_eq!(wizer.run(&wasm)?, wizened);
+    assert_eq!(std::fs::read(&cached[0])?, wizened);
+
+    // Changing the options, or the contents of a preopened directory, misses.
+    wizer.provenance(true);
+    wizer.run(&wasm)?;
+    assert_eq!(entries()?.len(), 2);
+    std::fs::write(host_dir.join("a.txt"), "goodbye")?;
+    wizer.run(&wasm)?;
+    assert_eq!(entries()?.len(), 3);
+
+    // Inherited stdio can't be cached.
+    wizer.inherit_stdio(true);
+    wizer.run(&wasm)?;
+    assert_eq!(entries()?.len(), 3);
+
+    // Neither can a preopened directory with a symlink, since its target
+    // can change without the directory changing.
+    wizer.inherit_stdio(false);
+    #[cfg(unix)]
+    {
+        std::fs::write(tmp.join("outside.txt"), "hello")?;
+        std::os::unix::fs::symlink(tmp.join("outside.txt"), host_dir.join("link.txt"))?;
+        wizer.run(&wasm)?;
+        assert_eq!(entries()?.len(), 3);
+        std::fs::remove_file(host_dir.join("link.txt"))?;
+    }
+
+    // Nor can a module that can read the clock or random numbers.
+    let random = wat_to_wasm(
+        r#"
+(module
+  (import "wasi_snapshot_preview1" "random_get" (func (param i32 i32) (result i32)))
+  (memory 1)
+  (func (export "wizer.initialize"))
+  (func (export "run") (result i32) (i32.const 0)))
+"#,
+    )?;
+    wizer.run(&random)?;
+    assert_eq!(entries()?.len(), 3);
+
+    // A failed wizening caches nothing, and leaves no temporary file behind.
+    wizer.init_func("missing");
+    assert!(wizer.run(&wasm).is_err());
+    assert_eq!(entries()?.len(), 3);
+
+    std::fs::remove_dir_all(&tmp)?;
+    Ok(())
+}
+
+#[test]
+fn heap_image_dir() -> Result<()> {
+    let wasm = wat_to_wasm(
+        r#"
+(module
+  (memory $m0 (export "m0") 1)
+  (memory $m1 (export "m1") 1)
+  (global $g (mut i32) (i32.const 0))
+  (global $f (mut f32) (f32.const 0))
+  (func (export "wizer.initialize")
+    (drop (memory.grow (i32.const 2)))
+    (i32.store (memory $m0) offset=70000 (i32.const 0) (i32.const 40))
+    (global.set $g (i32.const 2))
+    (global.set $f (f32.const 1.5)))
+  (func (export "run") (result i32)
+    (i32.add
+      (i32.load (memory $m0) offset=70000 (i32.const 0))
+      (global.get $g))))
+"#,
+    )?;
+
+    let dir = empty_temp_dir("heap-image")?;
+    // Images from an earlier run are removed, but nothing else is.
+    std::fs::write(dir.join("memory2.img"), "stale")?;
+    std::fs::write(dir.join("memory.img"), "other")?;
+    let mut wizer = get_wizer();
+    wizer.wasm_module_linking(false);
+    wizer.heap_image_dir(&dir);
+    let wizened = wizer.run(&wasm)?;
+    assert!(!dir.join("memory2.img").exists());
+    assert!(dir.join("memory.img").exists());
+
+    let manifest = std::fs::read_to_string(dir.join("manifest.toml"))?;
+    assert_eq!(
+        manifest,
+        format!(
+            "# Written by Wizer {}.\n\
+             page-size = 65536\n\
+             \n\
+             [[global]]\n\
+             index = 0\n\
+             type = \"i32\"\n\
+             value = 2\n\
+             \n\
+             [[global]]\n\
+             bits = 1069547520\n\
+             index = 1\n\
+             type = \"f32\"\n\
+             value = 1.5\n\
+             \n\
+             [[memory]]\n\
+             image = \"memory0.img\"\n\
+             image-pages = 2\n\
+             index = 0\n\
+             minimum-pages = 3\n\
+             \n\
+             [[memory]]\n\
+             image = \"memory1.img\"\n\
+             image-pages = 0\n\
+             index = 1\n\
+             minimum-pages = 1\n",
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+    let manifest: toml::Value = toml::from_str(&manifest)?;
+    assert_eq!(manifest["memory"][0]["minimum-pages"].as_integer(), Some(3));
+    assert_eq!(manifest["global"][1]["value"].as_float(), Some(1.5));
+
+    let image = std::fs::read(dir.join("memory0.img"))?;
+    assert_eq!(image.len(), 2 * 65536);
+    assert_eq!(&image[70000..70004], &40_i32.to_le_bytes());
+    assert!(std::fs::read(dir.join("memory1.img"))?.is_empty());
+
+    // The wizened module has no data, but works once the image is mapped in.
+    let mut config = wasmtime::Config::new();
+    config.wasm_multi_memory(true);
+    let engine = wasmtime::Engine::new(&config)?;
+    let mut store = wasmtime::Store::new(&engine, ());
+    let module = wasmtime::Module::new(&engine, &wizened)?;
+    let instance = wasmtime::Instance::new(&mut store, &module, &[])?;
+    let run = instance.get_typed_func::<(), i32, _>(&mut store, "run")?;
+    assert_eq!(run.call(&mut store, ())?, 2);
+    let memory = instance.get_memory(&mut store, "m0").unwrap();
+    assert_eq!(memory.size(&store), 3);
+    memory.write(&mut store, 0, &image)?;
+    assert_eq!(run.call(&mut store, ())?, 42);
+
+    let mut bad = wizer.clone();
+    bad.compress_data(true);
+    assert!(bad.run(&wasm).is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn module_diff() -> Result<()> {
+    let wat = |value: i32, offset: u32, grow: u32| {
+        format!(
+            r#"
+(module
+  (memory 1)
+  (global $g (mut i32) (i32.const 0))
+  (func (export "wizer.initialize")
+    (drop (memory.grow (i32.const {grow})))
+    (i32.store (i32.const 0) (i32.const 0x01010101))
+    (i32.store (i32.const {offset}) (i32.const 0x02020202))
+    (global.set $g (i32.const {value}))))
+"#,
+            value = value,
+            offset = offset,
+            grow = grow,
+        )
+    };
+    let wizer = get_wizer();
+    let old = wizer.run(&wat_to_wasm(wat(1, 100, 0))?)?;
+    let new = wizer.run(&wat_to_wasm(wat(2, 2, 0))?)?;
+
+    assert!(wizer::ModuleDiff::new(&old, &old)?.is_empty());
+
+    let diff = wizer::ModuleDiff::new(&old, &new)?;
+    assert_eq!(
+        diff.to_string(),
+        format!(
+            "module size: {} -> {} bytes (-{})\n\
+             data segments: 2 -> 1 (-1)\n\
+             data size: 8 -> 6 bytes (-2)\n\
+             global 0: i32 1 -> i32 2\n\
+             memory 0: changed 0x2..0x4 (2 bytes)\n\
+             memory 0: added 0x4..0x6 (2 bytes)\n\
+             memory 0: removed 0x64..0x68 (4 bytes)\n",
+            old.len(),
+            new.len(),
+            old.len() - new.len()
+        )
+    );
+
+    // Growing memory during initialization raises its minimum.
+    let grown = wizer.run(&wat_to_wasm(wat(1, 100, 1))?)?;
+    let diff = wizer::ModuleDiff::new(&old, &grown)?;
+    assert!(diff
+        .to_string()
+        .ends_with("data size: 8 -> 8 bytes (+0)\nmemory 0: minimum pages 1 -> 2\n"));
+    Ok(())
+}
+
 #[test]
 fn rename_functions() -> Result<()> {
     let wat = r#"